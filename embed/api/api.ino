@@ -1,44 +1,239 @@
 const int LED = 6;
+const int BACK_DOOR_LOCK = 7;
+const int FRONT_DOOR_LOCK = 8;
+
+// Answer to HELLO, see src/authorized/identity.rs on the host side.
+// Give every board its own serial number before flashing.
+const uint16_t MODEL = 0x0001;
+const byte FIRMWARE_VERSION[3] = {2, 0, 0};
+const byte COMMAND_SET = 2;
+const uint32_t SERIAL_NUMBER = 1;
+const uint16_t CAPABILITY_DOORS = 1 << 0;
+const uint16_t CAPABILITY_ANALOG = 1 << 1;
+const uint16_t CAPABILITY_SIREN = 1 << 2;
+const byte HELLO_LENGTH = 12;
+
+// Opcodes, the same in both protocols
+const byte CMD_HELLO = 0x48;
+const byte CMD_POLL = 0xAA;
+const byte CMD_RESET = 0x55;
+const byte CMD_LOCK_BACK_DOOR = 0xA2;
+const byte CMD_UNLOCK_BACK_DOOR = 0xA3;
+const byte CMD_LOCK_FRONT_DOOR = 0xA4;
+const byte CMD_UNLOCK_FRONT_DOOR = 0xA5;
+// Used to be 0xA5, which the host sends to unlock the front door
+const byte CMD_SIREN = 0xA6;
+
+// Framed protocol, see src/authorized/protocol.rs on the host side:
+// | 0x7E | version | sequence | kind | length | payload[length] | crc16 |
+const byte FRAME_START = 0x7E;
+const byte FRAME_VERSION = 0x01;
+const byte MAX_PAYLOAD = 16;
+
+const byte RESPONSE_STATE = 0x01;
+const byte RESPONSE_HELLO = 0x02;
+const byte RESPONSE_ACK = 0x06;
+const byte RESPONSE_NAK = 0x15;
+
+const byte NAK_BAD_CHECKSUM = 0x01;
+const byte NAK_UNKNOWN_COMMAND = 0x02;
+const byte NAK_BAD_LENGTH = 0x03;
+
+// Poll payload: the two state bytes, then the measurements big-endian.
+// The legacy protocol only sends the state bytes.
+const byte STATE_LENGTH = 9;
 
 struct State {
   int lower;
   int higher;
+  int temperature;            // 0.1 °C
+  byte smoke;                 // % obscuration
+  unsigned int acceleration;  // mg
+  unsigned int reed_voltage;  // mV
 };
 
 void setup();
 void handle_commands();
+void handle_frame();
+void send_frame(byte sequence, byte kind, const byte* payload, byte length);
+uint16_t crc16(uint16_t crc, const byte* data, byte length);
+bool handle_output(byte command);
+void fill_hello(byte* hello);
 void setoff_alarm();
 void reset_state();
 State get_state();
 
 void setup() {
   Serial.begin(9600);
+  Serial.setTimeout(100);
   pinMode(LED, OUTPUT);
+  pinMode(BACK_DOOR_LOCK, OUTPUT);
+  pinMode(FRONT_DOOR_LOCK, OUTPUT);
 }
 
 void handle_commands() {
   while (Serial.available()) {
     int command = Serial.read();
-    if (command == 0xAA) {
+    if (command == FRAME_START) {
+      handle_frame();
+    } else if (command == CMD_POLL) {
       State s = get_state();
       Serial.write(s.lower);
       Serial.write(s.higher);
 
       Serial.flush();
-    } else if (command == 0x55) {
+    } else if (command == CMD_HELLO) {
+      byte hello[HELLO_LENGTH];
+      fill_hello(hello);
+      Serial.write(hello, HELLO_LENGTH);
+
+      Serial.flush();
+    } else {
+      handle_output(command);
+    }
+  }
+}
+
+void handle_frame() {
+  byte header[4];
+  if (Serial.readBytes(header, 4) != 4) {
+    return;
+  }
+
+  byte version = header[0];
+  byte sequence = header[1];
+  byte kind = header[2];
+  byte length = header[3];
+
+  if (version != FRAME_VERSION) {
+    return;
+  }
+
+  if (length > MAX_PAYLOAD) {
+    byte code = NAK_BAD_LENGTH;
+    send_frame(sequence, RESPONSE_NAK, &code, 1);
+    return;
+  }
+
+  byte payload[MAX_PAYLOAD];
+  byte crc_bytes[2];
+  if (Serial.readBytes(payload, length) != length
+      || Serial.readBytes(crc_bytes, 2) != 2) {
+    return;
+  }
+
+  uint16_t expected = crc16(crc16(0xFFFF, header, 4), payload, length);
+  uint16_t actual = ((uint16_t)crc_bytes[0] << 8) | crc_bytes[1];
+  if (expected != actual) {
+    byte code = NAK_BAD_CHECKSUM;
+    send_frame(sequence, RESPONSE_NAK, &code, 1);
+    return;
+  }
+
+  if (kind == CMD_POLL) {
+    State s = get_state();
+    byte state[STATE_LENGTH] = {
+      (byte)s.lower,
+      (byte)s.higher,
+      (byte)(s.temperature >> 8),
+      (byte)(s.temperature & 0xFF),
+      s.smoke,
+      (byte)(s.acceleration >> 8),
+      (byte)(s.acceleration & 0xFF),
+      (byte)(s.reed_voltage >> 8),
+      (byte)(s.reed_voltage & 0xFF),
+    };
+    send_frame(sequence, RESPONSE_STATE, state, STATE_LENGTH);
+  } else if (kind == CMD_HELLO) {
+    byte hello[HELLO_LENGTH];
+    fill_hello(hello);
+    send_frame(sequence, RESPONSE_HELLO, hello, HELLO_LENGTH);
+  } else if (handle_output(kind)) {
+    send_frame(sequence, RESPONSE_ACK, 0, 0);
+  } else {
+    byte code = NAK_UNKNOWN_COMMAND;
+    send_frame(sequence, RESPONSE_NAK, &code, 1);
+  }
+}
+
+void send_frame(byte sequence, byte kind, const byte* payload, byte length) {
+  byte header[4] = {FRAME_VERSION, sequence, kind, length};
+  uint16_t crc = crc16(crc16(0xFFFF, header, 4), payload, length);
+
+  Serial.write(FRAME_START);
+  Serial.write(header, 4);
+  if (length > 0) {
+    Serial.write(payload, length);
+  }
+  Serial.write((byte)(crc >> 8));
+  Serial.write((byte)(crc & 0xFF));
+
+  Serial.flush();
+}
+
+// CRC-16/CCITT-FALSE, feed it 0xFFFF to start
+uint16_t crc16(uint16_t crc, const byte* data, byte length) {
+  for (byte i = 0; i < length; i++) {
+    crc ^= (uint16_t)data[i] << 8;
+    for (byte bit = 0; bit < 8; bit++) {
+      crc = (crc & 0x8000) ? (crc << 1) ^ 0x1021 : crc << 1;
+    }
+  }
+
+  return crc;
+}
+
+// Door locks and the siren. Returns false for unknown commands.
+bool handle_output(byte command) {
+  switch (command) {
+    case CMD_RESET:
       reset_state();
-    } else if (command == 0xA5) {
+      return true;
+    case CMD_LOCK_BACK_DOOR:
+      digitalWrite(BACK_DOOR_LOCK, HIGH);
+      return true;
+    case CMD_UNLOCK_BACK_DOOR:
+      digitalWrite(BACK_DOOR_LOCK, LOW);
+      return true;
+    case CMD_LOCK_FRONT_DOOR:
+      digitalWrite(FRONT_DOOR_LOCK, HIGH);
+      return true;
+    case CMD_UNLOCK_FRONT_DOOR:
+      digitalWrite(FRONT_DOOR_LOCK, LOW);
+      return true;
+    case CMD_SIREN:
       setoff_alarm();
-    }
+      return true;
+    default:
+      return false;
   }
 }
 
+// | model | firmware x.y.z | command set | serial | capabilities |, big-endian
+void fill_hello(byte* hello) {
+  uint16_t capabilities =
+      CAPABILITY_DOORS | CAPABILITY_ANALOG | CAPABILITY_SIREN;
+
+  hello[0] = MODEL >> 8;
+  hello[1] = MODEL & 0xFF;
+  hello[2] = FIRMWARE_VERSION[0];
+  hello[3] = FIRMWARE_VERSION[1];
+  hello[4] = FIRMWARE_VERSION[2];
+  hello[5] = COMMAND_SET;
+  hello[6] = SERIAL_NUMBER >> 24;
+  hello[7] = (SERIAL_NUMBER >> 16) & 0xFF;
+  hello[8] = (SERIAL_NUMBER >> 8) & 0xFF;
+  hello[9] = SERIAL_NUMBER & 0xFF;
+  hello[10] = capabilities >> 8;
+  hello[11] = capabilities & 0xFF;
+}
+
 void loop() {
   handle_commands();
 }
 
 State get_state() {
-  return {12, 13};
+  return {12, 13, 215, 0, 1000, 3300};
 }
 
 void setoff_alarm() {
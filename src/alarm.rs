@@ -0,0 +1,635 @@
+use crate::authorized::serial_connection::PollResult;
+use crate::sensors::{SensorId, SensorMap};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Delays the state machine works with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlarmTimings {
+    /// Time to leave the building after arming.
+    pub exit_delay: TimeDelta,
+    /// Time to disarm after an entry door opened.
+    pub entry_delay: TimeDelta,
+    /// How long the siren sounds before it silences itself.
+    pub siren_duration: TimeDelta,
+    /// How long the device may run on battery before that is an alarm.
+    pub power_loss_grace: TimeDelta,
+}
+
+impl Default for AlarmTimings {
+    fn default() -> Self {
+        Self {
+            exit_delay: TimeDelta::seconds(30),
+            entry_delay: TimeDelta::seconds(30),
+            siren_duration: TimeDelta::minutes(5),
+            power_loss_grace: TimeDelta::seconds(3),
+        }
+    }
+}
+
+/// What set the alarm off.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AlarmCause {
+    /// A sensor the [sensor map](crate::sensors::SensorMap) gives a zone.
+    Sensor {
+        id: SensorId,
+        name: String,
+        zone: Zone,
+    },
+    PowerLoss,
+    Panic,
+    /// Someone keeps guessing passwords, handled like a wrong RFID card.
+    FailedLogins,
+}
+
+/// How a cause is treated depending on the arming state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Zone {
+    /// Starts the entry delay when armed, ignored when disarmed.
+    Entry,
+    /// Triggers right away when armed, ignored when disarmed.
+    Instant,
+    /// Triggers no matter whether the system is armed.
+    TwentyFourHour,
+}
+
+impl AlarmCause {
+    pub fn zone(&self) -> Zone {
+        match self {
+            AlarmCause::Sensor { zone, .. } => *zone,
+            AlarmCause::PowerLoss
+            | AlarmCause::Panic
+            | AlarmCause::FailedLogins => Zone::TwentyFourHour,
+        }
+    }
+
+    /// Sensors that are currently tripped in a poll and have a zone.
+    pub fn active_in(res: &PollResult, map: &SensorMap) -> Vec<AlarmCause> {
+        map.sensors()
+            .iter()
+            .filter(|sensor| res.get(&sensor.id))
+            .filter_map(|sensor| {
+                Some(AlarmCause::Sensor {
+                    id: sensor.id.clone(),
+                    name: sensor.name.clone(),
+                    zone: sensor.alarm?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Display for AlarmCause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AlarmCause::Sensor { name, .. } => name,
+            AlarmCause::PowerLoss => "running on battery",
+            AlarmCause::Panic => "panic button",
+            AlarmCause::FailedLogins => "repeated failed logins",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlarmState {
+    Disarmed,
+    Arming {
+        armed_at: DateTime<Utc>,
+    },
+    Armed,
+    EntryDelay {
+        cause: AlarmCause,
+        triggers_at: DateTime<Utc>,
+    },
+    Triggered {
+        cause: AlarmCause,
+        since: DateTime<Utc>,
+        siren_until: DateTime<Utc>,
+    },
+    Acknowledged {
+        cause: AlarmCause,
+        since: DateTime<Utc>,
+    },
+    Silenced {
+        cause: AlarmCause,
+        since: DateTime<Utc>,
+    },
+}
+
+impl AlarmState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlarmState::Disarmed => "Disarmed",
+            AlarmState::Arming { .. } => "Arming",
+            AlarmState::Armed => "Armed",
+            AlarmState::EntryDelay { .. } => "Entry delay",
+            AlarmState::Triggered { .. } => "Triggered",
+            AlarmState::Acknowledged { .. } => "Acknowledged",
+            AlarmState::Silenced { .. } => "Silenced",
+        }
+    }
+
+    /// Whether an alarm happened and nobody cleared it yet.
+    pub fn is_alarm(&self) -> bool {
+        matches!(
+            self,
+            AlarmState::Triggered { .. }
+                | AlarmState::Acknowledged { .. }
+                | AlarmState::Silenced { .. }
+        )
+    }
+
+    pub fn cause(&self) -> Option<AlarmCause> {
+        match self {
+            AlarmState::EntryDelay { cause, .. }
+            | AlarmState::Triggered { cause, .. }
+            | AlarmState::Acknowledged { cause, .. }
+            | AlarmState::Silenced { cause, .. } => Some(cause.clone()),
+            _ => None,
+        }
+    }
+
+    /// The next moment a timer will move the state on by itself.
+    pub fn deadline(&self) -> Option<DateTime<Utc>> {
+        match self {
+            AlarmState::Arming { armed_at } => Some(*armed_at),
+            AlarmState::EntryDelay { triggers_at, .. } => Some(*triggers_at),
+            AlarmState::Triggered { siren_until, .. } => Some(*siren_until),
+            _ => None,
+        }
+    }
+}
+
+impl Display for AlarmState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.cause() {
+            Some(cause) => write!(f, "{} ({cause})", self.name()),
+            None => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Things an operator can do to the alarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperatorAction {
+    Arm,
+    Disarm,
+    Panic,
+    Acknowledge,
+    Silence,
+}
+
+impl OperatorAction {
+    pub fn label(self) -> &'static str {
+        match self {
+            OperatorAction::Arm => "Arm",
+            OperatorAction::Disarm => "Disarm",
+            OperatorAction::Panic => "Panic",
+            OperatorAction::Acknowledge => "Acknowledge",
+            OperatorAction::Silence => "Silence",
+        }
+    }
+}
+
+impl Display for OperatorAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            OperatorAction::Arm => "arm",
+            OperatorAction::Disarm => "disarm",
+            OperatorAction::Panic => "panic",
+            OperatorAction::Acknowledge => "acknowledge",
+            OperatorAction::Silence => "silence",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransitionReason {
+    Sensor(AlarmCause),
+    Operator(OperatorAction),
+    Timer,
+}
+
+impl Display for TransitionReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionReason::Sensor(cause) => write!(f, "{cause}"),
+            TransitionReason::Operator(action) => {
+                write!(f, "operator: {action}")
+            }
+            TransitionReason::Timer => write!(f, "timer"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub from: AlarmState,
+    pub to: AlarmState,
+    pub reason: TransitionReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidAction {
+    pub state: AlarmState,
+    pub action: OperatorAction,
+}
+
+impl Display for InvalidAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cannot {} while {}",
+            self.action,
+            self.state.name()
+        )
+    }
+}
+
+impl std::error::Error for InvalidAction {}
+
+/// Single source of truth for the alarm. Everything time dependent takes
+/// `now` from the caller, so the machine can be driven without a clock.
+#[derive(Debug, Clone)]
+pub struct AlarmStateMachine {
+    state: AlarmState,
+    timings: AlarmTimings,
+    power_lost_at: Option<DateTime<Utc>>,
+    /// The power-loss alarm went off for this outage already.
+    power_loss_fired: bool,
+}
+
+impl AlarmStateMachine {
+    pub fn new(initial: AlarmState, timings: AlarmTimings) -> Self {
+        Self {
+            state: initial,
+            timings,
+            power_lost_at: None,
+            power_loss_fired: false,
+        }
+    }
+
+    /// Running delays keep their deadline, the new timings apply from the
+    /// next transition on.
+    pub fn set_timings(&mut self, timings: AlarmTimings) {
+        self.timings = timings;
+    }
+
+    pub fn state(&self) -> &AlarmState {
+        &self.state
+    }
+
+    /// A sensor reports it is tripped. Level-triggered: call it for every
+    /// poll the sensor stays active.
+    pub fn sensor(
+        &mut self,
+        cause: AlarmCause,
+        now: DateTime<Utc>,
+    ) -> Option<Transition> {
+        let reason = TransitionReason::Sensor(cause.clone());
+
+        match (cause.zone(), &self.state) {
+            (_, state) if state.is_alarm() => None,
+            (Zone::TwentyFourHour, _)
+            | (
+                Zone::Instant,
+                AlarmState::Armed | AlarmState::EntryDelay { .. },
+            ) => Some(self.trigger(cause, now, reason)),
+            (Zone::Entry, AlarmState::Armed) => Some(self.go(
+                AlarmState::EntryDelay {
+                    cause,
+                    triggers_at: now + self.timings.entry_delay,
+                },
+                reason,
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn power_lost(&mut self, now: DateTime<Utc>) {
+        self.power_lost_at.get_or_insert(now);
+    }
+
+    pub fn power_restored(&mut self) {
+        self.power_lost_at = None;
+        self.power_loss_fired = false;
+    }
+
+    /// Moves timers on. Call it regularly, e.g. every frame.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Option<Transition> {
+        // Once per outage, a disarm while still on battery sticks
+        if let Some(lost_at) = self.power_lost_at
+            && !self.power_loss_fired
+            && now - lost_at >= self.timings.power_loss_grace
+        {
+            self.power_loss_fired = true;
+            if let Some(transition) = self.sensor(AlarmCause::PowerLoss, now) {
+                return Some(transition);
+            }
+        }
+
+        let deadline = self.state.deadline()?;
+        if now < deadline {
+            return None;
+        }
+
+        let transition = match self.state.clone() {
+            AlarmState::Arming { .. } => self.go(
+                AlarmState::Armed,
+                TransitionReason::Timer,
+            ),
+            AlarmState::EntryDelay { cause, .. } => {
+                self.trigger(cause, now, TransitionReason::Timer)
+            }
+            AlarmState::Triggered { cause, .. } => self.go(
+                AlarmState::Silenced { cause, since: now },
+                TransitionReason::Timer,
+            ),
+            _ => return None,
+        };
+
+        Some(transition)
+    }
+
+    pub fn operator(
+        &mut self,
+        action: OperatorAction,
+        now: DateTime<Utc>,
+    ) -> Result<Transition, InvalidAction> {
+        let reason = TransitionReason::Operator(action);
+        let next = match (action, &self.state) {
+            (OperatorAction::Arm, AlarmState::Disarmed)
+            | (OperatorAction::Arm, AlarmState::Acknowledged { .. }) => {
+                Some(AlarmState::Arming {
+                    armed_at: now + self.timings.exit_delay,
+                })
+            }
+            (OperatorAction::Disarm, state)
+                if !matches!(state, AlarmState::Disarmed) =>
+            {
+                Some(AlarmState::Disarmed)
+            }
+            (OperatorAction::Panic, state)
+                if !matches!(state, AlarmState::Triggered { .. }) =>
+            {
+                Some(self.triggered_state(AlarmCause::Panic, now))
+            }
+            (
+                OperatorAction::Acknowledge,
+                AlarmState::Triggered { cause, .. },
+            )
+            | (
+                OperatorAction::Acknowledge,
+                AlarmState::Silenced { cause, .. },
+            ) => Some(AlarmState::Acknowledged {
+                cause: cause.clone(),
+                since: now,
+            }),
+            (OperatorAction::Silence, AlarmState::Triggered { cause, .. }) => {
+                Some(AlarmState::Silenced { cause: cause.clone(), since: now })
+            }
+            _ => None,
+        };
+
+        match next {
+            Some(next) => Ok(self.go(next, reason)),
+            None => Err(InvalidAction { state: self.state.clone(), action }),
+        }
+    }
+
+    fn triggered_state(
+        &self,
+        cause: AlarmCause,
+        now: DateTime<Utc>,
+    ) -> AlarmState {
+        AlarmState::Triggered {
+            cause,
+            since: now,
+            siren_until: now + self.timings.siren_duration,
+        }
+    }
+
+    fn trigger(
+        &mut self,
+        cause: AlarmCause,
+        now: DateTime<Utc>,
+        reason: TransitionReason,
+    ) -> Transition {
+        let next = self.triggered_state(cause, now);
+        self.go(next, reason)
+    }
+
+    fn go(&mut self, next: AlarmState, reason: TransitionReason) -> Transition {
+        let from = std::mem::replace(&mut self.state, next.clone());
+        Transition { from, to: next, reason }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    fn secs(secs: i64) -> TimeDelta {
+        TimeDelta::seconds(secs)
+    }
+
+    fn door() -> AlarmCause {
+        AlarmCause::Sensor {
+            id: SensorId::new("front_door"),
+            name: "Front door".to_owned(),
+            zone: Zone::Entry,
+        }
+    }
+
+    fn armed() -> AlarmStateMachine {
+        AlarmStateMachine::new(
+            AlarmState::Armed,
+            AlarmTimings::default(),
+        )
+    }
+
+    #[test]
+    fn arming_ends_after_the_exit_delay() {
+        let mut alarm = AlarmStateMachine::new(
+            AlarmState::Disarmed,
+            AlarmTimings::default(),
+        );
+        alarm
+            .operator(OperatorAction::Arm, start())
+            .unwrap();
+        assert_eq!(
+            alarm.state(),
+            &AlarmState::Arming { armed_at: start() + secs(30) }
+        );
+
+        // Doors opened while leaving do not count
+        assert_eq!(
+            alarm.sensor(door(), start() + secs(10)),
+            None
+        );
+        assert_eq!(alarm.tick(start() + secs(29)), None);
+        let transition = alarm.tick(start() + secs(30)).unwrap();
+        assert_eq!(transition.to, AlarmState::Armed);
+        assert_eq!(
+            transition.reason,
+            TransitionReason::Timer
+        );
+    }
+
+    #[test]
+    fn entry_delay_runs_out_into_an_alarm() {
+        let mut alarm = armed();
+        alarm.sensor(door(), start()).unwrap();
+        assert_eq!(
+            alarm.state(),
+            &AlarmState::EntryDelay {
+                cause: door(),
+                triggers_at: start() + secs(30)
+            }
+        );
+        // Still open on the next poll, the delay keeps running
+        assert_eq!(
+            alarm.sensor(door(), start() + secs(1)),
+            None
+        );
+
+        assert_eq!(alarm.tick(start() + secs(29)), None);
+        alarm.tick(start() + secs(30)).unwrap();
+        assert_eq!(
+            alarm.state(),
+            &AlarmState::Triggered {
+                cause: door(),
+                since: start() + secs(30),
+                siren_until: start() + secs(330),
+            }
+        );
+    }
+
+    #[test]
+    fn disarming_in_the_entry_delay_stops_the_alarm() {
+        let mut alarm = armed();
+        alarm.sensor(door(), start()).unwrap();
+        alarm
+            .operator(
+                OperatorAction::Disarm,
+                start() + secs(5),
+            )
+            .unwrap();
+
+        assert_eq!(alarm.tick(start() + secs(60)), None);
+        assert_eq!(alarm.state(), &AlarmState::Disarmed);
+    }
+
+    #[test]
+    fn the_siren_silences_itself() {
+        let mut alarm = armed();
+        alarm
+            .operator(OperatorAction::Panic, start())
+            .unwrap();
+
+        assert_eq!(alarm.tick(start() + secs(299)), None);
+        let transition = alarm.tick(start() + secs(300)).unwrap();
+        assert_eq!(
+            transition.to,
+            AlarmState::Silenced {
+                cause: AlarmCause::Panic,
+                since: start() + secs(300)
+            }
+        );
+        assert!(alarm.state().is_alarm());
+    }
+
+    #[test]
+    fn power_loss_is_an_alarm_only_after_the_grace_period() {
+        let mut alarm = AlarmStateMachine::new(
+            AlarmState::Disarmed,
+            AlarmTimings::default(),
+        );
+
+        // A short blip is forgiven
+        alarm.power_lost(start());
+        assert_eq!(alarm.tick(start() + secs(2)), None);
+        alarm.power_restored();
+        assert_eq!(alarm.tick(start() + secs(10)), None);
+
+        alarm.power_lost(start() + secs(20));
+        // Repeated reports keep the first moment
+        alarm.power_lost(start() + secs(22));
+        let transition = alarm.tick(start() + secs(23)).unwrap();
+        assert_eq!(
+            alarm.state().cause(),
+            Some(AlarmCause::PowerLoss)
+        );
+        assert_eq!(
+            transition.reason,
+            TransitionReason::Sensor(AlarmCause::PowerLoss)
+        );
+    }
+
+    #[test]
+    fn power_loss_goes_off_once_per_outage() {
+        let mut alarm = AlarmStateMachine::new(
+            AlarmState::Disarmed,
+            AlarmTimings::default(),
+        );
+        alarm.power_lost(start());
+        alarm.tick(start() + secs(5)).unwrap();
+        alarm
+            .operator(
+                OperatorAction::Disarm,
+                start() + secs(6),
+            )
+            .unwrap();
+
+        // Still on battery, reported every frame
+        for second in 7..60 {
+            alarm.power_lost(start() + secs(second));
+            assert_eq!(alarm.tick(start() + secs(second)), None);
+        }
+        assert_eq!(alarm.state(), &AlarmState::Disarmed);
+
+        // The next outage counts again
+        alarm.power_restored();
+        alarm.power_lost(start() + secs(60));
+        assert!(alarm.tick(start() + secs(65)).is_some());
+    }
+
+    #[test]
+    fn actions_that_make_no_sense_are_refused() {
+        let mut alarm = AlarmStateMachine::new(
+            AlarmState::Disarmed,
+            AlarmTimings::default(),
+        );
+        for action in [
+            OperatorAction::Disarm,
+            OperatorAction::Acknowledge,
+            OperatorAction::Silence,
+        ] {
+            let err = alarm
+                .operator(action, start())
+                .unwrap_err();
+            assert_eq!(err.state, AlarmState::Disarmed);
+            assert_eq!(err.action, action);
+        }
+
+        let mut alarm = armed();
+        let err = alarm
+            .operator(OperatorAction::Arm, start())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot arm while Armed"
+        );
+        assert_eq!(alarm.state(), &AlarmState::Armed);
+    }
+}
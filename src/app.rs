@@ -1,16 +1,288 @@
+use crate::alarm::{
+    AlarmCause, AlarmState, AlarmStateMachine, OperatorAction, Transition,
+};
+use crate::auth::dual_control::{ApprovalError, DualAction, PendingApproval};
+use crate::auth::duress::DuressAlert;
+use crate::auth::permissions::{Permission, PermissionDenied};
+use crate::auth::totp;
+use crate::auth::users::{Authenticated, Role, SecondFactor, User};
+use crate::authorized::api::{Api, ConnectionStatus, TransportKind};
+use crate::authorized::protocol::ProtocolVersion;
+use crate::authorized::serial_connection::PollResult;
+use crate::config::Config;
+use crate::devices::{self, Device};
+use crate::events::log::EventLog;
+use crate::events::query::EventQuery;
+use crate::events::{EventKind, EventType};
+use crate::http::{self, Control};
+use crate::mqtt;
+use crate::notice::{Level, Notice};
+use crate::sensors::SensorMap;
 use crate::{
-    auth,
-    data::{AppState, AuthLevel, SessionData},
-    widgets::boolean_indicator::BooleanIndicator,
+    data::{AppState, HistoryView, PendingLogin, SessionData, TotpSetup},
+    widgets::{boolean_indicator::BooleanIndicator, qr_code::QrCodeImage},
 };
-use chrono::{Duration, TimeDelta, Utc};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Utc};
 use eframe::egui::PopupCloseBehavior::CloseOnClickOutside;
-use eframe::egui::{self};
+use eframe::egui::{self, Color32};
 use egui_notify::Toasts;
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// Rows the history panel keeps in memory at most.
+const HISTORY_LIMIT: usize = 10_000;
+/// Typing pause before the history is queried with the new filters.
+const HISTORY_DEBOUNCE: Duration = Duration::from_millis(300);
 
 pub fn render(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
     ui.heading("Анти-(Анти-Автомат) System");
 
+    if data.users.is_empty() {
+        render_first_run(data, toasts, ui);
+    } else {
+        render_auth_menu(data, toasts, ui);
+    }
+
+    render_data(data, toasts, ui);
+    render_totp_setup(data, toasts, ui);
+
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    let role = session.role;
+    if role.allows(Permission::ManageUsers) {
+        render_users(data, toasts, ui);
+    }
+
+    if role.allows(Permission::Configure) {
+        render_devices(data, toasts, ui);
+        render_settings(data, toasts, ui);
+    }
+
+    if role.allows(Permission::ViewHistory) {
+        render_history(data, ui);
+    }
+}
+
+fn render_settings(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    egui::CollapsingHeader::new("Settings").show(ui, |ui| {
+        let draft = &mut data.config_draft;
+
+        egui::Grid::new("settings")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.strong("Device");
+                ui.end_row();
+                setting(
+                    ui,
+                    "Poll interval (ms)",
+                    &mut draft.device.poll_interval_ms,
+                );
+                setting(
+                    ui,
+                    "Baud rate",
+                    &mut draft.device.baud_rate,
+                );
+                setting(
+                    ui,
+                    "Timeout (ms)",
+                    &mut draft.device.timeout_ms,
+                );
+
+                ui.strong("Alarm");
+                ui.end_row();
+                setting(
+                    ui,
+                    "Exit delay (s)",
+                    &mut draft.alarm.exit_delay_secs,
+                );
+                setting(
+                    ui,
+                    "Entry delay (s)",
+                    &mut draft.alarm.entry_delay_secs,
+                );
+                setting(
+                    ui,
+                    "Siren (s)",
+                    &mut draft.alarm.siren_secs,
+                );
+                setting(
+                    ui,
+                    "On battery before alarm (s)",
+                    &mut draft.alarm.power_loss_grace_secs,
+                );
+
+                ui.strong("Sessions");
+                ui.end_row();
+                setting(
+                    ui,
+                    "New user session (min)",
+                    &mut draft.sessions.default_minutes,
+                );
+                setting(
+                    ui,
+                    "Longest session (min)",
+                    &mut draft.sessions.max_minutes,
+                );
+                setting(
+                    ui,
+                    "Idle lock (s)",
+                    &mut draft.sessions.idle_lock_secs,
+                );
+                setting(
+                    ui,
+                    "Warn before (s)",
+                    &mut draft.sessions.expiry_warning_secs,
+                );
+                setting(
+                    ui,
+                    "Second factor time (s)",
+                    &mut draft.sessions.second_factor_secs,
+                );
+
+                ui.strong("Login lockout");
+                ui.end_row();
+                setting(
+                    ui,
+                    "Attempts per user",
+                    &mut draft.lockout.free_attempts,
+                );
+                setting(
+                    ui,
+                    "Attempts over all users",
+                    &mut draft.lockout.global_free_attempts,
+                );
+                setting(
+                    ui,
+                    "First lockout (s)",
+                    &mut draft.lockout.base_lockout_secs,
+                );
+                setting(
+                    ui,
+                    "Longest lockout (s)",
+                    &mut draft.lockout.max_lockout_secs,
+                );
+                setting(
+                    ui,
+                    "Alarm every n failures",
+                    &mut draft.lockout.alarm_after,
+                );
+            });
+
+        let valid = match draft.validate() {
+            Ok(()) => true,
+            Err(err) => {
+                ui.colored_label(Color32::LIGHT_RED, err.to_string());
+                false
+            }
+        };
+
+        ui.horizontal(|ui| {
+            let changed = data.config_draft != data.config;
+
+            if ui
+                .add_enabled(
+                    changed && valid,
+                    egui::Button::new("Apply"),
+                )
+                .on_hover_text(format!(
+                    "Save to {}",
+                    data.config_path.display()
+                ))
+                .clicked()
+            {
+                save_settings(data, toasts);
+            }
+
+            if ui
+                .add_enabled(changed, egui::Button::new("Revert"))
+                .clicked()
+            {
+                data.config_draft = data.config.clone();
+            }
+
+            if ui.button("Reload from file").clicked() {
+                reload_config(data, toasts);
+            }
+        });
+    });
+}
+
+fn setting<T: egui::emath::Numeric>(
+    ui: &mut egui::Ui,
+    label: &str,
+    value: &mut T,
+) {
+    ui.label(label);
+    ui.add(egui::DragValue::new(value));
+    ui.end_row();
+}
+
+fn save_settings(data: &mut AppState, toasts: &mut Toasts) {
+    if !authorize(
+        data,
+        toasts,
+        Permission::Configure,
+        "configure",
+    ) {
+        return;
+    }
+
+    let config = data.config_draft.clone();
+    if let Err(err) = config.save(&data.config_path) {
+        toasts.error(format!("{err:#}"));
+        return;
+    }
+
+    record_command(data, "configure", None);
+    apply_config(data, toasts, config);
+    toasts.success("Settings applied");
+}
+
+/// Picks up edits of the config file. A broken file leaves the running
+/// settings alone.
+pub fn reload_config(data: &mut AppState, toasts: &mut Toasts) {
+    match Config::load(&data.config_path) {
+        Ok(config) if config == data.config => {}
+        Ok(config) => {
+            apply_config(data, toasts, config);
+            toasts.info("Settings reloaded");
+        }
+        Err(err) => {
+            toasts.error(format!("{err:#}"));
+        }
+    }
+}
+
+fn apply_config(data: &mut AppState, toasts: &mut Toasts, config: Config) {
+    for device in &mut data.devices {
+        device.configure(&config);
+    }
+    data.login_guard
+        .set_policy(config.lockout.policy());
+    data.users
+        .set_max_session_minutes(config.sessions.max_minutes);
+    if let Err(err) = http::configure(&mut data.http, &config.http) {
+        toasts.error(format!("{err:#}"));
+    }
+    if let Err(err) = mqtt::configure(&mut data.mqtt, &config.mqtt) {
+        toasts.error(format!("{err:#}"));
+    }
+
+    data.config_draft = config.clone();
+    data.config = config;
+}
+
+fn render_auth_menu(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
     egui::Popup::menu(&ui.button("Auth"))
         .close_behavior(CloseOnClickOutside)
         .width(220.0)
@@ -18,201 +290,2155 @@ pub fn render(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
             ui.ctx().request_repaint();
 
             if let Some(session) = data.current_session.as_mut() {
-                render_session(session, ui);
-
-                if session.auth_level == AuthLevel::View {
-                    data.current_session = None;
-
-                    toasts.warning("Logged out");
+                match render_session(session, &mut data.input_password, ui) {
+                    Some(SessionAction::Logout) => {
+                        end_session(data, toasts, "Logged out");
+                    }
+                    Some(SessionAction::Lock) => {
+                        session.locked = true;
+                    }
+                    Some(SessionAction::Extend) => {
+                        if reauthenticate(data, toasts, "extend session") {
+                            extend_session(data, toasts);
+                        }
+                    }
+                    None => {
+                        ui.separator();
+                        render_totp_menu(data, toasts, ui);
+                    }
                 }
             } else {
                 render_login(data, toasts, ui);
             }
         });
-
-    render_data(data, toasts, ui);
 }
 
-fn render_data(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
-    if data.current_session.is_some() {
-        ui.group(|ui| {
-            ui.set_width(ui.available_width());
-            ui.label("General:");
-            if ui.button("Poll").clicked() {
-                let _ = data.api.send_poll().inspect_err(|err| {
-                    toasts.error(format!("{:?}", err));
-                });
-            }
+/// No users yet: the first account is created here and is always an admin.
+fn render_first_run(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    ui.group(|ui| {
+        ui.label("First run: create the administrator account.");
 
-            if ui.button("Reset").clicked() {
-                let _ = data
-                    .api
-                    .send_reset()
-                    .inspect_err(|err| {
-                        toasts.error(format!("{:?}", err));
-                    });
-            }
+        ui.horizontal(|ui| {
+            let name_label = ui.label("Username: ");
+            ui.text_edit_singleline(&mut data.input_username)
+                .labelled_by(name_label.id);
+        });
 
-            if ui.button("Alarm!!!!!!").clicked() {
-                data.alarm_end_time = Some(Utc::now() + Duration::minutes(5));
-                data.api.send_lock_back_door();
-                data.api.send_lock_front_door();
+        ui.horizontal(|ui| {
+            let password_label = ui.label("Password: ");
+            ui.add(
+                egui::TextEdit::singleline(&mut data.input_password)
+                    .password(true),
+            )
+            .labelled_by(password_label.id);
+        });
+
+        ui.horizontal(|ui| {
+            let confirm_label = ui.label("Repeat password: ");
+            ui.add(
+                egui::TextEdit::singleline(&mut data.input_password_confirm)
+                    .password(true),
+            )
+            .labelled_by(confirm_label.id);
+        });
+
+        if !ui
+            .button("Create administrator")
+            .clicked()
+        {
+            return;
+        }
+
+        if data.input_password != data.input_password_confirm {
+            toasts.error("Passwords do not match");
+            return;
+        }
+
+        let created = data.users.add(
+            &data.input_username,
+            &data.input_password,
+            Role::Admin,
+            data.config.sessions.default_minutes,
+        );
+
+        match created {
+            Ok(()) => {
+                data.input_password.clear();
+                data.input_password_confirm.clear();
+                toasts.success("Administrator created, you can log in now");
+            }
+            Err(err) => {
+                toasts.error(err.to_string());
             }
+        }
+    });
+}
 
-            ui.horizontal(|ui| {
-                ui.label("Back: ");
+fn render_users(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
+    egui::CollapsingHeader::new("Users").show(ui, |ui| {
+        let mut action = None;
+        let max_minutes = data.config.sessions.max_minutes;
 
-                if ui.button("Lock").clicked() {
-                    let _ = data
-                        .api
-                        .send_lock_back_door()
-                        .inspect_err(|err| {
-                            toasts.error(format!("{:?}", err));
-                        });
-                }
+        egui::Grid::new("users")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("User");
+                ui.label("Role");
+                ui.label("Session (min)");
+                ui.end_row();
 
-                if ui.button("Unlock").clicked() {
-                    let _ = data
-                        .api
-                        .send_unlock_back_door()
-                        .inspect_err(|err| {
-                            toasts.error(format!("{:?}", err));
-                        });
-                }
-            });
+                for user in data.users.users() {
+                    let mut role = user.role;
+                    let mut session_minutes = user.session_minutes;
 
-            ui.horizontal(|ui| {
-                ui.label("Front: ");
+                    ui.label(&user.username);
 
-                if ui.button("Lock").clicked() {
-                    let _ = data
-                        .api
-                        .send_lock_front_door()
-                        .inspect_err(|err| {
-                            toasts.error(format!("{:?}", err));
+                    egui::ComboBox::from_id_salt(("role", &user.username))
+                        .selected_text(role.to_string())
+                        .show_ui(ui, |ui| {
+                            for option in Role::ALL {
+                                ui.selectable_value(
+                                    &mut role,
+                                    option,
+                                    option.to_string(),
+                                );
+                            }
                         });
-                }
 
-                if ui.button("Unlock").clicked() {
-                    let _ = data
-                        .api
-                        .send_unlock_front_door()
-                        .inspect_err(|err| {
-                            toasts.error(format!("{:?}", err));
-                        });
+                    let minutes = ui.add(
+                        egui::DragValue::new(&mut session_minutes)
+                            .range(1..=max_minutes),
+                    );
+
+                    if role != user.role
+                        || (session_minutes != user.session_minutes
+                            && !minutes.dragged())
+                    {
+                        action = Some(UserAction::Update(
+                            user.username.clone(),
+                            role,
+                            session_minutes,
+                        ));
+                    }
+
+                    if ui
+                        .button("Set password")
+                        .on_hover_text("Use the password typed below")
+                        .clicked()
+                    {
+                        action = Some(UserAction::SetPassword(
+                            user.username.clone(),
+                        ));
+                    }
+
+                    if ui
+                        .button("Set duress")
+                        .on_hover_text(
+                            "Use the password typed below, clear it when empty",
+                        )
+                        .clicked()
+                    {
+                        action = Some(UserAction::SetDuress(
+                            user.username.clone(),
+                        ));
+                    }
+
+                    if ui
+                        .add_enabled(
+                            user.has_totp(),
+                            egui::Button::new("Reset 2FA"),
+                        )
+                        .on_hover_text("Turn off the user's second factor")
+                        .clicked()
+                    {
+                        action = Some(UserAction::ResetTotp(
+                            user.username.clone(),
+                        ));
+                    }
+
+                    if ui.button("Remove").clicked() {
+                        action = Some(UserAction::Remove(
+                            user.username.clone(),
+                        ));
+                    }
+
+                    ui.end_row();
                 }
             });
-        });
-    }
 
-    ui.group(|ui| {
-        ui.label("Двери");
+        ui.separator();
 
-        ui.add(BooleanIndicator {
-            label: "Передняя дверь:".to_owned(),
-            value_ref: &data.last_poll_result.open_door_front,
+        let form = &mut data.user_form;
+        ui.horizontal(|ui| {
+            let name_label = ui.label("Username: ");
+            ui.text_edit_singleline(&mut form.username)
+                .labelled_by(name_label.id);
         });
 
-        ui.add(BooleanIndicator {
-            label: "Задняя дверь:".to_owned(),
-            value_ref: &data.last_poll_result.open_door_back,
+        ui.horizontal(|ui| {
+            let password_label = ui.label("Password: ");
+            ui.add(
+                egui::TextEdit::singleline(&mut form.password).password(true),
+            )
+            .labelled_by(password_label.id);
         });
-    });
 
-    ui.group(|ui| {
-        ui.label("Датчики движения");
+        ui.horizontal(|ui| {
+            for role in Role::ALL {
+                ui.radio_value(&mut form.role, role, role.to_string());
+            }
 
-        ui.add(BooleanIndicator {
-            label: "Датчик движения 1:".to_owned(),
-            value_ref: &data.last_poll_result.motion_detected_1,
+            ui.label("Session (min):");
+            ui.add(
+                egui::DragValue::new(&mut form.session_minutes)
+                    .range(1..=max_minutes),
+            );
         });
 
-        ui.add(BooleanIndicator {
-            label: "Датчик движения 2:".to_owned(),
-            value_ref: &data.last_poll_result.motion_detected_2,
-        });
+        if ui.button("Add user").clicked() {
+            action = Some(UserAction::Add);
+        }
+
+        if let Some(action) = action {
+            apply_user_action(data, toasts, action);
+        }
     });
+}
 
-    ui.group(|ui| {
-        ui.label("Остальное");
+enum UserAction {
+    Add,
+    Update(String, Role, u32),
+    SetPassword(String),
+    SetDuress(String),
+    ResetTotp(String),
+    Remove(String),
+}
+
+fn apply_user_action(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    action: UserAction,
+) {
+    if !authorize(
+        data,
+        toasts,
+        Permission::ManageUsers,
+        "manage users",
+    ) {
+        return;
+    }
+
+    let form = &data.user_form;
+    let (command, result) = match &action {
+        UserAction::Add => (
+            format!(
+                "add_user {} ({})",
+                form.username.trim(),
+                form.role
+            ),
+            data.users.add(
+                &form.username,
+                &form.password,
+                form.role,
+                form.session_minutes,
+            ),
+        ),
+        UserAction::Update(username, role, session_minutes) => (
+            format!("update_user {username} ({role}, {session_minutes} min)"),
+            data.users
+                .update(username, *role, *session_minutes),
+        ),
+        UserAction::SetPassword(username) => (
+            format!("set_password {username}"),
+            data.users
+                .set_password(username, &form.password),
+        ),
+        UserAction::SetDuress(username) => {
+            let password = Some(form.password.as_str())
+                .filter(|password| !password.is_empty());
+            (
+                format!("set_duress_password {username}"),
+                data.users
+                    .set_duress_password(username, password),
+            )
+        }
+        UserAction::ResetTotp(username) => (
+            format!("reset_totp {username}"),
+            data.users.disable_totp(username),
+        ),
+        UserAction::Remove(username) => (
+            format!("remove_user {username}"),
+            data.users.remove(username),
+        ),
+    };
+
+    match result {
+        Ok(()) => {
+            record_command(data, &command, None);
+            data.user_form.password.clear();
+            if matches!(action, UserAction::Add) {
+                data.user_form.username.clear();
+            }
+        }
+        Err(err) => {
+            toasts.error(err.to_string());
+        }
+    }
+}
+
+/// Drains every device's worker: logs sensor changes, feeds the alarms and
+/// moves their timers on. Call it once per frame.
+pub fn update_devices(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    now: DateTime<Utc>,
+) {
+    for device in &mut data.devices {
+        for notice in device.update(&data.sensors, &mut data.events, now) {
+            show_notice(toasts, notice);
+        }
+    }
+}
+
+/// Answers the HTTP API's requests, as if they came from the window.
+pub fn update_http(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    now: DateTime<Utc>,
+) {
+    let Some(server) = &mut data.http else {
+        return;
+    };
+
+    let mut control = Control {
+        devices: &mut data.devices,
+        sensors: &data.sensors,
+        events: &mut data.events,
+        users: &mut data.users,
+        login_guard: &mut data.login_guard,
+        dual_control: &data.dual_control,
+        on_battery: data.power.on_battery(),
+        duress_alerts: &mut data.duress_alerts,
+    };
+    for notice in server.update(&mut control, now) {
+        show_notice(toasts, notice);
+    }
+}
+
+pub fn update_mqtt(data: &mut AppState, toasts: &mut Toasts) {
+    let Some(bridge) = &mut data.mqtt else {
+        return;
+    };
+
+    for notice in bridge.update(
+        &data.devices,
+        &data.sensors,
+        &mut data.events,
+        &data.dual_control,
+        data.power.on_battery(),
+    ) {
+        show_notice(toasts, notice);
+    }
+}
+
+pub fn show_notice(toasts: &mut Toasts, notice: Notice) {
+    match notice.level {
+        Level::Info => toasts.info(notice.message),
+        Level::Warning => toasts.warning(notice.message),
+        Level::Error => toasts.error(notice.message),
+    };
+}
+
+/// One row per device: connection, alarm state and tripped sensors. A click
+/// on the name opens the device below.
+pub fn render_overview(data: &mut AppState, ui: &mut egui::Ui) {
+    egui::Grid::new("devices")
+        .striped(true)
+        .show(ui, |ui| {
+            ui.strong("Device");
+            ui.strong("Connection");
+            ui.strong("Alarm");
+            ui.strong("Active sensors");
+            ui.end_row();
+
+            for (index, device) in data.devices.iter().enumerate() {
+                if ui
+                    .selectable_label(
+                        data.selected_device == Some(index),
+                        &device.name,
+                    )
+                    .clicked()
+                {
+                    data.selected_device = Some(index);
+                }
 
-        ui.add(BooleanIndicator {
-            label: "Неправильная RFID карточка:".to_owned(),
-            value_ref: &data.last_poll_result.door_invade,
+                let (color, connection) = match device.api.status() {
+                    ConnectionStatus::Connected(_) => {
+                        (Color32::GREEN, "Connected")
+                    }
+                    ConnectionStatus::Connecting(_) => {
+                        (Color32::YELLOW, "Connecting")
+                    }
+                    ConnectionStatus::Reconnecting { .. } => {
+                        (Color32::YELLOW, "Reconnecting")
+                    }
+                    ConnectionStatus::Disconnected => {
+                        (Color32::RED, "Disconnected")
+                    }
+                };
+                ui.colored_label(color, connection);
+
+                let state = device.alarm.state();
+                ui.colored_label(alarm_color(state), state.to_string());
+
+                let active: Vec<_> = data
+                    .sensors
+                    .sensors()
+                    .iter()
+                    .filter(|sensor| device.last_poll_result.get(&sensor.id))
+                    .map(|sensor| sensor.name.as_str())
+                    .collect();
+                ui.label(active.join(", "));
+                ui.end_row();
+            }
+        });
+}
+
+fn alarm_color(state: &AlarmState) -> Color32 {
+    match state {
+        AlarmState::Disarmed => Color32::GRAY,
+        AlarmState::Arming { .. } | AlarmState::Armed => Color32::GREEN,
+        AlarmState::EntryDelay { .. } => Color32::YELLOW,
+        _ => Color32::RED,
+    }
+}
+
+/// Adding and removing devices, saved to `data/devices.json`.
+fn render_devices(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
+    egui::CollapsingHeader::new("Devices").show(ui, |ui| {
+        let mut remove = None;
+
+        egui::Grid::new("device_list")
+            .striped(true)
+            .show(ui, |ui| {
+                for (index, device) in data.devices.iter().enumerate() {
+                    ui.label(&device.name);
+                    ui.label(device.endpoint.as_ref().map_or_else(
+                        || "connected by hand".to_owned(),
+                        ToString::to_string,
+                    ));
+                    if ui.button("Remove").clicked() {
+                        remove = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+
+        if let Some(index) = remove {
+            remove_device(data, toasts, index);
+        }
+
+        ui.separator();
+        let form = &mut data.device_form;
+
+        ui.horizontal(|ui| {
+            let name_label = ui.label("Name: ");
+            ui.text_edit_singleline(&mut form.name)
+                .labelled_by(name_label.id);
         });
 
-        ui.add(BooleanIndicator {
-            label: "Акселерометр:".to_owned(),
-            value_ref: &data.last_poll_result.accelerometer,
+        ui.horizontal(|ui| {
+            ui.label("Connect to:");
+            ui.radio_value(
+                &mut form.transport,
+                None,
+                "Later, by hand",
+            );
+            for kind in TransportKind::ALL {
+                ui.radio_value(
+                    &mut form.transport,
+                    Some(kind),
+                    kind.label(),
+                );
+            }
         });
 
-        ui.add(BooleanIndicator {
-            label: "Пожар:".to_owned(),
-            value_ref: &data.last_poll_result.fire_detected,
+        if matches!(
+            form.transport,
+            Some(TransportKind::Serial | TransportKind::Tcp)
+        ) {
+            ui.horizontal(|ui| {
+                let address_label = ui.label("Port or host:port: ");
+                ui.text_edit_singleline(&mut form.address)
+                    .labelled_by(address_label.id);
+            });
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Protocol:");
+            for protocol in [ProtocolVersion::Framed, ProtocolVersion::Legacy] {
+                ui.radio_value(
+                    &mut form.protocol,
+                    protocol,
+                    protocol.to_string(),
+                );
+            }
         });
+
+        if ui.button("Add device").clicked() {
+            add_device(data, toasts);
+        }
     });
 }
 
-fn render_login(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
-    let username = data.input_username.clone();
-    ui.label(format!("Will login as [{username}]"));
+fn add_device(data: &mut AppState, toasts: &mut Toasts) {
+    if !authorize(
+        data,
+        toasts,
+        Permission::Configure,
+        "add device",
+    ) {
+        return;
+    }
 
-    ui.separator();
+    let form = &data.device_form;
+    let entry = devices::DeviceEntry {
+        name: form.name.trim().to_owned(),
+        endpoint: form.endpoint(),
+        protocol: form.protocol,
+    };
 
-    ui.horizontal(|ui| {
-        let name_label = ui.label("Username: ");
-        ui.text_edit_singleline(&mut data.input_username)
-            .labelled_by(name_label.id);
-    });
+    let mut entries: Vec<_> = data
+        .devices
+        .iter()
+        .map(Device::entry)
+        .collect();
+    if let Err(err) = devices::validate_name(&entry.name, &entries) {
+        toasts.error(err.to_string());
+        return;
+    }
+
+    entries.push(entry.clone());
+    if let Err(err) = devices::save(&data.devices_path, &entries) {
+        toasts.error(format!("{err:#}"));
+        return;
+    }
+
+    record_command(
+        data,
+        &format!("add device {}", entry.name),
+        None,
+    );
+    toasts.success(format!("Added {}", entry.name));
+    data.devices.push(Device::start(
+        entry,
+        &data.config,
+        &data.sensors,
+    ));
+    data.selected_device = Some(data.devices.len() - 1);
+    data.device_form = Default::default();
+}
+
+fn remove_device(data: &mut AppState, toasts: &mut Toasts, index: usize) {
+    let name = data.devices[index].name.clone();
+    if !authorize(
+        data,
+        toasts,
+        Permission::Configure,
+        &format!("remove device {name}"),
+    ) {
+        return;
+    }
+
+    let entries: Vec<_> = data
+        .devices
+        .iter()
+        .enumerate()
+        .filter(|(other, _)| *other != index)
+        .map(|(_, device)| device.entry())
+        .collect();
+    if let Err(err) = devices::save(&data.devices_path, &entries) {
+        toasts.error(format!("{err:#}"));
+        return;
+    }
+
+    record_command(
+        data,
+        &format!("remove device {name}"),
+        None,
+    );
+    toasts.success(format!("Removed {name}"));
+    // Dropping the device stops its worker
+    data.devices.remove(index);
+    data.selected_device = match data.selected_device {
+        _ if data.devices.is_empty() => None,
+        Some(selected) if selected > index => Some(selected - 1),
+        Some(selected) => Some(selected.min(data.devices.len() - 1)),
+        None => None,
+    };
+}
+
+/// Alarm status line with the countdown to the next timed transition.
+pub fn render_alarm(alarm: &AlarmStateMachine, ui: &mut egui::Ui) {
+    let state = alarm.state();
+    let color = alarm_color(state);
 
     ui.horizontal(|ui| {
-        let name_label = ui.label("Password: ");
-        ui.text_edit_singleline(&mut data.input_password)
-            .labelled_by(name_label.id);
-    });
+        ui.label("Alarm:");
+        ui.colored_label(color, state.to_string());
 
-    if ui.button("Login").clicked() {
-        if auth::is_valid(
-            data.input_username.as_str(),
-            data.input_password.as_str(),
-        ) {
-            data.input_password = "".to_owned();
-            data.current_session = Some(SessionData {
-                username: data.input_username.to_owned(),
-                auth_level: AuthLevel::Modify,
-                begin_timestamp: Utc::now(),
-                timeout_time: if data.input_username == "god" {
-                    TimeDelta::hours(24)
-                } else {
-                    TimeDelta::minutes(5)
-                },
-            });
+        if let Some(deadline) = state.deadline() {
+            let seconds = (deadline - Utc::now())
+                .num_seconds()
+                .max(0);
+            let remaining_time_str = format!(
+                "{:02}:{:02}",
+                seconds / 60,
+                seconds % 60
+            );
 
-            toasts.info(format!(
-                "Logged in as {}",
-                data.input_username
-            ));
-        } else {
-            toasts.error("Invalid credentials");
+            ui.colored_label(color, remaining_time_str);
         }
+    });
+
+    if matches!(state, AlarmState::Triggered { .. }) {
+        ui.colored_label(Color32::RED, "ALARM TRIGGERED!");
     }
 }
 
-fn render_session(session: &mut SessionData, ui: &mut egui::Ui) {
-    let session_end_time = session.begin_timestamp + session.timeout_time;
-    if Utc::now() > session_end_time {
-        session.auth_level = AuthLevel::View;
+pub fn report_transition(
+    events: &mut EventLog,
+    device: &str,
+    transition: &Transition,
+    toasts: &mut Toasts,
+) {
+    show_notice(
+        toasts,
+        devices::record_transition(events, device, transition),
+    );
+}
+
+fn alarm_action(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    device: usize,
+    action: OperatorAction,
+) {
+    if action == OperatorAction::Disarm
+        && data.devices[device]
+            .alarm
+            .state()
+            .is_alarm()
+    {
+        critical_command(
+            data,
+            toasts,
+            device,
+            DualAction::CancelAlarm,
+        );
         return;
     }
 
-    display_session_header(session, ui, session_end_time);
+    let command = format!("alarm {action}");
+    if authorize(
+        data,
+        toasts,
+        Permission::for_alarm(action),
+        &command,
+    ) {
+        run_alarm_action(data, toasts, device, action, None);
+    }
+}
 
-    ui.separator();
+fn run_alarm_action(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    device: usize,
+    action: OperatorAction,
+    approved_by: Option<String>,
+) {
+    if action == OperatorAction::Disarm
+        && let Some(session) = &data.current_session
+        && session.duress
+    {
+        data.duress_alerts.push(DuressAlert {
+            user: session.username.clone(),
+            action: format!("alarm {action}"),
+        });
+    }
+
+    // Refused actions are not commands anyone ran
+    let transition = match data.devices[device]
+        .alarm
+        .operator(action, Utc::now())
+    {
+        Ok(transition) => transition,
+        Err(err) => {
+            toasts.error(err.to_string());
+            return;
+        }
+    };
+
+    record_device_command(
+        data,
+        device,
+        &format!("alarm {action}"),
+        approved_by,
+    );
+    report_transition(
+        &mut data.events,
+        &data.devices[device].name,
+        &transition,
+        toasts,
+    );
+}
+
+/// Runs `action` right away, or parks it until a second operator approves
+/// if the two-person policy covers it.
+fn critical_command(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    device: usize,
+    action: DualAction,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+    let username = session.username.clone();
+
+    if !authorize(
+        data,
+        toasts,
+        action.permission(),
+        action.command(),
+    ) {
+        return;
+    }
 
-    if ui.button("Logout").clicked() {
-        session.auth_level = AuthLevel::View;
+    if !data.dual_control.requires(action) {
+        run_critical_command(data, toasts, device, action, None);
         return;
     }
+
+    data.pending_approval = Some(PendingApproval::new(
+        action,
+        &data.devices[device].name,
+        &username,
+        &data.dual_control,
+        Utc::now(),
+    ));
+    toasts.info(format!(
+        "{action} needs a second operator"
+    ));
+}
+
+fn run_critical_command(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    device: usize,
+    action: DualAction,
+    approved_by: Option<String>,
+) {
+    let send: fn(&Api, Role) -> anyhow::Result<()> = match action {
+        DualAction::UnlockBackDoor => Api::send_unlock_back_door,
+        DualAction::UnlockFrontDoor => Api::send_unlock_front_door,
+        DualAction::Reset => Api::send_reset,
+        DualAction::CancelAlarm => {
+            run_alarm_action(
+                data,
+                toasts,
+                device,
+                OperatorAction::Disarm,
+                approved_by,
+            );
+            return;
+        }
+    };
+
+    device_command(
+        data,
+        toasts,
+        device,
+        action.command(),
+        send,
+        approved_by,
+    );
+}
+
+fn render_pending_approval(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    let Some(pending) = data.pending_approval.clone() else {
+        return;
+    };
+
+    let now = Utc::now();
+    if pending.is_expired(now) {
+        data.pending_approval = None;
+        toasts.warning(format!(
+            "{} was not approved in time",
+            pending.action
+        ));
+        return;
+    }
+
+    ui.group(|ui| {
+        let seconds = (pending.expires_at - now).num_seconds();
+        ui.colored_label(
+            Color32::YELLOW,
+            format!(
+                "{} on {} requested by {} needs a second operator ({seconds}s left)",
+                pending.action, pending.device, pending.requested_by
+            ),
+        );
+
+        ui.horizontal(|ui| {
+            let name_label = ui.label("Username: ");
+            ui.text_edit_singleline(&mut data.approver_username)
+                .labelled_by(name_label.id);
+        });
+
+        ui.horizontal(|ui| {
+            let password_label = ui.label("Password: ");
+            ui.add(
+                egui::TextEdit::singleline(&mut data.approver_password)
+                    .password(true),
+            )
+            .labelled_by(password_label.id);
+        });
+
+        ui.horizontal(|ui| {
+            let code_label = ui.label("Code: ");
+            ui.text_edit_singleline(&mut data.approver_code)
+                .labelled_by(code_label.id)
+                .on_hover_text("Only for approvers with two-factor login");
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("Approve").clicked() {
+                approve_pending(data, toasts, pending.clone());
+            }
+
+            if ui.button("Cancel").clicked() {
+                data.pending_approval = None;
+                data.approver_password.clear();
+                data.approver_code.clear();
+            }
+        });
+    });
+}
+
+fn approve_pending(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    pending: PendingApproval,
+) {
+    let username = data.approver_username.trim().to_owned();
+    let password = std::mem::take(&mut data.approver_password);
+    let code = std::mem::take(&mut data.approver_code);
+
+    let Some(Authenticated { user: approver, duress }) =
+        verify_credentials(data, toasts, &username, &password)
+    else {
+        return;
+    };
+
+    // Approving takes as much as logging in would
+    if approver.has_totp()
+        && !verify_approver_code(data, toasts, &approver.username, &code)
+    {
+        return;
+    }
+
+    // The approval goes through as if nothing happened
+    if duress {
+        data.duress_alerts.push(DuressAlert {
+            user: approver.username.clone(),
+            action: format!("approve {}", pending.action.command()),
+        });
+    }
+
+    if let Err(err) = pending.check_approver(&approver, Utc::now()) {
+        if let ApprovalError::NotAllowed(denied) = &err {
+            data.events
+                .record(EventKind::AccessDenied {
+                    user: approver.username,
+                    role: denied.role,
+                    action: format!("approve {}", pending.action.command()),
+                });
+        }
+
+        toasts.error(err.to_string());
+        return;
+    }
+    data.login_guard
+        .succeeded(&approver.username);
+
+    // The command runs with the requester's session, which must still be it
+    let requester_present = data
+        .current_session
+        .as_ref()
+        .is_some_and(|session| session.username == pending.requested_by);
+    data.pending_approval = None;
+    data.approver_username.clear();
+
+    if !requester_present {
+        toasts.error(format!(
+            "{} is no longer logged in, request cancelled",
+            pending.requested_by
+        ));
+        return;
+    }
+
+    let Some(device) = data
+        .devices
+        .iter()
+        .position(|device| device.name == pending.device)
+    else {
+        toasts.error(format!(
+            "{} was removed, request cancelled",
+            pending.device
+        ));
+        return;
+    };
+
+    run_critical_command(
+        data,
+        toasts,
+        device,
+        pending.action,
+        Some(approver.username),
+    );
+}
+
+/// Second factor of an approver, failures count like a failed login.
+fn verify_approver_code(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    username: &str,
+    code: &str,
+) -> bool {
+    if code.trim().is_empty() {
+        toasts.error(format!(
+            "{username} needs a code from their authenticator app"
+        ));
+        return false;
+    }
+
+    let now = Utc::now();
+    match data
+        .users
+        .verify_second_factor(username, code, now)
+    {
+        Ok(Some(factor)) => {
+            if let SecondFactor::RecoveryCode { remaining } = factor {
+                toasts.warning(format!(
+                    "Recovery code used, {remaining} left"
+                ));
+            }
+            true
+        }
+        Ok(None) => {
+            count_failed_login(data, toasts, username, now);
+            toasts.error("Invalid code");
+            false
+        }
+        Err(err) => {
+            toasts.error(err.to_string());
+            false
+        }
+    }
+}
+
+/// Queues a command for the device as the session user and logs it. The
+/// permission check itself is done by the [`Api`].
+fn device_command(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    device: usize,
+    command: &str,
+    send: fn(&Api, Role) -> anyhow::Result<()>,
+    approved_by: Option<String>,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    match send(&data.devices[device].api, session.role) {
+        Ok(()) => record_device_command(data, device, command, approved_by),
+        Err(err) => {
+            if let Some(denied) = err.downcast_ref::<PermissionDenied>() {
+                record_denied(data, denied, command);
+            }
+
+            toasts.error(format!("{:?}", err));
+        }
+    }
+}
+
+/// Checks the session role for UI-only actions, denials are shown and
+/// logged.
+fn authorize(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    permission: Permission,
+    action: &str,
+) -> bool {
+    let Some(session) = &data.current_session else {
+        return false;
+    };
+
+    match session.role.require(permission) {
+        Ok(()) => true,
+        Err(denied) => {
+            record_denied(data, &denied, action);
+            toasts.error(denied.to_string());
+            false
+        }
+    }
+}
+
+fn record_denied(data: &mut AppState, denied: &PermissionDenied, action: &str) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    data.events
+        .record(EventKind::AccessDenied {
+            user: session.username.clone(),
+            role: denied.role,
+            action: action.to_owned(),
+        });
+}
+
+fn record_command(
+    data: &mut AppState,
+    command: &str,
+    approved_by: Option<String>,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    data.events.record(EventKind::Command {
+        user: session.username.clone(),
+        command: command.to_owned(),
+        approved_by,
+    });
+}
+
+/// [`record_command`] for a command sent to, or run for, one device.
+fn record_device_command(
+    data: &mut AppState,
+    device: usize,
+    command: &str,
+    approved_by: Option<String>,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    data.events.record_device(
+        &data.devices[device].name,
+        EventKind::Command {
+            user: session.username.clone(),
+            command: command.to_owned(),
+            approved_by,
+        },
+    );
+}
+
+fn render_data(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
+    if data.current_session.is_some() {
+        render_pending_approval(data, toasts, ui);
+    }
+
+    let Some(device) = data.selected_device else {
+        return;
+    };
+
+    if data.current_session.is_some() && data.devices[device].api.exists() {
+        ui.group(|ui| {
+            ui.set_width(ui.available_width());
+            ui.label("General:");
+            if ui.button("Poll").clicked() {
+                device_command(
+                    data,
+                    toasts,
+                    device,
+                    "poll",
+                    Api::send_poll,
+                    None,
+                );
+            }
+
+            if ui.button("Reset").clicked() {
+                critical_command(data, toasts, device, DualAction::Reset);
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Alarm: ");
+
+                for action in [
+                    OperatorAction::Arm,
+                    OperatorAction::Disarm,
+                    OperatorAction::Acknowledge,
+                    OperatorAction::Silence,
+                ] {
+                    if ui.button(action.label()).clicked() {
+                        alarm_action(data, toasts, device, action);
+                    }
+                }
+            });
+
+            if ui.button("Alarm!!!!!!").clicked() {
+                alarm_action(
+                    data,
+                    toasts,
+                    device,
+                    OperatorAction::Panic,
+                );
+                device_command(
+                    data,
+                    toasts,
+                    device,
+                    "lock_back_door",
+                    Api::send_lock_back_door,
+                    None,
+                );
+                device_command(
+                    data,
+                    toasts,
+                    device,
+                    "lock_front_door",
+                    Api::send_lock_front_door,
+                    None,
+                );
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Back: ");
+
+                if ui.button("Lock").clicked() {
+                    device_command(
+                        data,
+                        toasts,
+                        device,
+                        "lock_back_door",
+                        Api::send_lock_back_door,
+                        None,
+                    );
+                }
+
+                if ui.button("Unlock").clicked() {
+                    critical_command(
+                        data,
+                        toasts,
+                        device,
+                        DualAction::UnlockBackDoor,
+                    );
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Front: ");
+
+                if ui.button("Lock").clicked() {
+                    device_command(
+                        data,
+                        toasts,
+                        device,
+                        "lock_front_door",
+                        Api::send_lock_front_door,
+                        None,
+                    );
+                }
+
+                if ui.button("Unlock").clicked() {
+                    critical_command(
+                        data,
+                        toasts,
+                        device,
+                        DualAction::UnlockFrontDoor,
+                    );
+                }
+            });
+        });
+    }
+
+    render_sensors(
+        &data.sensors,
+        &data.devices[device].last_poll_result,
+        ui,
+    );
+}
+
+fn render_sensors(
+    sensors: &SensorMap,
+    poll_result: &PollResult,
+    ui: &mut egui::Ui,
+) {
+    for group in sensors.groups() {
+        ui.group(|ui| {
+            ui.label(group);
+
+            for sensor in sensors
+                .sensors()
+                .iter()
+                .filter(|sensor| sensor.group == group)
+            {
+                if let Some(analog) = &sensor.analog {
+                    let reading = match poll_result.value(&sensor.id) {
+                        Some(value) => format!("{value:.1} {}", analog.unit),
+                        None => "—".to_owned(),
+                    };
+                    let color = if poll_result.get(&sensor.id) {
+                        Color32::LIGHT_RED
+                    } else {
+                        Color32::LIGHT_GREEN
+                    };
+
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}:", sensor.name));
+                        ui.colored_label(color, reading);
+                    });
+                    continue;
+                }
+
+                ui.add(BooleanIndicator {
+                    label: format!("{}:", sensor.name),
+                    value_ref: &poll_result.get(&sensor.id),
+                });
+            }
+        });
+    }
+}
+
+fn render_history(data: &mut AppState, ui: &mut egui::Ui) {
+    egui::CollapsingHeader::new("Event history").show(ui, |ui| {
+        let sensors = data.sensors.clone();
+        let device_names: Vec<String> = data
+            .devices
+            .iter()
+            .map(|device| device.name.clone())
+            .collect();
+        let view = &mut data.history;
+        let mut changed = false;
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Only types:");
+            for event_type in EventType::ALL {
+                changed |= toggle_filter(
+                    ui,
+                    &mut view.query.types,
+                    event_type,
+                    event_type.label(),
+                );
+            }
+        });
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Only sensors:");
+            for sensor in sensors.sensors() {
+                changed |= toggle_filter(
+                    ui,
+                    &mut view.query.sensors,
+                    sensor.id.clone(),
+                    &sensor.name,
+                );
+            }
+        });
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Only devices:");
+            for name in &device_names {
+                changed |= toggle_filter(
+                    ui,
+                    &mut view.query.devices,
+                    name.clone(),
+                    name,
+                );
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("User:");
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut view.user)
+                        .desired_width(100.0),
+                )
+                .changed();
+
+            ui.label("Search:");
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut view.text)
+                        .desired_width(160.0),
+                )
+                .changed();
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("From:");
+            changed |= time_input(ui, &mut view.since);
+            ui.label("To:");
+            changed |= time_input(ui, &mut view.until);
+
+            if ui.button("Clear filters").clicked() {
+                clear_history_filters(view);
+                changed = true;
+            }
+        });
+
+        if changed {
+            view.reload_at = Some(Instant::now() + HISTORY_DEBOUNCE);
+        }
+
+        if view.recorded.is_none() {
+            view.recorded = Some(data.events.subscribe());
+            view.reload_at = Some(Instant::now());
+        }
+        update_history(view, &data.events);
+
+        if let Some(err) = &view.error {
+            ui.colored_label(Color32::RED, err);
+        }
+
+        ui.label(format!(
+            "{} event(s), newest first",
+            view.rows.len()
+        ));
+        ui.separator();
+
+        // Every row is one selectable label, which is exactly this tall
+        let row_height = ui.spacing().interact_size.y;
+        let mut scroll = egui::ScrollArea::vertical()
+            .max_height(300.0)
+            .auto_shrink([false, true]);
+
+        // Waits for the rows without filters
+        if view.loading.is_none()
+            && view.reload_at.is_none()
+            && let Some(id) = view.scroll_to.take()
+            && let Some(index) = view
+                .rows
+                .iter()
+                .rev()
+                .position(|event| event.id == id)
+        {
+            let spacing = ui.spacing().item_spacing.y;
+            scroll = scroll
+                .vertical_scroll_offset(index as f32 * (row_height + spacing));
+        }
+
+        scroll.show_rows(
+            ui,
+            row_height,
+            view.rows.len(),
+            |ui, range| {
+                for index in range {
+                    let event = &view.rows[view.rows.len() - 1 - index];
+                    let device = event
+                        .device
+                        .as_ref()
+                        .map_or_else(String::new, |name| format!("[{name}] "));
+                    let text = format!(
+                        "{}  {:<10}  {device}{}",
+                        local_time(event.timestamp),
+                        event.kind.event_type().label(),
+                        event.kind
+                    );
+
+                    if ui
+                        .selectable_label(view.selected == Some(event.id), text)
+                        .clicked()
+                    {
+                        view.selected = Some(event.id);
+                    }
+                }
+            },
+        );
+
+        let Some(selected) = view.selected.and_then(|id| {
+            view.rows
+                .iter()
+                .find(|event| event.id == id)
+        }) else {
+            return;
+        };
+
+        ui.separator();
+        ui.label(format!(
+            "Event #{} at {}",
+            selected.id,
+            local_time(selected.timestamp)
+        ));
+        ui.label(
+            serde_json::to_string_pretty(&selected.kind).unwrap_or_default(),
+        );
+
+        if ui.button("Show in context").clicked() {
+            let id = selected.id;
+            clear_history_filters(view);
+            view.scroll_to = Some(id);
+        }
+    });
+}
+
+/// Checkbox adding or removing `value` from a filter list.
+fn toggle_filter<T: PartialEq>(
+    ui: &mut egui::Ui,
+    selected: &mut Vec<T>,
+    value: T,
+    label: &str,
+) -> bool {
+    let mut checked = selected.contains(&value);
+    if !ui
+        .checkbox(&mut checked, label)
+        .changed()
+    {
+        return false;
+    }
+
+    if checked {
+        selected.push(value);
+    } else {
+        selected.retain(|other| *other != value);
+    }
+
+    true
+}
+
+fn time_input(ui: &mut egui::Ui, input: &mut String) -> bool {
+    let valid = input.trim().is_empty() || parse_local_time(input).is_some();
+    let mut edit = egui::TextEdit::singleline(input)
+        .hint_text("YYYY-MM-DD HH:MM")
+        .desired_width(130.0);
+    if !valid {
+        edit = edit.text_color(Color32::RED);
+    }
+
+    ui.add(edit).changed()
+}
+
+fn clear_history_filters(view: &mut HistoryView) {
+    view.query = EventQuery::default();
+    view.user.clear();
+    view.text.clear();
+    view.since.clear();
+    view.until.clear();
+    view.reload_at = Some(Instant::now());
+}
+
+/// Queries the files once the filters have settled, takes in the answer
+/// and appends matching events recorded since.
+fn update_history(view: &mut HistoryView, events: &EventLog) {
+    if view
+        .reload_at
+        .is_some_and(|at| Instant::now() >= at)
+    {
+        view.reload_at = None;
+        start_history_query(view, events);
+    }
+
+    let answer = match view
+        .loading
+        .as_ref()
+        .map(|loading| loading.try_recv())
+    {
+        None | Some(Err(TryRecvError::Empty)) => None,
+        Some(Ok(answer)) => Some(answer),
+        Some(Err(TryRecvError::Disconnected)) => Some(Err(anyhow::anyhow!(
+            "The history query stopped"
+        ))),
+    };
+    if let Some(answer) = answer {
+        match answer {
+            Ok(rows) => {
+                view.rows = rows;
+                view.error = None;
+            }
+            Err(err) => view.error = Some(format!("{err:?}")),
+        }
+
+        view.loading = None;
+        // The query may have found some of them already
+        let newest = view
+            .rows
+            .last()
+            .map_or(0, |event| event.id);
+        let tail = std::mem::take(&mut view.tail);
+        view.rows.extend(
+            tail.into_iter()
+                .filter(|event| event.id > newest),
+        );
+    }
+
+    if let Some(recorded) = &view.recorded {
+        for event in recorded.try_iter() {
+            if !view.query.matches(&event) {
+                continue;
+            }
+
+            if view.loading.is_some() {
+                view.tail.push(event);
+            } else {
+                view.rows.push(event);
+            }
+        }
+    }
+
+    if view.rows.len() > HISTORY_LIMIT {
+        view.rows
+            .drain(..view.rows.len() - HISTORY_LIMIT);
+    }
+}
+
+/// Replaces a query still running, its answer is dropped.
+fn start_history_query(view: &mut HistoryView, events: &EventLog) {
+    let non_empty = |input: &str| {
+        let input = input.trim();
+        (!input.is_empty()).then(|| input.to_owned())
+    };
+
+    view.query.user = non_empty(&view.user);
+    view.query.text = non_empty(&view.text);
+    view.query.since = parse_local_time(&view.since);
+    view.query.until = parse_local_time(&view.until);
+    view.query.limit = Some(HISTORY_LIMIT);
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = events.reader();
+    let query = view.query.clone();
+    std::thread::spawn(move || {
+        let _ = sender.send(reader.query(&query));
+    });
+
+    view.loading = Some(receiver);
+    view.tail.clear();
+}
+
+/// Accepts `YYYY-MM-DD HH:MM` or just `YYYY-MM-DD` in local time.
+fn parse_local_time(input: &str) -> Option<DateTime<Utc>> {
+    let input = input.trim();
+    let naive = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })?;
+
+    naive
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+fn local_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn render_login(data: &mut AppState, toasts: &mut Toasts, ui: &mut egui::Ui) {
+    if let Some(pending) = data.pending_login.clone() {
+        if Utc::now() > pending.expires_at {
+            data.pending_login = None;
+            toasts.warning("Second factor timed out, log in again");
+        } else {
+            render_second_factor(data, toasts, ui, &pending);
+            return;
+        }
+    }
+
+    let username = data.input_username.clone();
+    ui.label(format!("Will login as [{username}]"));
+
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        let name_label = ui.label("Username: ");
+        ui.text_edit_singleline(&mut data.input_username)
+            .labelled_by(name_label.id);
+    });
+
+    ui.horizontal(|ui| {
+        let name_label = ui.label("Password: ");
+        ui.add(
+            egui::TextEdit::singleline(&mut data.input_password).password(true),
+        )
+        .labelled_by(name_label.id);
+    });
+
+    if ui.button("Login").clicked() {
+        try_login(data, toasts);
+    }
+}
+
+fn render_second_factor(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+    pending: &PendingLogin,
+) {
+    ui.label(format!(
+        "Second factor for [{}]",
+        pending.username
+    ));
+
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        let code_label = ui.label("Code: ");
+        ui.text_edit_singleline(&mut data.input_code)
+            .labelled_by(code_label.id)
+            .on_hover_text("Authenticator code or a recovery code");
+    });
+
+    ui.horizontal(|ui| {
+        if ui.button("Verify").clicked() {
+            try_second_factor(data, toasts, pending);
+        }
+
+        if ui.button("Cancel").clicked() {
+            data.pending_login = None;
+            data.input_code.clear();
+        }
+    });
+}
+
+fn try_login(data: &mut AppState, toasts: &mut Toasts) {
+    let username = data.input_username.trim().to_owned();
+    let password = std::mem::take(&mut data.input_password);
+
+    let Some(Authenticated { user, duress }) =
+        verify_credentials(data, toasts, &username, &password)
+    else {
+        return;
+    };
+
+    // The failure counters keep running until the second factor passes too
+    if user.has_totp() {
+        data.pending_login = Some(PendingLogin {
+            username: user.username,
+            expires_at: Utc::now() + data.config.sessions.second_factor(),
+            duress,
+        });
+        data.input_code.clear();
+        return;
+    }
+
+    data.login_guard
+        .succeeded(&user.username);
+    start_session(data, toasts, &user, duress);
+}
+
+fn try_second_factor(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    pending: &PendingLogin,
+) {
+    let code = std::mem::take(&mut data.input_code);
+    let now = Utc::now();
+
+    if let Err(locked) = data
+        .login_guard
+        .check(&pending.username, now)
+    {
+        data.pending_login = None;
+        toasts.error(locked.to_string());
+        return;
+    }
+
+    let verified =
+        data.users
+            .verify_second_factor(&pending.username, &code, now);
+
+    match verified {
+        Ok(Some(factor)) => {
+            data.pending_login = None;
+            data.login_guard
+                .succeeded(&pending.username);
+
+            if let SecondFactor::RecoveryCode { remaining } = factor {
+                toasts.warning(format!(
+                    "Recovery code used, {remaining} left"
+                ));
+            }
+
+            if let Some(user) = data
+                .users
+                .find(&pending.username)
+                .cloned()
+            {
+                start_session(data, toasts, &user, pending.duress);
+            }
+        }
+        Ok(None) => {
+            count_failed_login(data, toasts, &pending.username, now);
+            toasts.error("Invalid code");
+        }
+        Err(err) => {
+            data.pending_login = None;
+            toasts.error(err.to_string());
+        }
+    }
+}
+
+/// A duress login looks and logs like any other, only the alert tells.
+fn start_session(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    user: &User,
+    duress: bool,
+) {
+    data.events
+        .record(EventKind::Login { user: user.username.clone() });
+    data.current_session = Some(SessionData {
+        username: user.username.clone(),
+        role: user.role,
+        begin_timestamp: Utc::now(),
+        timeout_time: user.session_timeout(),
+        duress,
+        last_activity: Utc::now(),
+        locked: false,
+        warned_for: None,
+    });
+
+    if duress {
+        data.duress_alerts.push(DuressAlert {
+            user: user.username.clone(),
+            action: "login".to_owned(),
+        });
+    }
+
+    toasts.info(format!(
+        "Logged in as {}",
+        user.username
+    ));
+}
+
+/// Password check behind the lockout, shared by logins and approvals.
+/// Failures are counted, logged and may raise the alarm. Callers clear the
+/// counters once everything they ask for checked out.
+fn verify_credentials(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    username: &str,
+    password: &str,
+) -> Option<Authenticated> {
+    let now = Utc::now();
+
+    if let Err(locked) = data.login_guard.check(username, now) {
+        toasts.error(locked.to_string());
+        return None;
+    }
+
+    let authenticated = data
+        .users
+        .authenticate(username, password);
+
+    if authenticated.is_none() {
+        count_failed_login(data, toasts, username, now);
+        toasts.error("Invalid credentials");
+    }
+
+    authenticated
+}
+
+fn count_failed_login(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    username: &str,
+    now: DateTime<Utc>,
+) {
+    let failure = data.login_guard.failed(username, now);
+    data.events
+        .record(EventKind::LoginFailed {
+            user: username.to_owned(),
+            failures: failure.failures,
+            locked_until: failure.locked_until,
+        });
+
+    if !failure.raise_alarm {
+        return;
+    }
+
+    // The console guards every device, so every device goes off
+    for device in &mut data.devices {
+        if let Some(transition) = device
+            .alarm
+            .sensor(AlarmCause::FailedLogins, now)
+        {
+            report_transition(
+                &mut data.events,
+                &device.name,
+                &transition,
+                toasts,
+            );
+        }
+    }
+}
+
+/// Enable or disable the session user's second factor. Turning it off
+/// takes a current code, a walked-away session is not enough.
+fn render_totp_menu(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+    let username = session.username.clone();
+
+    let enrolled = data
+        .users
+        .find(&username)
+        .is_some_and(User::has_totp);
+
+    if !enrolled {
+        if ui.button("Enable two-factor").clicked() {
+            data.totp_setup = Some(TotpSetup::new());
+        }
+        return;
+    }
+
+    ui.horizontal(|ui| {
+        let code_label = ui.label("Code: ");
+        ui.text_edit_singleline(&mut data.input_code)
+            .labelled_by(code_label.id);
+    });
+
+    if !ui
+        .button("Disable two-factor")
+        .clicked()
+    {
+        return;
+    }
+
+    let code = std::mem::take(&mut data.input_code);
+    let result = data
+        .users
+        .verify_second_factor(&username, &code, Utc::now())
+        .and_then(|verified| match verified {
+            Some(_) => data.users.disable_totp(&username),
+            None => Err(anyhow::anyhow!("Invalid code")),
+        });
+
+    match result {
+        Ok(()) => {
+            record_command(data, "disable_totp", None);
+            toasts.info("Two-factor login disabled");
+        }
+        Err(err) => {
+            toasts.error(err.to_string());
+        }
+    }
+}
+
+fn render_totp_setup(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    let Some(session) = &data.current_session else {
+        data.totp_setup = None;
+        return;
+    };
+    let username = session.username.clone();
+
+    let Some(setup) = data.totp_setup.as_mut() else {
+        return;
+    };
+
+    let mut open = true;
+    let mut confirm = false;
+    let mut done = false;
+
+    egui::Window::new("Two-factor setup")
+        .collapsible(false)
+        .resizable(false)
+        .open(&mut open)
+        .show(ui.ctx(), |ui| {
+            if setup.enrolled {
+                ui.label("Write these recovery codes down now, each works once and they are not shown again:");
+                ui.monospace(setup.recovery_codes.join("\n"));
+
+                done = ui.button("Done").clicked();
+                return;
+            }
+
+            ui.label("Scan the code with an authenticator app:");
+            let uri = totp::provisioning_uri(&username, &setup.secret);
+            ui.add(QrCodeImage { data: &uri, module_size: 4.0 });
+
+            ui.label("Or enter this key by hand:");
+            ui.monospace(totp::base32_encode(&setup.secret));
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                let code_label = ui.label("Code from the app: ");
+                ui.text_edit_singleline(&mut setup.code)
+                    .labelled_by(code_label.id);
+            });
+
+            confirm = ui.button("Confirm").clicked();
+        });
+
+    if !open || done {
+        data.totp_setup = None;
+        return;
+    }
+
+    if !confirm {
+        return;
+    }
+
+    let now = Utc::now().timestamp().max(0) as u64;
+    let Some(step) = totp::verify(&setup.secret, &setup.code, now, None) else {
+        setup.code.clear();
+        toasts
+            .error("Invalid code, check the clock of the device with the app");
+        return;
+    };
+
+    let enrolled = data.users.enroll_totp(
+        &username,
+        &setup.secret,
+        &setup.recovery_codes,
+        step,
+    );
+
+    match enrolled {
+        Ok(()) => {
+            setup.enrolled = true;
+            record_command(data, "enable_totp", None);
+            toasts.success("Two-factor login enabled");
+        }
+        Err(err) => {
+            toasts.error(err.to_string());
+        }
+    }
+}
+
+enum SessionAction {
+    Logout,
+    Lock,
+    Extend,
+}
+
+fn render_session(
+    session: &mut SessionData,
+    password: &mut String,
+    ui: &mut egui::Ui,
+) -> Option<SessionAction> {
+    display_session_header(session, ui, session.expires_at());
+
+    ui.separator();
+
+    let mut action = None;
+
+    ui.horizontal(|ui| {
+        let password_label = ui.label("Password: ");
+        ui.add(egui::TextEdit::singleline(password).password(true))
+            .labelled_by(password_label.id);
+    });
+
+    if ui
+        .button("Extend session")
+        .on_hover_text("Starts the session time over")
+        .clicked()
+    {
+        action = Some(SessionAction::Extend);
+    }
+
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        if ui.button("Lock").clicked() {
+            action = Some(SessionAction::Lock);
+        }
+
+        if ui.button("Logout").clicked() {
+            action = Some(SessionAction::Logout);
+        }
+    });
+
+    action
+}
+
+/// Per-frame session bookkeeping: input keeps it awake, idling locks it,
+/// the session time running out logs it out. A toast warns before either.
+pub fn update_session(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ctx: &egui::Context,
+) {
+    let now = Utc::now();
+    let active = ctx.input(|input| !input.events.is_empty());
+
+    let Some(session) = data.current_session.as_mut() else {
+        return;
+    };
+
+    if now > session.expires_at() {
+        end_session(data, toasts, "Session expired");
+        return;
+    }
+
+    if session.locked {
+        return;
+    }
+
+    if active {
+        session.last_activity = now;
+    }
+
+    let sessions = &data.config.sessions;
+    let idle_deadline = session.last_activity + sessions.idle_lock();
+    if now > idle_deadline {
+        session.locked = true;
+        data.input_password.clear();
+        toasts.warning("Locked after inactivity");
+        return;
+    }
+
+    let deadline = idle_deadline.min(session.expires_at());
+    if deadline - now <= sessions.expiry_warning()
+        && session.warned_for != Some(deadline)
+    {
+        session.warned_for = Some(deadline);
+
+        let seconds = (deadline - now).num_seconds().max(1);
+        if deadline == idle_deadline {
+            toasts.warning(format!(
+                "Locking in {seconds}s without input"
+            ));
+        } else {
+            toasts.warning(format!(
+                "Session expires in {seconds}s, extend it from the Auth menu"
+            ));
+        }
+    }
+}
+
+pub fn is_locked(data: &AppState) -> bool {
+    data.current_session
+        .as_ref()
+        .is_some_and(|session| session.locked)
+}
+
+/// Shown instead of the whole window while the session is locked: the
+/// sensors stay visible, every control is gone.
+pub fn render_lock_screen(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    ui: &mut egui::Ui,
+) {
+    let Some(session) = &data.current_session else {
+        return;
+    };
+
+    ui.heading("Анти-(Анти-Автомат) System");
+    ui.label(format!(
+        "Locked, session of [{}]",
+        session.username
+    ));
+
+    ui.horizontal(|ui| {
+        let password_label = ui.label("Password: ");
+        ui.add(
+            egui::TextEdit::singleline(&mut data.input_password).password(true),
+        )
+        .labelled_by(password_label.id);
+
+        if ui.button("Unlock").clicked()
+            && reauthenticate(data, toasts, "unlock")
+            && let Some(session) = data.current_session.as_mut()
+        {
+            session.locked = false;
+            session.last_activity = Utc::now();
+        }
+
+        if ui.button("Logout").clicked() {
+            end_session(data, toasts, "Logged out");
+        }
+    });
+
+    ui.separator();
+
+    if let Some(device) = data.selected_device {
+        render_sensors(
+            &data.sensors,
+            &data.devices[device].last_poll_result,
+            ui,
+        );
+    }
+}
+
+/// Asks the session user's password again, counted like a login. The
+/// duress password works here as well and sets off the alert.
+fn reauthenticate(
+    data: &mut AppState,
+    toasts: &mut Toasts,
+    action: &str,
+) -> bool {
+    let Some(session) = &data.current_session else {
+        return false;
+    };
+    let username = session.username.clone();
+    let password = std::mem::take(&mut data.input_password);
+
+    let Some(Authenticated { duress, .. }) =
+        verify_credentials(data, toasts, &username, &password)
+    else {
+        return false;
+    };
+    data.login_guard.succeeded(&username);
+
+    if duress {
+        data.duress_alerts.push(DuressAlert {
+            user: username,
+            action: action.to_owned(),
+        });
+
+        if let Some(session) = data.current_session.as_mut() {
+            session.duress = true;
+        }
+    }
+
+    true
+}
+
+fn extend_session(data: &mut AppState, toasts: &mut Toasts) {
+    let Some(session) = data.current_session.as_mut() else {
+        return;
+    };
+
+    let now = Utc::now();
+    session.begin_timestamp = now;
+    session.last_activity = now;
+
+    toasts.info("Session extended");
+}
+
+fn end_session(data: &mut AppState, toasts: &mut Toasts, message: &str) {
+    data.current_session = None;
+    data.pending_approval = None;
+    data.totp_setup = None;
+    data.input_password.clear();
+
+    toasts.warning(message);
 }
 
 fn display_session_header(
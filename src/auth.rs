@@ -1,3 +0,0 @@
-pub fn is_valid(username: &str, password: &str) -> bool {
-    username == "Sigma" && password == "zZz_amogus_sussy_baka1337_zZz"
-}
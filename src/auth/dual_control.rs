@@ -0,0 +1,164 @@
+use crate::auth::permissions::{Permission, PermissionDenied};
+use crate::auth::users::User;
+use anyhow::Context;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Commands that can be put under two-person control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DualAction {
+    UnlockBackDoor,
+    UnlockFrontDoor,
+    Reset,
+    /// Disarming while an alarm is triggered, acknowledged or silenced.
+    CancelAlarm,
+}
+
+impl DualAction {
+    pub fn permission(self) -> Permission {
+        match self {
+            DualAction::UnlockBackDoor | DualAction::UnlockFrontDoor => {
+                Permission::UnlockDoors
+            }
+            DualAction::Reset => Permission::Reset,
+            DualAction::CancelAlarm => Permission::DisarmAlarm,
+        }
+    }
+
+    /// Name the command is logged under.
+    pub fn command(self) -> &'static str {
+        match self {
+            DualAction::UnlockBackDoor => "unlock_back_door",
+            DualAction::UnlockFrontDoor => "unlock_front_door",
+            DualAction::Reset => "reset",
+            DualAction::CancelAlarm => "alarm disarm",
+        }
+    }
+}
+
+impl Display for DualAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DualAction::UnlockBackDoor => "Unlock back door",
+            DualAction::UnlockFrontDoor => "Unlock front door",
+            DualAction::Reset => "Reset device",
+            DualAction::CancelAlarm => "Cancel alarm",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Which commands need a second operator, and how long they have.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DualControlPolicy {
+    pub actions: Vec<DualAction>,
+    pub window_secs: u32,
+}
+
+impl Default for DualControlPolicy {
+    fn default() -> Self {
+        Self { actions: Vec::new(), window_secs: 60 }
+    }
+}
+
+impl DualControlPolicy {
+    /// Defaults when the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn requires(&self, action: DualAction) -> bool {
+        self.actions.contains(&action)
+    }
+
+    pub fn window(&self) -> TimeDelta {
+        TimeDelta::seconds(self.window_secs as i64)
+    }
+}
+
+/// A command waiting for its second operator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingApproval {
+    pub action: DualAction,
+    /// Name of the device the command is for.
+    pub device: String,
+    pub requested_by: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PendingApproval {
+    pub fn new(
+        action: DualAction,
+        device: &str,
+        requested_by: &str,
+        policy: &DualControlPolicy,
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            action,
+            device: device.to_owned(),
+            requested_by: requested_by.to_owned(),
+            expires_at: now + policy.window(),
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now > self.expires_at
+    }
+
+    /// The approver has to be somebody else who may run the command too.
+    pub fn check_approver(
+        &self,
+        approver: &User,
+        now: DateTime<Utc>,
+    ) -> Result<(), ApprovalError> {
+        if self.is_expired(now) {
+            return Err(ApprovalError::Expired);
+        }
+
+        if approver.username == self.requested_by {
+            return Err(ApprovalError::SameUser);
+        }
+
+        approver
+            .role
+            .require(self.action.permission())
+            .map_err(ApprovalError::NotAllowed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApprovalError {
+    Expired,
+    SameUser,
+    NotAllowed(PermissionDenied),
+}
+
+impl Display for ApprovalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApprovalError::Expired => write!(f, "The approval window expired"),
+            ApprovalError::SameUser => {
+                write!(
+                    f,
+                    "The second operator must be a different user"
+                )
+            }
+            ApprovalError::NotAllowed(denied) => write!(f, "{denied}"),
+        }
+    }
+}
+
+impl std::error::Error for ApprovalError {}
@@ -0,0 +1,52 @@
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Somebody used their duress password. Nothing on screen may change, the
+/// alert only goes to the log and the notifications.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuressAlert {
+    pub user: String,
+    /// What was done under duress, e.g. `login` or `alarm disarm`.
+    pub action: String,
+}
+
+/// Programs to run on duress, e.g. a script that calls the police line. Kept
+/// for setups from before `notifications.toml`, see
+/// [`crate::notify::NotificationPolicy::add_duress_hooks`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DuressPolicy {
+    /// Each hook is a program followed by its arguments. The alert is
+    /// passed in `CONTROL_DURESS_USER` and `CONTROL_DURESS_ACTION`.
+    pub hooks: Vec<Vec<String>>,
+}
+
+impl DuressPolicy {
+    /// No hooks when the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy: Self = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).with_context(|| {
+                    format!("Failed to parse {}", path.display())
+                })?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        if policy.hooks.iter().any(Vec::is_empty) {
+            bail!(
+                "{}: a duress hook has no program",
+                path.display()
+            );
+        }
+
+        Ok(policy)
+    }
+}
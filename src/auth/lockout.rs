@@ -0,0 +1,352 @@
+use anyhow::Context;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// How failed logins are punished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failures per user before the first lockout.
+    pub free_attempts: u32,
+    /// Failures over all users before everybody is locked out.
+    pub global_free_attempts: u32,
+    /// First lockout, doubled with every further failure.
+    pub base_lockout: TimeDelta,
+    pub max_lockout: TimeDelta,
+    /// Every this many failures in a row raise an alarm.
+    pub alarm_after: u32,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            global_free_attempts: 10,
+            base_lockout: TimeDelta::seconds(30),
+            max_lockout: TimeDelta::hours(1),
+            alarm_after: 5,
+        }
+    }
+}
+
+/// Users remembered at most, guessed names must not grow the file forever.
+const MAX_USERS: usize = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Counter {
+    failures: u32,
+    locked_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_failure: Option<DateTime<Utc>>,
+}
+
+impl Counter {
+    fn locked_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.locked_until
+            .filter(|until| *until > now)
+    }
+
+    fn fail(
+        &mut self,
+        free_attempts: u32,
+        policy: &LockoutPolicy,
+        now: DateTime<Utc>,
+    ) {
+        self.failures += 1;
+        self.last_failure = Some(now);
+        if self.failures < free_attempts {
+            return;
+        }
+
+        let doublings = (self.failures - free_attempts).min(16);
+        let lockout =
+            (policy.base_lockout * 2i32.pow(doublings)).min(policy.max_lockout);
+        self.locked_until = Some(now + lockout);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counters {
+    users: HashMap<String, Counter>,
+    global: Counter,
+}
+
+/// Login refused without even looking at the password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedOut {
+    /// `None` when the lock is on all logins, not just this user.
+    pub username: Option<String>,
+    pub until: DateTime<Utc>,
+}
+
+impl Display for LockedOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = (self.until - Utc::now())
+            .num_seconds()
+            .max(1);
+        match &self.username {
+            Some(username) => write!(
+                f,
+                "Too many failed attempts for {username}, try again in {seconds}s"
+            ),
+            None => write!(
+                f,
+                "Too many failed logins, all logins are blocked for {seconds}s"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockedOut {}
+
+/// What a failed attempt led to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub failures: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub raise_alarm: bool,
+}
+
+/// Failed-attempt counters, per user and over all users. Saved on every
+/// change so restarting the app does not reset them.
+#[derive(Debug)]
+pub struct LoginGuard {
+    path: PathBuf,
+    policy: LockoutPolicy,
+    counters: Counters,
+}
+
+impl LoginGuard {
+    /// A file that does not parse is started over with a warning, the
+    /// window must come up to let anyone in at all.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        policy: LockoutPolicy,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let counters = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).unwrap_or_else(|err| {
+                    eprintln!(
+                        "Ignoring the login counters in {}, they do not parse: {err}",
+                        path.display()
+                    );
+                    Counters::default()
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Counters::default()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        Ok(Self { path, policy, counters })
+    }
+
+    /// Current lockouts stay, the policy applies to the next failure.
+    pub fn set_policy(&mut self, policy: LockoutPolicy) {
+        self.policy = policy;
+    }
+
+    /// Has to pass before the password is checked.
+    pub fn check(
+        &self,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), LockedOut> {
+        if let Some(until) = self.counters.global.locked_at(now) {
+            return Err(LockedOut { username: None, until });
+        }
+
+        let user = self.counters.users.get(username);
+        if let Some(until) = user.and_then(|counter| counter.locked_at(now)) {
+            return Err(LockedOut {
+                username: Some(username.to_owned()),
+                until,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn failed(&mut self, username: &str, now: DateTime<Utc>) -> Failure {
+        let policy = &self.policy;
+        self.counters
+            .global
+            .fail(policy.global_free_attempts, policy, now);
+
+        let counter = self
+            .counters
+            .users
+            .entry(username.to_owned())
+            .or_default();
+        counter.fail(policy.free_attempts, policy, now);
+
+        let global_failures = self.counters.global.failures;
+        let failure = Failure {
+            failures: counter.failures,
+            locked_until: counter.locked_until,
+            raise_alarm: policy.alarm_after > 0
+                && (counter
+                    .failures
+                    .is_multiple_of(policy.alarm_after)
+                    || global_failures.is_multiple_of(policy.alarm_after)),
+        };
+
+        self.prune(now);
+        self.save();
+        failure
+    }
+
+    /// A good password clears the user's counter and the global one, the
+    /// lockouts of other users stay.
+    pub fn succeeded(&mut self, username: &str) {
+        self.counters.users.remove(username);
+        self.counters.global = Counter::default();
+        self.save();
+    }
+
+    /// Forgets users that have not failed for longer than the longest
+    /// lockout, then the quietest ones above [`MAX_USERS`].
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let forget_before = now - self.policy.max_lockout;
+        self.counters
+            .users
+            .retain(|_, counter| {
+                counter.locked_at(now).is_some()
+                    || counter
+                        .last_failure
+                        .is_some_and(|at| at > forget_before)
+            });
+
+        let excess = self
+            .counters
+            .users
+            .len()
+            .saturating_sub(MAX_USERS);
+        if excess == 0 {
+            return;
+        }
+
+        let mut quietest: Vec<_> = self
+            .counters
+            .users
+            .iter()
+            .map(|(username, counter)| (counter.last_failure, username.clone()))
+            .collect();
+        quietest.sort();
+        for (_, username) in quietest.into_iter().take(excess) {
+            self.counters.users.remove(&username);
+        }
+    }
+
+    fn save(&self) {
+        if let Err(err) = self.write() {
+            eprintln!(
+                "Failed to save login counters to {}: {err:?}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Write next to the file and swap, a crash must not unlock everyone
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(
+            &temp,
+            serde_json::to_vec_pretty(&self.counters)?,
+        )
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, &self.path).with_context(|| {
+            format!(
+                "Failed to replace {}",
+                self.path.display()
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockouts_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockout.json");
+        let now = Utc::now();
+        let mut guard =
+            LoginGuard::open(&path, LockoutPolicy::default()).unwrap();
+        for _ in 0..3 {
+            guard.failed("admin", now);
+        }
+        assert!(guard.check("admin", now).is_err());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let guard = LoginGuard::open(&path, LockoutPolicy::default()).unwrap();
+        assert!(guard.check("admin", now).is_err());
+        assert!(guard.check("guard", now).is_ok());
+    }
+
+    #[test]
+    fn a_corrupt_file_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockout.json");
+        std::fs::write(&path, "{\"users\": {\"adm").unwrap();
+
+        let mut guard =
+            LoginGuard::open(&path, LockoutPolicy::default()).unwrap();
+        assert!(guard.check("admin", Utc::now()).is_ok());
+        guard.failed("admin", Utc::now());
+        assert!(
+            LoginGuard::open(&path, LockoutPolicy::default())
+                .unwrap()
+                .counters
+                .users
+                .contains_key("admin")
+        );
+    }
+
+    #[test]
+    fn quiet_and_surplus_users_are_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockout.json");
+        let policy = LockoutPolicy::default();
+        let start = Utc::now();
+        let mut guard = LoginGuard::open(&path, policy.clone()).unwrap();
+        guard.failed("admin", start);
+
+        // Long after the longest lockout
+        let later = start + policy.max_lockout + TimeDelta::seconds(1);
+        for index in 0..MAX_USERS {
+            guard.counters.users.insert(
+                format!("guess{index}"),
+                Counter {
+                    failures: 1,
+                    locked_until: None,
+                    last_failure: Some(
+                        later + TimeDelta::seconds(index as i64),
+                    ),
+                },
+            );
+        }
+        guard.failed("latest", later + TimeDelta::hours(1));
+
+        let users = &guard.counters.users;
+        assert!(!users.contains_key("admin"));
+        assert_eq!(users.len(), MAX_USERS);
+        assert!(!users.contains_key("guess0"));
+        assert!(users.contains_key("guess1"));
+        assert!(users.contains_key("latest"));
+    }
+}
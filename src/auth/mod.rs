@@ -0,0 +1,6 @@
+pub mod dual_control;
+pub mod duress;
+pub mod lockout;
+pub mod permissions;
+pub mod totp;
+pub mod users;
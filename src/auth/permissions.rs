@@ -0,0 +1,102 @@
+use crate::alarm::OperatorAction;
+use crate::auth::users::Role;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    ViewHistory,
+    Poll,
+    LockDoors,
+    UnlockDoors,
+    Reset,
+    ArmAlarm,
+    DisarmAlarm,
+    AcknowledgeAlarm,
+    SilenceAlarm,
+    Panic,
+    ManageUsers,
+    Configure,
+}
+
+impl Permission {
+    pub fn for_alarm(action: OperatorAction) -> Self {
+        match action {
+            OperatorAction::Arm => Permission::ArmAlarm,
+            OperatorAction::Disarm => Permission::DisarmAlarm,
+            OperatorAction::Panic => Permission::Panic,
+            OperatorAction::Acknowledge => Permission::AcknowledgeAlarm,
+            OperatorAction::Silence => Permission::SilenceAlarm,
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Permission::ViewHistory => "view the event history",
+            Permission::Poll => "poll the device",
+            Permission::LockDoors => "lock doors",
+            Permission::UnlockDoors => "unlock doors",
+            Permission::Reset => "reset the device",
+            Permission::ArmAlarm => "arm the alarm",
+            Permission::DisarmAlarm => "disarm the alarm",
+            Permission::AcknowledgeAlarm => "acknowledge alarms",
+            Permission::SilenceAlarm => "silence alarms",
+            Permission::Panic => "raise a panic alarm",
+            Permission::ManageUsers => "manage users",
+            Permission::Configure => "change the settings",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl Role {
+    /// Every role can do everything the roles below it can.
+    pub fn allows(self, permission: Permission) -> bool {
+        let required = match permission {
+            Permission::ViewHistory => Role::Viewer,
+            Permission::Poll
+            | Permission::LockDoors
+            | Permission::ArmAlarm
+            | Permission::AcknowledgeAlarm
+            | Permission::Panic => Role::Guard,
+            Permission::UnlockDoors
+            | Permission::Reset
+            | Permission::DisarmAlarm
+            | Permission::SilenceAlarm => Role::Supervisor,
+            Permission::ManageUsers | Permission::Configure => Role::Admin,
+        };
+
+        self >= required
+    }
+
+    pub fn require(
+        self,
+        permission: Permission,
+    ) -> Result<(), PermissionDenied> {
+        if self.allows(permission) {
+            Ok(())
+        } else {
+            Err(PermissionDenied { role: self, permission })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionDenied {
+    pub role: Role,
+    pub permission: Permission,
+}
+
+impl Display for PermissionDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Permission denied: {} may not {}",
+            self.role, self.permission
+        )
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
@@ -0,0 +1,309 @@
+//! RFC 6238 time-based one-time passwords (HMAC-SHA1, 30 s steps, 6
+//! digits), as understood by the usual authenticator apps. Only the local
+//! clock is needed.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+pub const DIGITS: u32 = 6;
+pub const STEP_SECS: u64 = 30;
+pub const ISSUER: &str = "Control";
+const SECRET_LEN: usize = 20;
+/// Codes one step before or after are accepted for clock drift.
+const ALLOWED_SKEW: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// What the user store keeps for an enrolled user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32, the same string the authenticator app got.
+    pub secret: String,
+    /// Argon2 hashes of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+    /// Step of the last accepted code, a code cannot be used twice.
+    #[serde(default)]
+    pub last_used_step: Option<u64>,
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Ten single-use codes like `k3x9-7fqa`.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            OsRng.fill_bytes(&mut bytes);
+            let chars: String = bytes
+                .iter()
+                .map(|byte| {
+                    RECOVERY_ALPHABET[*byte as usize % RECOVERY_ALPHABET.len()]
+                        as char
+                })
+                .collect();
+
+            format!("{}-{}", &chars[..4], &chars[4..])
+        })
+        .collect()
+}
+
+/// Whether `code` could be one of [`generate_recovery_codes`], checking
+/// the hashes is only worth it then.
+pub fn is_recovery_code(code: &str) -> bool {
+    let code = code.trim().as_bytes();
+    code.len() == 9
+        && code[4] == b'-'
+        && code
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| i == 4 || RECOVERY_ALPHABET.contains(byte))
+}
+
+/// RFC 4226 HOTP value for `counter`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0F) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7F,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    value % 10u32.pow(digits)
+}
+
+pub fn format_code(code: u32, digits: u32) -> String {
+    format!(
+        "{code:0width$}",
+        width = digits as usize
+    )
+}
+
+/// The time step the code belongs to, if it is valid at `unix_secs` and
+/// newer than `last_used_step`.
+pub fn verify(
+    secret: &[u8],
+    code: &str,
+    unix_secs: u64,
+    last_used_step: Option<u64>,
+) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize
+        || !code.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let current = unix_secs / STEP_SECS;
+    (current.saturating_sub(ALLOWED_SKEW)..=current + ALLOWED_SKEW)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| format_code(hotp(secret, *step, DIGITS), DIGITS) == code)
+}
+
+/// `otpauth://` link for the enrollment QR code.
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{ISSUER}:{}?secret={}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        percent_encode(account),
+        base32_encode(secret)
+    )
+}
+
+/// RFC 4648 base32 without padding, which is what authenticators expect.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(
+                BASE32_ALPHABET[((buffer >> bits) & 0x1F) as usize] as char,
+            );
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(
+            BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char,
+        );
+    }
+
+    encoded
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in encoded.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totp(secret: &[u8], unix_secs: u64, digits: u32) -> u32 {
+        hotp(secret, unix_secs / STEP_SECS, digits)
+    }
+
+    /// RFC 6238 appendix B, SHA1 with the ASCII key "12345678901234567890".
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: [(u64, u32); 6] = [
+        (59, 94287082),
+        (1111111109, 7081804),
+        (1111111111, 14050471),
+        (1234567890, 89005924),
+        (2000000000, 69279037),
+        (20000000000, 65353130),
+    ];
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        for (time, expected) in RFC_VECTORS {
+            assert_eq!(
+                totp(RFC_SECRET, time, 8),
+                expected,
+                "at {time}"
+            );
+        }
+    }
+
+    #[test]
+    fn six_digit_codes_are_zero_padded() {
+        assert_eq!(
+            format_code(totp(RFC_SECRET, 1111111109, 6), 6),
+            "081804"
+        );
+    }
+
+    #[test]
+    fn verify_accepts_one_step_of_skew() {
+        let code = format_code(
+            totp(RFC_SECRET, 1111111109, DIGITS),
+            DIGITS,
+        );
+
+        assert_eq!(
+            verify(RFC_SECRET, &code, 1111111109, None),
+            Some(37037036)
+        );
+        assert!(
+            verify(
+                RFC_SECRET,
+                &code,
+                1111111109 + STEP_SECS,
+                None
+            )
+            .is_some()
+        );
+        assert!(
+            verify(
+                RFC_SECRET,
+                &code,
+                1111111109 - STEP_SECS,
+                None
+            )
+            .is_some()
+        );
+        assert!(
+            verify(
+                RFC_SECRET,
+                &code,
+                1111111109 + 2 * STEP_SECS,
+                None
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn verify_rejects_replayed_and_malformed_codes() {
+        let code = format_code(totp(RFC_SECRET, 59, DIGITS), DIGITS);
+
+        assert!(verify(RFC_SECRET, &code, 59, Some(1)).is_none());
+        assert!(verify(RFC_SECRET, "12345", 59, None).is_none());
+        assert!(verify(RFC_SECRET, "abcdef", 59, None).is_none());
+    }
+
+    #[test]
+    fn only_recovery_codes_look_like_them() {
+        for code in generate_recovery_codes() {
+            assert!(is_recovery_code(&code), "{code}");
+        }
+        assert!(is_recovery_code(" k3x9-7fqa "));
+        assert!(!is_recovery_code("123456"));
+        assert!(!is_recovery_code("k3x97fqa"));
+        assert!(!is_recovery_code("K3X9-7FQA"));
+    }
+
+    #[test]
+    fn base32_round_trips_rfc_4648_vectors() {
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(
+                base32_decode(encoded).unwrap(),
+                plain.as_bytes()
+            );
+        }
+
+        assert_eq!(
+            base32_decode("MZXW6YTBOI======").unwrap(),
+            b"foobar"
+        );
+        assert!(base32_decode("not base32!").is_none());
+    }
+}
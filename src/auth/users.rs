@@ -0,0 +1,605 @@
+use crate::auth::totp::{self, TotpEnrollment};
+use anyhow::{Context, anyhow, bail};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Ordered from least to most privileged, see [`Role::allows`].
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Guard,
+    /// Stores written before the finer roles had `operator`, which could do
+    /// everything a supervisor can.
+    #[serde(alias = "operator")]
+    Supervisor,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] =
+        [Role::Viewer, Role::Guard, Role::Supervisor, Role::Admin];
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Role::Viewer => "Viewer",
+            Role::Guard => "Guard",
+            Role::Supervisor => "Supervisor",
+            Role::Admin => "Admin",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    /// Argon2id PHC string, salt included.
+    pub password_hash: String,
+    pub role: Role,
+    pub session_minutes: u32,
+    /// Second factor, asked for after the password when set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub totp: Option<TotpEnrollment>,
+    /// Logs in like the password but silently signals duress.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duress_hash: Option<String>,
+}
+
+impl User {
+    pub fn session_timeout(&self) -> TimeDelta {
+        TimeDelta::minutes(self.session_minutes as i64)
+    }
+
+    pub fn has_totp(&self) -> bool {
+        self.totp.is_some()
+    }
+}
+
+/// A user whose password or duress password matched.
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    pub user: User,
+    pub duress: bool,
+}
+
+/// How a second factor was accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactor {
+    Code,
+    /// A recovery code, this many are left.
+    RecoveryCode {
+        remaining: usize,
+    },
+}
+
+/// Users file, rewritten as a whole on every change.
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    users: Vec<User>,
+    max_session_minutes: u32,
+}
+
+impl UserStore {
+    /// A missing file is an empty store, which starts the first-run setup.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let users = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).with_context(|| {
+                    format!("Failed to parse {}", path.display())
+                })?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Vec::new()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        Ok(Self {
+            path,
+            users,
+            max_session_minutes: 24 * 60,
+        })
+    }
+
+    /// Longest session new settings may have, existing users keep theirs.
+    pub fn set_max_session_minutes(&mut self, max_session_minutes: u32) {
+        self.max_session_minutes = max_session_minutes;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn users(&self) -> &[User] {
+        &self.users
+    }
+
+    pub fn find(&self, username: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| user.username == username)
+    }
+
+    /// See [`check_password`].
+    pub fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Option<Authenticated> {
+        check_password(&self.users, username, password)
+    }
+
+    pub fn add(
+        &mut self,
+        username: &str,
+        password: &str,
+        role: Role,
+        session_minutes: u32,
+    ) -> anyhow::Result<()> {
+        let username = username.trim();
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            bail!("Username must be a single word");
+        }
+
+        if self.find(username).is_some() {
+            bail!("User '{username}' already exists");
+        }
+
+        validate_password(password)?;
+        validate_session_minutes(
+            session_minutes,
+            self.max_session_minutes,
+        )?;
+
+        self.users.push(User {
+            username: username.to_owned(),
+            password_hash: hash_password(password)?,
+            role,
+            session_minutes,
+            totp: None,
+            duress_hash: None,
+        });
+
+        self.save()
+    }
+
+    pub fn remove(&mut self, username: &str) -> anyhow::Result<()> {
+        let user = self.find_existing(username)?;
+        if user.role == Role::Admin && self.admin_count() == 1 {
+            bail!("Cannot remove the last admin");
+        }
+
+        self.users
+            .retain(|user| user.username != username);
+        self.save()
+    }
+
+    pub fn set_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<()> {
+        validate_password(password)?;
+
+        let user = self.find_existing(username)?;
+        if user
+            .duress_hash
+            .as_deref()
+            .is_some_and(|hash| verify_password(hash, password))
+        {
+            bail!("Password must differ from the duress password");
+        }
+
+        let hash = hash_password(password)?;
+        self.find_existing_mut(username)?
+            .password_hash = hash;
+        self.save()
+    }
+
+    /// `None` removes the duress password.
+    pub fn set_duress_password(
+        &mut self,
+        username: &str,
+        password: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let hash = match password {
+            Some(password) => {
+                validate_password(password)?;
+
+                let user = self.find_existing(username)?;
+                if verify_password(&user.password_hash, password) {
+                    bail!("Duress password must differ from the password");
+                }
+
+                Some(hash_password(password)?)
+            }
+            None => None,
+        };
+
+        self.find_existing_mut(username)?
+            .duress_hash = hash;
+        self.save()
+    }
+
+    pub fn update(
+        &mut self,
+        username: &str,
+        role: Role,
+        session_minutes: u32,
+    ) -> anyhow::Result<()> {
+        validate_session_minutes(
+            session_minutes,
+            self.max_session_minutes,
+        )?;
+
+        let user = self.find_existing(username)?;
+        if user.role == Role::Admin
+            && role != Role::Admin
+            && self.admin_count() == 1
+        {
+            bail!("Cannot demote the last admin");
+        }
+
+        let user = self.find_existing_mut(username)?;
+        user.role = role;
+        user.session_minutes = session_minutes;
+        self.save()
+    }
+
+    /// Stores the secret once the user proved their app has it with the
+    /// code of `confirmed_step`. Recovery codes are only kept hashed.
+    pub fn enroll_totp(
+        &mut self,
+        username: &str,
+        secret: &[u8],
+        recovery_codes: &[String],
+        confirmed_step: u64,
+    ) -> anyhow::Result<()> {
+        let recovery_codes = recovery_codes
+            .iter()
+            .map(|code| hash_password(code))
+            .collect::<anyhow::Result<_>>()?;
+
+        self.find_existing_mut(username)?.totp = Some(TotpEnrollment {
+            secret: totp::base32_encode(secret),
+            recovery_codes,
+            last_used_step: Some(confirmed_step),
+        });
+        self.save()
+    }
+
+    pub fn disable_totp(&mut self, username: &str) -> anyhow::Result<()> {
+        self.find_existing_mut(username)?.totp = None;
+        self.save()
+    }
+
+    /// Checks a one-time or recovery code. Both work only once, so the
+    /// store is saved on success.
+    pub fn verify_second_factor(
+        &mut self,
+        username: &str,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<SecondFactor>> {
+        let recovery_code = self
+            .find(username)
+            .and_then(|user| find_recovery_code(user, code));
+        self.use_second_factor(
+            username,
+            code,
+            recovery_code.as_deref(),
+            now,
+        )
+    }
+
+    /// [`UserStore::verify_second_factor`] with the recovery code already
+    /// looked up by [`find_recovery_code`], e.g. on another thread. It
+    /// still has to be unused.
+    pub fn use_second_factor(
+        &mut self,
+        username: &str,
+        code: &str,
+        recovery_code: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<SecondFactor>> {
+        let user = self.find_existing_mut(username)?;
+        let enrollment = user
+            .totp
+            .as_mut()
+            .with_context(|| format!("'{username}' has no second factor"))?;
+        let secret = totp::base32_decode(&enrollment.secret)
+            .context("Malformed second factor secret in the user store")?;
+
+        let factor = if let Some(step) = totp::verify(
+            &secret,
+            code,
+            now.timestamp().max(0) as u64,
+            enrollment.last_used_step,
+        ) {
+            enrollment.last_used_step = Some(step);
+            SecondFactor::Code
+        } else if let Some(index) = recovery_code.and_then(|hash| {
+            enrollment
+                .recovery_codes
+                .iter()
+                .position(|unused| unused == hash)
+        }) {
+            enrollment.recovery_codes.remove(index);
+            SecondFactor::RecoveryCode {
+                remaining: enrollment.recovery_codes.len(),
+            }
+        } else {
+            return Ok(None);
+        };
+
+        self.save()?;
+        Ok(Some(factor))
+    }
+
+    fn admin_count(&self) -> usize {
+        self.users
+            .iter()
+            .filter(|user| user.role == Role::Admin)
+            .count()
+    }
+
+    fn find_existing(&self, username: &str) -> anyhow::Result<&User> {
+        self.find(username)
+            .with_context(|| format!("No user '{username}'"))
+    }
+
+    fn find_existing_mut(
+        &mut self,
+        username: &str,
+    ) -> anyhow::Result<&mut User> {
+        self.users
+            .iter_mut()
+            .find(|user| user.username == username)
+            .with_context(|| format!("No user '{username}'"))
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Write next to the file and swap, a crash must not lose all users
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(
+            &temp,
+            serde_json::to_vec_pretty(&self.users)?,
+        )
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, &self.path).with_context(|| {
+            format!(
+                "Failed to replace {}",
+                self.path.display()
+            )
+        })
+    }
+}
+
+/// The user if the password or the duress password matches. Unknown
+/// names cost as much as a wrong password, so timing does not tell which
+/// users exist. Takes a while, Argon2 is slow on purpose.
+pub fn check_password(
+    users: &[User],
+    username: &str,
+    password: &str,
+) -> Option<Authenticated> {
+    let Some(user) = users
+        .iter()
+        .find(|user| user.username == username)
+    else {
+        let _ = hash_password(password);
+        return None;
+    };
+
+    if verify_password(&user.password_hash, password) {
+        return Some(Authenticated { user: user.clone(), duress: false });
+    }
+
+    user.duress_hash
+        .as_deref()
+        .is_some_and(|hash| verify_password(hash, password))
+        .then(|| Authenticated { user: user.clone(), duress: true })
+}
+
+/// Hash of the unused recovery code `code` is, if any. Up to ten Argon2
+/// checks, so only input shaped like a recovery code gets them.
+pub fn find_recovery_code(user: &User, code: &str) -> Option<String> {
+    if !totp::is_recovery_code(code) {
+        return None;
+    }
+
+    user.totp
+        .as_ref()?
+        .recovery_codes
+        .iter()
+        .find(|hash| verify_password(hash, code.trim()))
+        .cloned()
+}
+
+fn validate_password(password: &str) -> anyhow::Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        bail!("Password must be at least {MIN_PASSWORD_LEN} characters long");
+    }
+
+    Ok(())
+}
+
+fn validate_session_minutes(
+    session_minutes: u32,
+    max_session_minutes: u32,
+) -> anyhow::Result<()> {
+    if !(1..=max_session_minutes).contains(&session_minutes) {
+        bail!(
+            "Session timeout must be between 1 and {max_session_minutes} minutes"
+        );
+    }
+
+    Ok(())
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow!("Failed to hash password: {err}"))?;
+
+    Ok(hash.to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        eprintln!("Malformed password hash in the user store");
+        return false;
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const PASSWORD: &str = "correct horse";
+
+    /// A store whose file does not exist yet.
+    fn empty_store(dir: &TempDir) -> UserStore {
+        UserStore::open(dir.path().join("users.json")).unwrap()
+    }
+
+    #[test]
+    fn the_first_admin_is_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = empty_store(&dir);
+        assert!(store.is_empty());
+
+        store
+            .add("admin", PASSWORD, Role::Admin, 60)
+            .unwrap();
+
+        let store = UserStore::open(&store.path).unwrap();
+        assert!(!store.is_empty());
+        assert_eq!(
+            store.find("admin").unwrap().role,
+            Role::Admin
+        );
+    }
+
+    #[test]
+    fn passwords_are_checked_against_the_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = empty_store(&dir);
+        store
+            .add("guard", PASSWORD, Role::Guard, 60)
+            .unwrap();
+        store
+            .set_duress_password("guard", Some("battery staple"))
+            .unwrap();
+
+        let user = store.find("guard").unwrap();
+        assert!(
+            user.password_hash
+                .starts_with("$argon2")
+        );
+        assert!(!user.password_hash.contains(PASSWORD));
+
+        let login = store
+            .authenticate("guard", PASSWORD)
+            .unwrap();
+        assert!(!login.duress);
+        let duress = store
+            .authenticate("guard", "battery staple")
+            .unwrap();
+        assert!(duress.duress);
+        assert!(
+            store
+                .authenticate("guard", "wrong horse")
+                .is_none()
+        );
+        assert!(
+            store
+                .authenticate("nobody", PASSWORD)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn duplicate_and_invalid_users_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = empty_store(&dir);
+        store
+            .add("guard", PASSWORD, Role::Guard, 60)
+            .unwrap();
+
+        assert!(
+            store
+                .add("guard", PASSWORD, Role::Viewer, 60)
+                .is_err()
+        );
+        assert!(
+            store
+                .add("two words", PASSWORD, Role::Viewer, 60)
+                .is_err()
+        );
+        assert!(
+            store
+                .add("viewer", "short", Role::Viewer, 60)
+                .is_err()
+        );
+        assert!(
+            store
+                .add("viewer", PASSWORD, Role::Viewer, 0)
+                .is_err()
+        );
+        assert_eq!(store.users().len(), 1);
+    }
+
+    #[test]
+    fn the_last_admin_cannot_be_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = empty_store(&dir);
+        store
+            .add("admin", PASSWORD, Role::Admin, 60)
+            .unwrap();
+        store
+            .add("second", PASSWORD, Role::Admin, 60)
+            .unwrap();
+
+        store.remove("second").unwrap();
+        assert!(store.remove("admin").is_err());
+        assert!(store.find("admin").is_some());
+    }
+}
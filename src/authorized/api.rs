@@ -1,125 +1,530 @@
-use crate::authorized::serial_connection::{PollResult, SerialConnection};
-use eframe::egui::{Color32, Response, Ui, Widget};
+use crate::auth::permissions::Permission;
+use crate::auth::users::Role;
+use crate::authorized::identity::{
+    DeviceIdentity, FRONT_DOOR_REFUSED, Handshake,
+};
+use crate::authorized::protocol::ProtocolVersion;
+use crate::authorized::serial_connection::PollResult;
+use crate::authorized::transport::TransportConfig;
+use crate::authorized::worker::{Worker, WorkerCommand, WorkerEvent};
+use crate::config::DeviceConfig;
+use crate::sensors::SensorMap;
+use crate::simulator::device::SimulatorHandle;
+#[cfg(feature = "gui")]
+use eframe::egui::{self, Color32, Response, Ui, Widget};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+#[cfg(feature = "gui")]
+use std::time::Duration;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Serial,
+    Tcp,
+    Loopback,
+}
+
+impl TransportKind {
+    pub const ALL: [TransportKind; 3] = [
+        TransportKind::Serial,
+        TransportKind::Tcp,
+        TransportKind::Loopback,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TransportKind::Serial => "Serial",
+            TransportKind::Tcp => "TCP",
+            TransportKind::Loopback => "Loopback (simulator)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Disconnected,
+    Connecting(String),
+    Connected(String),
+    Reconnecting { target: String, attempt: u32 },
+}
+
+impl ConnectionStatus {
+    /// `disconnected`, `connecting`, `connected` or `reconnecting`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionStatus::Disconnected => "disconnected",
+            ConnectionStatus::Connecting(_) => "connecting",
+            ConnectionStatus::Connected(_) => "connected",
+            ConnectionStatus::Reconnecting { .. } => "reconnecting",
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            ConnectionStatus::Disconnected => None,
+            ConnectionStatus::Connecting(target)
+            | ConnectionStatus::Connected(target)
+            | ConnectionStatus::Reconnecting { target, .. } => Some(target),
+        }
+    }
+}
+
+/// Connection changes reported by the worker, as they go into the event log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "change",
+    rename_all = "snake_case"
+)]
+pub enum ConnectionChange {
+    Connected {
+        target: String,
+        /// What the device said about itself in the handshake. Not
+        /// `device`, the event it goes into already has that key.
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        identity: Option<String>,
+    },
+    ConnectFailed {
+        target: String,
+        error: String,
+    },
+    Disconnected,
+    Reconnecting {
+        target: String,
+        attempt: u32,
+    },
+}
+
+impl Display for ConnectionChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionChange::Connected { target, identity: None } => {
+                write!(f, "Connected to {target}")
+            }
+            ConnectionChange::Connected {
+                target,
+                identity: Some(identity),
+            } => write!(f, "Connected to {target} ({identity})"),
+            ConnectionChange::ConnectFailed { target, error } => {
+                write!(
+                    f,
+                    "Failed to connect to {target}: {error}"
+                )
+            }
+            ConnectionChange::Disconnected => write!(f, "Disconnected"),
+            ConnectionChange::Reconnecting { target, attempt } => {
+                write!(
+                    f,
+                    "Reconnecting to {target} (attempt {attempt})"
+                )
+            }
+        }
+    }
+}
+
+/// What happened on the worker side since the last [`Api::update`].
+#[derive(Debug)]
+pub enum ApiUpdate {
+    Polled(PollResult),
+    Connection(ConnectionChange),
+    /// The device works, but not fully, e.g. it lacks a capability.
+    Warning(String),
+    Error(String),
+}
+
+/// Handle to the I/O worker thread. Commands are queued and executed in
+/// order, results come back through [`Api::update`].
 #[derive(Debug)]
 pub struct Api {
-    active_connection: Option<SerialConnection>,
+    commands: Sender<WorkerCommand>,
+    events: Receiver<WorkerEvent>,
+    status: ConnectionStatus,
+    /// Of the last connection, only shown while connected.
+    handshake: Handshake,
+    last_error: Option<String>,
+    protocol: ProtocolVersion,
+    transport_kind: TransportKind,
+    tcp_address: String,
+    simulator: Option<SimulatorHandle>,
+    sensors: Arc<SensorMap>,
 }
 
 impl Api {
-    pub fn new() -> Self {
-        Self { active_connection: None }
+    pub fn new(device: DeviceConfig, sensors: Arc<SensorMap>) -> Self {
+        let (command_tx, command_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+
+        std::thread::Builder::new()
+            .name("device-io".to_owned())
+            .spawn({
+                let sensors = sensors.clone();
+                move || Worker::new(command_rx, event_tx, device, sensors).run()
+            })
+            .expect("Failed to spawn the device I/O worker!");
+
+        Self {
+            commands: command_tx,
+            events: event_rx,
+            status: ConnectionStatus::Disconnected,
+            handshake: Handshake::default(),
+            last_error: None,
+            protocol: ProtocolVersion::Framed,
+            transport_kind: TransportKind::Serial,
+            tcp_address: "127.0.0.1:2000".to_owned(),
+            simulator: None,
+            sensors,
+        }
     }
 
+    #[cfg(feature = "gui")]
     pub fn widget(&mut self) -> impl Widget + '_ {
         ApiWidget { api: self }
     }
 
+    pub fn connect(&mut self, config: TransportConfig) {
+        self.status = ConnectionStatus::Connecting(config.target());
+        self.last_error = None;
+        self.simulator = match &config {
+            TransportConfig::Loopback(handle) => Some(handle.clone()),
+            _ => None,
+        };
+        let _ = self.enqueue(WorkerCommand::Connect(
+            config,
+            self.protocol,
+        ));
+    }
+
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    /// Protocol of the next [`Api::connect`].
+    pub fn set_protocol(&mut self, protocol: ProtocolVersion) {
+        self.protocol = protocol;
+    }
+
+    pub fn configure(&self, device: DeviceConfig) {
+        let _ = self.enqueue(WorkerCommand::Configure(device));
+    }
+
     pub fn close_connection(&mut self) {
-        self.active_connection = None;
+        self.status = ConnectionStatus::Disconnected;
+        let _ = self.enqueue(WorkerCommand::Disconnect);
+    }
+
+    /// Drains everything the worker reported since the previous call. Has to
+    /// be called once per frame.
+    pub fn update(&mut self) -> Vec<ApiUpdate> {
+        let mut updates = Vec::new();
+
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                WorkerEvent::Connected { description, handshake } => {
+                    self.status =
+                        ConnectionStatus::Connected(description.clone());
+                    updates.push(ApiUpdate::Connection(
+                        ConnectionChange::Connected {
+                            target: description,
+                            identity: handshake
+                                .identity
+                                .map(|identity| identity.to_string()),
+                        },
+                    ));
+                    updates.extend(
+                        handshake
+                            .warnings
+                            .iter()
+                            .cloned()
+                            .map(ApiUpdate::Warning),
+                    );
+                    self.handshake = handshake;
+                }
+                WorkerEvent::ConnectFailed(port_name, err) => {
+                    let change = ConnectionChange::ConnectFailed {
+                        target: port_name,
+                        error: err,
+                    };
+                    self.status = ConnectionStatus::Disconnected;
+                    self.last_error = Some(change.to_string());
+                    updates.push(ApiUpdate::Error(change.to_string()));
+                    updates.push(ApiUpdate::Connection(change));
+                }
+                WorkerEvent::Disconnected => {
+                    self.status = ConnectionStatus::Disconnected;
+                    updates.push(ApiUpdate::Connection(
+                        ConnectionChange::Disconnected,
+                    ));
+                }
+                WorkerEvent::Reconnecting { target, attempt, .. } => {
+                    self.status = ConnectionStatus::Reconnecting {
+                        target: target.clone(),
+                        attempt,
+                    };
+                    updates.push(ApiUpdate::Connection(
+                        ConnectionChange::Reconnecting { target, attempt },
+                    ));
+                }
+                WorkerEvent::Polled(res) => {
+                    updates.push(ApiUpdate::Polled(res));
+                }
+                WorkerEvent::PollFailed(err)
+                | WorkerEvent::CommandFailed(err) => {
+                    updates.push(ApiUpdate::Error(err));
+                }
+            }
+        }
+
+        updates
+    }
+
+    fn enqueue(&self, command: WorkerCommand) -> anyhow::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Device worker has stopped"))
+    }
+
+    /// Queues a device command on behalf of someone with `role`. A refusal
+    /// comes back as a
+    /// [`PermissionDenied`](crate::auth::permissions::PermissionDenied)
+    /// inside the error.
+    fn enqueue_as(
+        &self,
+        role: Role,
+        permission: Permission,
+        command: WorkerCommand,
+    ) -> anyhow::Result<()> {
+        role.require(permission)?;
+
+        if !self.exists() {
+            anyhow::bail!("No active connection");
+        }
+
+        self.enqueue(command)
     }
 }
 
 impl Api {
+    pub fn status(&self) -> &ConnectionStatus {
+        &self.status
+    }
+
     pub fn exists(&self) -> bool {
-        self.active_connection.is_some()
+        matches!(
+            self.status,
+            ConnectionStatus::Connected(_)
+        )
     }
 
-    pub fn send_poll(&self) -> anyhow::Result<PollResult> {
-        match &self.active_connection {
-            Some(active_connection) => active_connection.send_poll(),
-            None => {
-                anyhow::bail!("No active connection");
-            }
-        }
+    /// What the connected board said about itself in the handshake.
+    pub fn identity(&self) -> Option<&DeviceIdentity> {
+        self.handshake
+            .identity
+            .as_ref()
+            .filter(|_| self.exists())
     }
 
-    pub fn send_reset(&self) -> anyhow::Result<()> {
-        match &self.active_connection {
-            Some(active_connection) => active_connection.send_reset(),
-            None => {
-                anyhow::bail!("No active connection");
-            }
-        }
+    pub fn send_poll(&self, role: Role) -> anyhow::Result<()> {
+        self.enqueue_as(
+            role,
+            Permission::Poll,
+            WorkerCommand::Poll,
+        )
     }
 
-    pub fn send_unlock_back_door(&self) -> anyhow::Result<()> {
-        match &self.active_connection {
-            Some(active_connection) => {
-                active_connection.send_unlock_back_door()
-            }
-            None => {
-                anyhow::bail!("No active connection");
-            }
-        }
+    pub fn send_reset(&self, role: Role) -> anyhow::Result<()> {
+        self.enqueue_as(
+            role,
+            Permission::Reset,
+            WorkerCommand::Reset,
+        )
     }
 
-    pub fn send_lock_back_door(&self) -> anyhow::Result<()> {
-        match &self.active_connection {
-            Some(active_connection) => active_connection.send_lock_back_door(),
-            None => {
-                anyhow::bail!("No active connection");
-            }
-        }
+    pub fn send_unlock_back_door(&self, role: Role) -> anyhow::Result<()> {
+        self.enqueue_as(
+            role,
+            Permission::UnlockDoors,
+            WorkerCommand::UnlockBackDoor,
+        )
     }
 
-    pub fn send_unlock_front_door(&self) -> anyhow::Result<()> {
-        match &self.active_connection {
-            Some(active_connection) => {
-                active_connection.send_unlock_front_door()
-            }
-            None => {
-                anyhow::bail!("No active connection");
-            }
-        }
+    pub fn send_lock_back_door(&self, role: Role) -> anyhow::Result<()> {
+        self.enqueue_as(
+            role,
+            Permission::LockDoors,
+            WorkerCommand::LockBackDoor,
+        )
     }
 
-    pub fn send_lock_front_door(&self) -> anyhow::Result<()> {
-        match &self.active_connection {
-            Some(active_connection) => active_connection.send_lock_front_door(),
-            None => {
-                anyhow::bail!("No active connection");
-            }
+    /// Refused for boards without an identity, the worker checks again.
+    pub fn send_unlock_front_door(&self, role: Role) -> anyhow::Result<()> {
+        role.require(Permission::UnlockDoors)?;
+        if self.exists() && self.handshake.identity.is_none() {
+            anyhow::bail!(FRONT_DOOR_REFUSED);
         }
+
+        self.enqueue_as(
+            role,
+            Permission::UnlockDoors,
+            WorkerCommand::UnlockFrontDoor,
+        )
+    }
+
+    pub fn send_lock_front_door(&self, role: Role) -> anyhow::Result<()> {
+        self.enqueue_as(
+            role,
+            Permission::LockDoors,
+            WorkerCommand::LockFrontDoor,
+        )
     }
 }
 
+#[cfg(feature = "gui")]
 #[derive(Debug)]
 pub struct ApiWidget<'a> {
     api: &'a mut Api,
 }
 
+#[cfg(feature = "gui")]
 impl Widget for ApiWidget<'_> {
     fn ui(self, ui: &mut Ui) -> Response {
-        let connection = match &mut self.api.active_connection {
-            Some(c) => c,
-            None => {
+        let port_name = match self.api.status.clone() {
+            ConnectionStatus::Connected(port_name) => port_name,
+            ConnectionStatus::Connecting(port_name) => {
+                ui.spinner();
+                return ui.label(format!("Connecting to {port_name}..."));
+            }
+            ConnectionStatus::Reconnecting { target, attempt } => {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.colored_label(
+                        Color32::YELLOW,
+                        format!("Reconnecting to {target} (attempt {attempt})"),
+                    );
+                });
+
+                if ui.button("Cancel").clicked() {
+                    self.api.close_connection();
+                    self.api.simulator = None;
+                }
+
+                return ui.response();
+            }
+            ConnectionStatus::Disconnected => {
                 self.connection_picker(ui);
                 return ui.colored_label(Color32::RED, "No connection!");
             }
         };
 
-        ui.add(connection.widget());
+        ui.label(format!("Connected to {port_name}"));
+
+        match &self.api.handshake.identity {
+            Some(identity) => {
+                ui.label(format!(
+                    "{identity}, can: {}",
+                    identity.capabilities
+                ));
+            }
+            None => {
+                ui.colored_label(Color32::YELLOW, "Unidentified device");
+            }
+        }
+        for warning in &self.api.handshake.warnings {
+            ui.colored_label(Color32::YELLOW, warning);
+        }
+
+        if let Some(simulator) = &self.api.simulator {
+            simulator_controls(simulator, ui);
+        }
 
         if ui.button("Disconnect").clicked() {
-            self.api.active_connection = None;
+            self.api.close_connection();
+            self.api.simulator = None;
         }
 
         ui.response()
     }
 }
 
+#[cfg(feature = "gui")]
 impl ApiWidget<'_> {
     fn connection_picker(self, ui: &mut Ui) -> Response {
+        ui.horizontal(|ui| {
+            ui.label("Protocol:");
+            for protocol in [ProtocolVersion::Framed, ProtocolVersion::Legacy] {
+                ui.radio_value(
+                    &mut self.api.protocol,
+                    protocol,
+                    protocol.to_string(),
+                );
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Transport:");
+            for kind in TransportKind::ALL {
+                ui.radio_value(
+                    &mut self.api.transport_kind,
+                    kind,
+                    kind.label(),
+                );
+            }
+        });
+
+        let selected = match self.api.transport_kind {
+            TransportKind::Serial => match Self::serial_picker(ui) {
+                Ok(selected) => selected,
+                Err(response) => return response,
+            },
+            TransportKind::Tcp => {
+                let mut selected = None;
+                ui.horizontal(|ui| {
+                    let address_label = ui.label("host:port ");
+                    ui.text_edit_singleline(&mut self.api.tcp_address)
+                        .labelled_by(address_label.id);
+
+                    if ui.button("Connect").clicked() {
+                        selected = Some(TransportConfig::Tcp {
+                            address: self.api.tcp_address.trim().to_owned(),
+                        });
+                    }
+                });
+                selected
+            }
+            TransportKind::Loopback => ui
+                .button("Start simulator")
+                .clicked()
+                .then(|| {
+                    TransportConfig::Loopback(SimulatorHandle::new(
+                        self.api.sensors.clone(),
+                    ))
+                }),
+        };
+
+        if let Some(selected) = selected {
+            self.api.connect(selected);
+        }
+
+        if let Some(err) = &self.api.last_error {
+            return ui.colored_label(Color32::RED, err);
+        }
+
+        ui.response()
+    }
+
+    fn serial_picker(ui: &mut Ui) -> Result<Option<TransportConfig>, Response> {
         ui.label("Connect to port:");
 
         let ports = match serialport::available_ports() {
             Ok(p) => p,
             Err(err) => {
-                return ui.colored_label(
+                return Err(ui.colored_label(
                     Color32::RED,
                     format!("Failed to get ports: {}", err),
-                );
+                ));
             }
         };
 
@@ -129,27 +534,127 @@ impl ApiWidget<'_> {
                 .button(p.port_name.as_str())
                 .clicked()
             {
-                selected = Some(p.port_name);
+                selected =
+                    Some(TransportConfig::Serial { port_name: p.port_name });
             }
         }
 
-        if let Some(selected) = selected {
-            println!("Connecting to port: {}", selected);
-            match SerialConnection::new(selected.as_str()) {
-                Ok(connection) => {
-                    self.api.active_connection = Some(connection);
+        Ok(selected)
+    }
+}
+
+#[cfg(feature = "gui")]
+fn simulator_controls(simulator: &SimulatorHandle, ui: &mut Ui) {
+    egui::CollapsingHeader::new("Simulated device").show(ui, |ui| {
+        ui.horizontal_wrapped(|ui| {
+            for sensor in simulator.sensor_map().sensors() {
+                if let Some(analog) = &sensor.analog {
+                    let mut value = simulator.value(&sensor.id);
+                    ui.label(sensor.id.as_str());
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut value)
+                                .speed(analog.scale)
+                                .suffix(format!(" {}", analog.unit)),
+                        )
+                        .changed()
+                    {
+                        simulator.set_value(&sensor.id, value);
+                    }
+                    continue;
                 }
 
-                Err(err) => {
-                    eprintln!("Failed to connect to port: {}", err);
-                    return ui.colored_label(
-                        Color32::RED,
-                        format!("Failed to connect to port: {}", err),
-                    );
+                let mut value = simulator.sensor(&sensor.id);
+                if ui
+                    .checkbox(&mut value, sensor.id.as_str())
+                    .changed()
+                {
+                    simulator.set_sensor(&sensor.id, value);
                 }
             }
+        });
+
+        let current = simulator.lock().faults.clone();
+        let mut delay_ms = current.reply_delay.as_millis() as u64;
+        let mut garbage_bytes = current.garbage_bytes;
+        let mut disconnected = current.disconnected;
+        let mut corrupt = false;
+        let mut drop = false;
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Reply delay (ms):");
+            ui.add(egui::DragValue::new(&mut delay_ms).range(0..=10_000));
+            ui.label("Garbage bytes:");
+            ui.add(egui::DragValue::new(&mut garbage_bytes).range(0..=64));
+            ui.checkbox(&mut disconnected, "Unplugged");
+            corrupt = ui
+                .button("Corrupt next reply")
+                .clicked();
+            drop = ui.button("Drop next reply").clicked();
+        });
+
+        simulator.update_faults(|faults| {
+            faults.reply_delay = Duration::from_millis(delay_ms);
+            faults.garbage_bytes = garbage_bytes;
+            faults.disconnected = disconnected;
+            faults.corrupt_replies += corrupt as usize;
+            faults.dropped_replies += drop as usize;
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::device::SimulatedDevice;
+    use std::time::{Duration, Instant};
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    /// Runs the worker until an update matches.
+    fn wait_for(api: &mut Api, wanted: impl Fn(&ApiUpdate) -> bool) {
+        let started = Instant::now();
+        while !api.update().iter().any(&wanted) {
+            assert!(started.elapsed() < WAIT, "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
         }
+    }
 
-        ui.response()
+    #[test]
+    fn legacy_boards_never_get_the_siren_opcode() {
+        let sensors = Arc::new(SensorMap::default());
+        let board = SimulatorHandle::new(sensors.clone());
+        board.lock().identity = None;
+        board.lock().front_door_locked = true;
+
+        let mut api = Api::new(DeviceConfig::default(), sensors);
+        api.set_protocol(ProtocolVersion::Legacy);
+        api.connect(TransportConfig::Loopback(board.clone()));
+        wait_for(&mut api, |update| {
+            matches!(
+                update,
+                ApiUpdate::Connection(ConnectionChange::Connected { .. })
+            )
+        });
+        assert_eq!(api.identity(), None);
+
+        let refused = api
+            .send_unlock_front_door(Role::Admin)
+            .unwrap_err();
+        assert_eq!(refused.to_string(), FRONT_DOOR_REFUSED);
+        // The worker refuses it as well, whoever queues it
+        api.enqueue(WorkerCommand::UnlockFrontDoor)
+            .unwrap();
+        wait_for(
+            &mut api,
+            |update| matches!(update, ApiUpdate::Error(err) if err == FRONT_DOOR_REFUSED),
+        );
+        api.close_connection();
+
+        assert!(!board.lock().alarm_output);
+        assert!(board.lock().front_door_locked);
+        // Which it would have done
+        SimulatedDevice::new(board.clone()).feed(&[0xA5]);
+        assert!(board.lock().alarm_output);
     }
 }
@@ -0,0 +1,209 @@
+use crate::authorized::protocol::{
+    HELLO_LEN, ProtocolError, ProtocolVersion, nak,
+};
+use crate::authorized::transport::DeviceTransport;
+use crate::sensors::SensorMap;
+use anyhow::bail;
+use std::fmt::{Display, Formatter};
+
+/// Revision of the opcode table this host speaks. Revision 1 was the
+/// firmware from before the HELLO command, where 0xA5 set off the alarm
+/// instead of unlocking the front door.
+pub const COMMAND_SET: u8 = 2;
+
+const UNIDENTIFIED: &str = "The device does not identify itself, its firmware predates the HELLO command. On such boards 0xA5 sets off the alarm instead of unlocking the front door, so unlocking the front door is refused";
+
+/// Why [`Command::UnlockFrontDoor`](crate::authorized::protocol::Command)
+/// is never sent to a board without an identity.
+pub const FRONT_DOOR_REFUSED: &str = "Refusing to unlock the front door of an unidentified device, on its firmware 0xA5 sets off the alarm";
+
+/// Model codes of the boards we know.
+pub mod model {
+    pub const CONTROL_BOARD: u16 = 0x0001;
+    pub const SIMULATOR: u16 = 0x00FF;
+}
+
+/// What a board can do, as a bitmap in its HELLO answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u16);
+
+impl Capabilities {
+    /// Front and back door locks, opcodes 0xA2 to 0xA5.
+    pub const DOORS: u16 = 1 << 0;
+    /// Measured values after the state bytes of a framed poll.
+    pub const ANALOG: u16 = 1 << 1;
+    /// Siren output, driven by the firmware on its own.
+    pub const SIREN: u16 = 1 << 2;
+
+    const NAMES: [(u16, &str); 3] = [
+        (Self::DOORS, "doors"),
+        (Self::ANALOG, "analog"),
+        (Self::SIREN, "siren"),
+    ];
+
+    pub fn contains(self, capability: u16) -> bool {
+        self.0 & capability == capability
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(bit, _)| self.contains(*bit))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Answer to the HELLO command:
+///
+/// ```text
+/// | model u16 | firmware major, minor, patch | command set | serial u32 | capabilities u16 |
+/// ```
+///
+/// Multi-byte fields are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub model: u16,
+    pub firmware: [u8; 3],
+    pub command_set: u8,
+    pub serial_number: u32,
+    pub capabilities: Capabilities,
+}
+
+impl DeviceIdentity {
+    pub fn parse(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let Ok(bytes) = <[u8; HELLO_LEN]>::try_from(payload) else {
+            return Err(ProtocolError::MalformedIdentity(
+                payload.len(),
+            ));
+        };
+
+        Ok(Self {
+            model: u16::from_be_bytes([bytes[0], bytes[1]]),
+            firmware: [bytes[2], bytes[3], bytes[4]],
+            command_set: bytes[5],
+            serial_number: u32::from_be_bytes([
+                bytes[6], bytes[7], bytes[8], bytes[9],
+            ]),
+            capabilities: Capabilities(u16::from_be_bytes([
+                bytes[10], bytes[11],
+            ])),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; HELLO_LEN] {
+        let mut bytes = [0u8; HELLO_LEN];
+        bytes[0..2].copy_from_slice(&self.model.to_be_bytes());
+        bytes[2..5].copy_from_slice(&self.firmware);
+        bytes[5] = self.command_set;
+        bytes[6..10].copy_from_slice(&self.serial_number.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.capabilities.0.to_be_bytes());
+        bytes
+    }
+
+    pub fn model_name(&self) -> String {
+        match self.model {
+            model::CONTROL_BOARD => "Control board".to_owned(),
+            model::SIMULATOR => "Simulated board".to_owned(),
+            other => format!("Unknown model {other:#06X}"),
+        }
+    }
+
+    /// Refuses boards whose opcodes mean something else, returns warnings
+    /// for what works only in part.
+    pub fn check(&self, sensors: &SensorMap) -> anyhow::Result<Vec<String>> {
+        if self.command_set != COMMAND_SET {
+            bail!(
+                "{self} uses command set {}, this version of Control needs {COMMAND_SET}; update the firmware",
+                self.command_set
+            );
+        }
+
+        let mut warnings = Vec::new();
+        if !self
+            .capabilities
+            .contains(Capabilities::DOORS)
+        {
+            warnings.push(format!(
+                "{} has no door locks, door commands will be rejected",
+                self.model_name()
+            ));
+        }
+
+        if sensors
+            .sensors()
+            .iter()
+            .any(|sensor| sensor.analog.is_some())
+            && !self
+                .capabilities
+                .contains(Capabilities::ANALOG)
+        {
+            warnings.push(format!(
+                "{} does not measure analog values, those sensors stay empty",
+                self.model_name()
+            ));
+        }
+
+        Ok(warnings)
+    }
+}
+
+impl Display for DeviceIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [major, minor, patch] = self.firmware;
+        write!(
+            f,
+            "{} #{:08}, firmware {major}.{minor}.{patch}",
+            self.model_name(),
+            self.serial_number
+        )
+    }
+}
+
+/// What [`handshake`] found out about a freshly opened device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Handshake {
+    /// `None` for firmware older than the HELLO command.
+    pub identity: Option<DeviceIdentity>,
+    /// Things that will not work with this device, shown to the operator.
+    pub warnings: Vec<String>,
+}
+
+/// Asks the device who it is before anything else is sent. Fails for
+/// devices that are not ours or use other opcodes.
+pub fn handshake(
+    transport: &mut dyn DeviceTransport,
+    protocol: ProtocolVersion,
+    sensors: &SensorMap,
+) -> anyhow::Result<Handshake> {
+    let unidentified = || Handshake {
+        identity: None,
+        warnings: vec![UNIDENTIFIED.to_owned()],
+    };
+
+    match transport.hello() {
+        Ok(identity) => Ok(Handshake {
+            warnings: identity.check(sensors)?,
+            identity: Some(identity),
+        }),
+        Err(ProtocolError::Rejected(nak::UNKNOWN_COMMAND)) => {
+            Ok(unidentified())
+        }
+        // Legacy firmware ignores opcodes it does not know
+        Err(ProtocolError::Timeout) if protocol == ProtocolVersion::Legacy => {
+            Ok(unidentified())
+        }
+        Err(err) => bail!(
+            "{} did not answer the handshake, is it a Control board? {err}",
+            transport.describe()
+        ),
+    }
+}
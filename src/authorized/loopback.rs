@@ -0,0 +1,179 @@
+use crate::authorized::protocol::{
+    Command, Link, ProtocolError, ProtocolSession, ProtocolVersion,
+};
+use crate::authorized::transport::DeviceTransport;
+use crate::simulator::device::{SimulatedDevice, SimulatorHandle};
+use crate::simulator::port::SimulatedPort;
+use serialport::{ClearBuffer, SerialPort};
+
+/// Talks to an in-memory [`SimulatedDevice`], no OS resources involved.
+#[derive(Debug)]
+pub struct LoopbackTransport {
+    port: SimulatedPort,
+    session: ProtocolSession,
+}
+
+impl LoopbackTransport {
+    pub fn new(handle: SimulatorHandle, protocol: ProtocolVersion) -> Self {
+        Self {
+            port: SimulatedPort::new(SimulatedDevice::new(handle)),
+            session: ProtocolSession::new(protocol),
+        }
+    }
+}
+
+impl DeviceTransport for LoopbackTransport {
+    fn describe(&self) -> String {
+        "loopback simulator".to_owned()
+    }
+
+    fn transact(&mut self, command: Command) -> Result<Vec<u8>, ProtocolError> {
+        self.session
+            .transact(&mut self.port, command)
+    }
+}
+
+impl Link for SimulatedPort {
+    fn discard_input(&mut self) {
+        let _ = self.clear(ClearBuffer::Input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorized::identity::handshake;
+    use crate::sensors::{SensorId, SensorMap};
+    use crate::simulator::device::SIMULATED_IDENTITY;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Short enough that a dropped reply does not hold the test up.
+    const TIMEOUT: Duration = Duration::from_millis(300);
+
+    fn board() -> (SimulatorHandle, Arc<SensorMap>) {
+        let sensors = Arc::new(SensorMap::default());
+        (
+            SimulatorHandle::new(sensors.clone()),
+            sensors,
+        )
+    }
+
+    fn connect(
+        board: &SimulatorHandle,
+        protocol: ProtocolVersion,
+    ) -> LoopbackTransport {
+        let mut transport = LoopbackTransport::new(board.clone(), protocol);
+        transport
+            .port
+            .set_timeout(TIMEOUT)
+            .unwrap();
+        transport
+    }
+
+    #[test]
+    fn polls_report_the_simulated_sensors() {
+        let (board, sensors) = board();
+        let front_door = SensorId::new("front_door");
+        board.set_sensor(&front_door, true);
+
+        for protocol in [ProtocolVersion::Framed, ProtocolVersion::Legacy] {
+            let mut transport = connect(&board, protocol);
+            let polled = transport.poll(&sensors).unwrap();
+            assert!(polled.get(&front_door), "{protocol:?}");
+            assert!(
+                !polled.get(&SensorId::new("back_door")),
+                "{protocol:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn door_commands_reach_the_board() {
+        let (board, _) = board();
+        let mut transport = connect(&board, ProtocolVersion::Framed);
+
+        transport.lock_front_door().unwrap();
+        transport.lock_back_door().unwrap();
+        assert!(board.lock().front_door_locked);
+        assert!(board.lock().back_door_locked);
+
+        transport.unlock_back_door().unwrap();
+        assert!(!board.lock().back_door_locked);
+        assert!(board.lock().front_door_locked);
+    }
+
+    #[test]
+    fn the_handshake_identifies_the_board() {
+        let (board, sensors) = board();
+        let mut transport = connect(&board, ProtocolVersion::Framed);
+        let found = handshake(
+            &mut transport,
+            ProtocolVersion::Framed,
+            &sensors,
+        )
+        .unwrap();
+        assert_eq!(found.identity, Some(SIMULATED_IDENTITY));
+        assert!(
+            found.warnings.is_empty(),
+            "{:?}",
+            found.warnings
+        );
+
+        // Firmware from before HELLO stays silent
+        board.lock().identity = None;
+        let mut transport = connect(&board, ProtocolVersion::Legacy);
+        let found = handshake(
+            &mut transport,
+            ProtocolVersion::Legacy,
+            &sensors,
+        )
+        .unwrap();
+        assert_eq!(found.identity, None);
+        assert_eq!(found.warnings.len(), 1);
+    }
+
+    #[test]
+    fn a_dropped_reply_times_out_once() {
+        let (board, sensors) = board();
+        let mut transport = connect(&board, ProtocolVersion::Framed);
+        board.update_faults(|faults| faults.dropped_replies = 1);
+
+        assert!(matches!(
+            transport.poll(&sensors),
+            Err(ProtocolError::Timeout)
+        ));
+        transport.poll(&sensors).unwrap();
+    }
+
+    #[test]
+    fn a_corrupted_reply_is_asked_for_again() {
+        let (board, sensors) = board();
+        let front_door = SensorId::new("front_door");
+        board.set_sensor(&front_door, true);
+        let mut transport = connect(&board, ProtocolVersion::Framed);
+        board.update_faults(|faults| faults.corrupt_replies = 1);
+
+        let polled = transport.poll(&sensors).unwrap();
+        assert!(polled.get(&front_door));
+        assert_eq!(board.lock().faults.corrupt_replies, 0);
+    }
+
+    #[test]
+    fn delayed_replies_arrive_within_the_timeout() {
+        let (board, sensors) = board();
+        let mut transport = connect(&board, ProtocolVersion::Framed);
+        let delay = TIMEOUT / 3;
+        board.update_faults(|faults| faults.reply_delay = delay);
+
+        let started = Instant::now();
+        transport.poll(&sensors).unwrap();
+        assert!(started.elapsed() >= delay);
+
+        board.update_faults(|faults| faults.reply_delay = TIMEOUT * 2);
+        assert!(matches!(
+            transport.poll(&sensors),
+            Err(ProtocolError::Timeout)
+        ));
+    }
+}
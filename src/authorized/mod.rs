@@ -1,2 +1,9 @@
 pub mod api;
+pub mod identity;
+pub mod loopback;
+pub mod protocol;
+pub mod reconnect;
 pub mod serial_connection;
+pub mod tcp_transport;
+pub mod transport;
+pub mod worker;
@@ -0,0 +1,612 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Write};
+
+/// Marks the beginning of every frame, both directions.
+pub const FRAME_START: u8 = 0x7E;
+/// Version of the framed protocol written into every frame header.
+pub const FRAME_VERSION: u8 = 0x01;
+/// Longest payload a frame may carry. The firmware buffer is this big.
+pub const MAX_PAYLOAD: usize = 16;
+/// The legacy firmware answers a poll with exactly the two state bytes.
+pub const LEGACY_STATE_LEN: usize = 2;
+/// Length of the answer to [`Command::Hello`], see
+/// [`DeviceIdentity`](crate::authorized::identity::DeviceIdentity).
+pub const HELLO_LEN: usize = 12;
+/// How many times a framed request is sent again after a corrupted answer.
+const MAX_RETRIES: usize = 3;
+/// How many bytes we are willing to throw away while looking for
+/// [`FRAME_START`] before declaring the line desynchronized.
+const MAX_SKIPPED_BYTES: usize = 64;
+
+/// Frame layout:
+///
+/// ```text
+/// | 0x7E | version | sequence | kind | length | payload[length] | crc16 |
+/// ```
+///
+/// The CRC is CRC-16/CCITT-FALSE over everything between the start marker
+/// and the CRC itself, sent big-endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub version: u8,
+    pub sequence: u8,
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolVersion {
+    /// One opcode byte out, raw bytes back, two of them for a poll. No error
+    /// detection at all.
+    Legacy,
+    /// [`Frame`]-based protocol with sequence numbers and a CRC.
+    #[default]
+    Framed,
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolVersion::Legacy => write!(f, "Legacy (1 byte)"),
+            ProtocolVersion::Framed => write!(f, "Framed v{FRAME_VERSION}"),
+        }
+    }
+}
+
+/// Commands understood by the device. The discriminants are the opcodes of
+/// the legacy protocol and are reused as frame kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Who the device is, sent once after connecting.
+    Hello = 0x48,
+    Poll = 0xAA,
+    Reset = 0x55,
+    LockBackDoor = 0xA2,
+    UnlockBackDoor = 0xA3,
+    LockFrontDoor = 0xA4,
+    UnlockFrontDoor = 0xA5,
+}
+
+impl Command {
+    pub fn opcode(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Frame kinds the device answers with.
+pub mod response {
+    pub const STATE: u8 = 0x01;
+    pub const HELLO: u8 = 0x02;
+    pub const ACK: u8 = 0x06;
+    pub const NAK: u8 = 0x15;
+}
+
+/// Reason codes carried in the payload of a NAK frame.
+pub mod nak {
+    pub const BAD_CHECKSUM: u8 = 0x01;
+    pub const UNKNOWN_COMMAND: u8 = 0x02;
+    pub const BAD_LENGTH: u8 = 0x03;
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    Timeout,
+    Desync { skipped: usize },
+    UnsupportedVersion(u8),
+    PayloadTooLong(usize),
+    ChecksumMismatch { expected: u16, actual: u16 },
+    SequenceMismatch { expected: u8, actual: u8 },
+    UnexpectedResponse(u8),
+    Rejected(u8),
+    EmptyPayload,
+    PayloadTooShort { needed: usize, actual: usize },
+    MalformedIdentity(usize),
+}
+
+impl ProtocolError {
+    /// Whether sending the same request again has a chance of succeeding.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::ChecksumMismatch { .. }
+                | ProtocolError::Rejected(nak::BAD_CHECKSUM)
+                | ProtocolError::SequenceMismatch { .. }
+        )
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "I/O error: {err}"),
+            ProtocolError::Timeout => {
+                write!(f, "Device did not answer in time")
+            }
+            ProtocolError::Desync { skipped } => write!(
+                f,
+                "Lost frame sync, skipped {skipped} bytes of garbage"
+            ),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported protocol version {version}"
+                )
+            }
+            ProtocolError::PayloadTooLong(len) => write!(
+                f,
+                "Payload of {len} bytes exceeds the {MAX_PAYLOAD} byte limit"
+            ),
+            ProtocolError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {expected:#06X}, got {actual:#06X}"
+            ),
+            ProtocolError::SequenceMismatch { expected, actual } => write!(
+                f,
+                "Sequence mismatch: expected {expected}, got {actual}"
+            ),
+            ProtocolError::UnexpectedResponse(kind) => {
+                write!(
+                    f,
+                    "Unexpected response kind {kind:#04X}"
+                )
+            }
+            ProtocolError::Rejected(code) => {
+                let reason = match *code {
+                    nak::BAD_CHECKSUM => "bad checksum",
+                    nak::UNKNOWN_COMMAND => "unknown command",
+                    nak::BAD_LENGTH => "bad length",
+                    _ => "unknown reason",
+                };
+
+                write!(
+                    f,
+                    "Device rejected the command: {reason} ({code:#04X})"
+                )
+            }
+            ProtocolError::EmptyPayload => {
+                write!(f, "Device sent an empty state report")
+            }
+            ProtocolError::PayloadTooShort { needed, actual } => write!(
+                f,
+                "Device sent a {actual} byte state report, the sensor map needs {needed}"
+            ),
+            ProtocolError::MalformedIdentity(len) => write!(
+                f,
+                "Device sent a {len} byte identity, expected {HELLO_LEN}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                ProtocolError::Timeout
+            }
+            _ => ProtocolError::Io(err),
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(sequence: u8, kind: u8, payload: Vec<u8>) -> Self {
+        Self {
+            version: FRAME_VERSION,
+            sequence,
+            kind,
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        if self.payload.len() > MAX_PAYLOAD {
+            return Err(ProtocolError::PayloadTooLong(
+                self.payload.len(),
+            ));
+        }
+
+        let mut bytes = Vec::with_capacity(self.payload.len() + 7);
+        bytes.push(FRAME_START);
+        bytes.push(self.version);
+        bytes.push(self.sequence);
+        bytes.push(self.kind);
+        bytes.push(self.payload.len() as u8);
+        bytes.extend_from_slice(&self.payload);
+
+        let crc = crc16(&bytes[1..]);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+
+        Ok(bytes)
+    }
+
+    /// Reads exactly one frame, skipping any garbage before the start
+    /// marker.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, ProtocolError> {
+        let mut byte = [0u8; 1];
+        let mut skipped = 0;
+        loop {
+            reader.read_exact(&mut byte)?;
+            if byte[0] == FRAME_START {
+                break;
+            }
+
+            skipped += 1;
+            if skipped > MAX_SKIPPED_BYTES {
+                return Err(ProtocolError::Desync { skipped });
+            }
+        }
+
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let [version, sequence, kind, len] = header;
+
+        if version != FRAME_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(
+                version,
+            ));
+        }
+
+        if len as usize > MAX_PAYLOAD {
+            return Err(ProtocolError::PayloadTooLong(
+                len as usize,
+            ));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload)?;
+
+        let mut crc = [0u8; 2];
+        reader.read_exact(&mut crc)?;
+        let actual = u16::from_be_bytes(crc);
+
+        let mut checked = header.to_vec();
+        checked.extend_from_slice(&payload);
+        let expected = crc16(&checked);
+
+        if expected != actual {
+            return Err(ProtocolError::ChecksumMismatch { expected, actual });
+        }
+
+        Ok(Self { version, sequence, kind, payload })
+    }
+}
+
+/// CRC-16/CCITT-FALSE: poly 0x1021, init 0xFFFF, no reflection, no xor out.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// A byte pipe to the device the protocol can run over.
+pub trait Link: Read + Write {
+    /// Throws away whatever the device sent that nobody read yet.
+    fn discard_input(&mut self);
+}
+
+/// Protocol state that outlives a single request: which dialect we speak and
+/// the sequence counter for framed requests.
+#[derive(Debug)]
+pub struct ProtocolSession {
+    version: ProtocolVersion,
+    sequence: u8,
+}
+
+impl ProtocolSession {
+    pub fn new(version: ProtocolVersion) -> Self {
+        Self { version, sequence: 0 }
+    }
+
+    /// Sends a command and returns the payload of the answer.
+    pub fn transact(
+        &mut self,
+        link: &mut dyn Link,
+        command: Command,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        match self.version {
+            ProtocolVersion::Legacy => {
+                let result = Self::transact_legacy(link, command);
+                if result.is_err() {
+                    // A late or partial answer would be read as the next one
+                    link.discard_input();
+                }
+                result
+            }
+            ProtocolVersion::Framed => {
+                let mut attempt = 0;
+                loop {
+                    match self.transact_framed(link, command) {
+                        Err(err)
+                            if err.is_retryable() && attempt < MAX_RETRIES =>
+                        {
+                            attempt += 1;
+                            eprintln!(
+                                "Retrying {command:?} ({attempt}/{MAX_RETRIES}): {err}"
+                            );
+                            // Whatever is left in the buffer belongs to the
+                            // broken frame
+                            link.discard_input();
+                        }
+                        result => return result,
+                    }
+                }
+            }
+        }
+    }
+
+    fn transact_legacy(
+        link: &mut dyn Link,
+        command: Command,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        // Four times a second per device, too much for a service's journal
+        if cfg!(debug_assertions) {
+            println!("Sending: {:#04X}", command.opcode());
+        }
+        link.write_all(&[command.opcode()])?;
+
+        // api.ino carries out the other commands without a word
+        let len = match command {
+            Command::Poll => LEGACY_STATE_LEN,
+            Command::Hello => HELLO_LEN,
+            _ => 0,
+        };
+        let mut response = vec![0u8; len];
+        link.read_exact(&mut response)?;
+
+        if cfg!(debug_assertions) {
+            println!("Received response: {:?}", response);
+        }
+
+        Ok(response)
+    }
+
+    fn transact_framed(
+        &mut self,
+        link: &mut dyn Link,
+        command: Command,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let sequence = self.sequence;
+        let request = Frame::new(sequence, command.opcode(), Vec::new());
+
+        if cfg!(debug_assertions) {
+            println!("Sending: {:?}", request);
+        }
+        link.write_all(&request.encode()?)?;
+
+        let answer = Frame::read_from(&mut &mut *link)?;
+        if cfg!(debug_assertions) {
+            println!("Received response: {:?}", answer);
+        }
+
+        if answer.sequence != sequence {
+            return Err(ProtocolError::SequenceMismatch {
+                expected: sequence,
+                actual: answer.sequence,
+            });
+        }
+
+        match (command, answer.kind) {
+            (Command::Poll, response::STATE) => Ok(answer.payload),
+            (Command::Hello, response::HELLO) => Ok(answer.payload),
+            (_, response::ACK)
+                if !matches!(command, Command::Poll | Command::Hello) =>
+            {
+                Ok(answer.payload)
+            }
+            (_, response::NAK) => Err(ProtocolError::Rejected(
+                answer
+                    .payload
+                    .first()
+                    .copied()
+                    .unwrap_or_default(),
+            )),
+            (_, kind) => Err(ProtocolError::UnexpectedResponse(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A device that answers every write with the next canned reply.
+    struct Wire {
+        answers: VecDeque<Vec<u8>>,
+        input: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl Wire {
+        fn new(answers: impl IntoIterator<Item = Vec<u8>>) -> Self {
+            Self {
+                answers: answers.into_iter().collect(),
+                input: VecDeque::new(),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            if let Some(answer) = self.answers.pop_front() {
+                self.input.extend(answer);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Link for Wire {
+        fn discard_input(&mut self) {
+            self.input.clear();
+        }
+    }
+
+    fn encoded(frame: &Frame) -> Vec<u8> {
+        frame.encode().unwrap()
+    }
+
+    #[test]
+    fn crc16_matches_the_ccitt_false_check_values() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), 0xFFFF);
+        assert_eq!(crc16(&[0x00]), 0xE1F0);
+    }
+
+    #[test]
+    fn frames_survive_encoding() {
+        let frame = Frame::new(7, response::STATE, vec![0x12, 0x34]);
+        let bytes = encoded(&frame);
+        let crc = crc16(&bytes[1..7]).to_be_bytes();
+        assert_eq!(
+            bytes,
+            [
+                FRAME_START,
+                FRAME_VERSION,
+                7,
+                response::STATE,
+                2,
+                0x12,
+                0x34,
+                crc[0],
+                crc[1]
+            ]
+        );
+
+        let decoded = Frame::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded, frame);
+        assert!(matches!(
+            Frame::new(1, 1, vec![0; MAX_PAYLOAD + 1]).encode(),
+            Err(ProtocolError::PayloadTooLong(17))
+        ));
+    }
+
+    #[test]
+    fn garbage_before_a_frame_is_skipped() {
+        let frame = Frame::new(3, response::ACK, Vec::new());
+        let mut bytes = vec![0x00, 0xFF, 0x42];
+        bytes.extend(encoded(&frame));
+        assert_eq!(
+            Frame::read_from(&mut bytes.as_slice()).unwrap(),
+            frame
+        );
+
+        let mut flooded = vec![0x00; MAX_SKIPPED_BYTES + 1];
+        flooded.extend(encoded(&frame));
+        assert!(matches!(
+            Frame::read_from(&mut flooded.as_slice()),
+            Err(ProtocolError::Desync { .. })
+        ));
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let frame = Frame::new(3, response::STATE, vec![0x01, 0x02]);
+
+        let mut flipped = encoded(&frame);
+        flipped[5] ^= 0x10;
+        assert!(matches!(
+            Frame::read_from(&mut flipped.as_slice()),
+            Err(ProtocolError::ChecksumMismatch { .. })
+        ));
+
+        let mut versioned = encoded(&frame);
+        versioned[1] = 0x09;
+        assert!(matches!(
+            Frame::read_from(&mut versioned.as_slice()),
+            Err(ProtocolError::UnsupportedVersion(0x09))
+        ));
+
+        let mut truncated = encoded(&frame);
+        truncated.pop();
+        assert!(matches!(
+            Frame::read_from(&mut truncated.as_slice()),
+            Err(ProtocolError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn framed_requests_are_retried_after_a_corrupted_answer() {
+        let mut corrupted = encoded(&Frame::new(
+            1,
+            response::ACK,
+            Vec::new(),
+        ));
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        let retried = encoded(&Frame::new(
+            2,
+            response::ACK,
+            Vec::new(),
+        ));
+        let mut wire = Wire::new([corrupted, retried]);
+
+        let mut session = ProtocolSession::new(ProtocolVersion::Framed);
+        session
+            .transact(&mut wire, Command::Reset)
+            .unwrap();
+        let request = encoded(&Frame::new(
+            2,
+            Command::Reset.opcode(),
+            Vec::new(),
+        ));
+        assert!(wire.written.ends_with(&request));
+    }
+
+    #[test]
+    fn legacy_outputs_expect_no_answer() {
+        let mut session = ProtocolSession::new(ProtocolVersion::Legacy);
+
+        let mut wire = Wire::new([]);
+        let answer = session
+            .transact(&mut wire, Command::LockFrontDoor)
+            .unwrap();
+        assert!(answer.is_empty());
+        assert_eq!(
+            wire.written,
+            [Command::LockFrontDoor.opcode()]
+        );
+
+        let mut wire = Wire::new([vec![0x05, 0x00]]);
+        let state = session
+            .transact(&mut wire, Command::Poll)
+            .unwrap();
+        assert_eq!(state, [0x05, 0x00]);
+    }
+}
@@ -0,0 +1,141 @@
+use serialport::SerialPortType;
+use std::time::{Duration, Instant};
+
+const FIRST_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// What identifies a USB serial adapter independent of the COM/tty name the
+/// OS happened to give it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+}
+
+impl UsbIdentity {
+    /// Looks up the identity of a currently present port.
+    pub fn of_port(port_name: &str) -> Option<Self> {
+        serialport::available_ports()
+            .ok()?
+            .into_iter()
+            .find(|p| p.port_name == port_name)
+            .and_then(|p| match p.port_type {
+                SerialPortType::UsbPort(info) => Some(Self {
+                    vid: info.vid,
+                    pid: info.pid,
+                    serial_number: info.serial_number,
+                }),
+                _ => None,
+            })
+    }
+
+    /// Finds the port this device is currently attached to, if any.
+    pub fn find_port(&self) -> Option<String> {
+        serialport::available_ports()
+            .ok()?
+            .into_iter()
+            .find(|p| match &p.port_type {
+                SerialPortType::UsbPort(info) => {
+                    info.vid == self.vid
+                        && info.pid == self.pid
+                        && (self.serial_number.is_none()
+                            || info.serial_number == self.serial_number)
+                }
+                _ => false,
+            })
+            .map(|p| p.port_name)
+    }
+}
+
+/// Exponential backoff between reconnect attempts: 0.5 s, 1 s, 2 s, ...
+/// capped at 30 s.
+#[derive(Debug)]
+pub struct Backoff {
+    attempt: u32,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { attempt: 0, next_attempt: Instant::now() }
+    }
+
+    /// Number of attempts made so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_attempt(&self) -> Instant {
+        self.next_attempt
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Records a failed attempt and returns how long to wait for the next
+    /// one.
+    pub fn failed(&mut self) -> Duration {
+        let delay = FIRST_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(MAX_DELAY);
+
+        self.attempt += 1;
+        self.next_attempt = Instant::now() + delay;
+
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_from_half_a_second() {
+        let mut backoff = Backoff::new();
+        assert!(backoff.is_due());
+
+        let delays: Vec<_> = (0..4)
+            .map(|_| backoff.failed())
+            .collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.attempt(), 4);
+        assert!(!backoff.is_due());
+        assert!(backoff.next_attempt() > Instant::now());
+    }
+
+    #[test]
+    fn delays_stop_growing_at_the_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..100)
+            .map(|_| backoff.failed())
+            .collect();
+
+        assert_eq!(delays[6], MAX_DELAY);
+        assert!(
+            delays[6..]
+                .iter()
+                .all(|delay| *delay == MAX_DELAY)
+        );
+        assert_eq!(backoff.attempt(), 100);
+    }
+
+    #[test]
+    fn a_new_backoff_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.failed();
+        }
+
+        // What the worker does after a successful reconnect
+        backoff = Backoff::new();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.is_due());
+        assert_eq!(backoff.failed(), FIRST_DELAY);
+    }
+}
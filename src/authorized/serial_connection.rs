@@ -1,169 +1,127 @@
-use crate::fluent::bit_inspect::BitInspect;
-use crate::fluent::containers::Innable;
-use eframe::egui::{Color32, Response, Ui, Widget};
-use serialport::SerialPort;
-use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+use crate::authorized::protocol::{
+    Command, Link, ProtocolError, ProtocolSession, ProtocolVersion,
+};
+use crate::authorized::transport::DeviceTransport;
+use crate::config::DeviceConfig;
+use crate::sensors::SensorId;
+use serialport::{ClearBuffer, SerialPort};
 
 #[derive(Debug)]
 pub struct SerialConnection {
-    port: Arc<Mutex<Box<dyn SerialPort>>>,
+    port: Box<dyn SerialPort>,
+    session: ProtocolSession,
 }
 
-#[derive(Debug)]
+/// Sensor values of one poll, in sensor map order.
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct PollResult {
-    pub open_door_front: bool,
-    pub motion_detected_1: bool,
-    pub motion_detected_2: bool,
-    pub accelerometer: bool,
-    pub fire_detected: bool,
-    pub door_invade: bool,
-    pub open_door_back: bool,
+    readings: Vec<SensorReading>,
 }
 
-impl SerialConnection {
-    pub fn new(port_name: &str) -> anyhow::Result<Self> {
-        let opened = serialport::new(port_name, 9600)
-            .timeout(std::time::Duration::from_secs(3))
-            .open()?;
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorReading {
+    pub id: SensorId,
+    /// Tripped, for analog sensors: past one of the thresholds.
+    pub active: bool,
+    /// Measured value of analog sensors.
+    pub value: Option<f64>,
+}
 
-        Ok(Self { port: opened.in_mutex().in_arc() })
+impl PollResult {
+    pub fn new(readings: Vec<SensorReading>) -> Self {
+        Self { readings }
     }
 
-    pub fn send_poll(&self) -> anyhow::Result<PollResult> {
-        let mut guard = self
-            .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0xAA");
-        guard.write(&[0xAA])?;
-
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
-
-        println!("Received response: {:?}", response);
-
-        Ok(PollResult {
-            open_door_front: response[0].is_bit_set(1),
-            motion_detected_1: response[0].is_bit_set(6),
-            motion_detected_2: response[0].is_bit_set(5),
-            accelerometer: response[0].is_bit_set(4),
-            fire_detected: response[0].is_bit_set(3),
-            door_invade: response[0].is_bit_set(2),
-            open_door_back: response[0].is_bit_set(7),
-        })
+    /// Sensors missing from the poll count as inactive.
+    pub fn get(&self, id: &SensorId) -> bool {
+        self.readings
+            .iter()
+            .any(|reading| &reading.id == id && reading.active)
     }
 
-    pub fn send_reset(&self) -> anyhow::Result<()> {
-        let mut guard = self
-            .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0x55");
-
-        guard.write(&[0x55])?;
-
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
-
-        println!("Received response: {:?}", response);
-
-        Ok(())
+    pub fn value(&self, id: &SensorId) -> Option<f64> {
+        self.readings
+            .iter()
+            .find(|reading| &reading.id == id)
+            .and_then(|reading| reading.value)
     }
 
-    pub fn send_unlock_back_door(&self) -> anyhow::Result<()> {
-        let mut guard = self
-            .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0xA3");
-
-        guard.write(&[0xA3])?;
-
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
-
-        println!("Received response: {:?}", response);
-
-        Ok(())
+    pub fn set(&mut self, id: &SensorId, active: bool) {
+        self.reading_mut(id).active = active;
     }
 
-    pub fn send_lock_back_door(&self) -> anyhow::Result<()> {
-        let mut guard = self
-            .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0xA2");
-
-        guard.write(&[0xA2])?;
-
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
-
-        println!("Received response: {:?}", response);
-
-        Ok(())
+    pub fn set_value(&mut self, id: &SensorId, value: f64) {
+        self.reading_mut(id).value = Some(value);
     }
 
-    pub fn send_lock_front_door(&self) -> anyhow::Result<()> {
-        let mut guard = self
-            .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0xA4");
-
-        guard.write(&[0xA4])?;
+    fn reading_mut(&mut self, id: &SensorId) -> &mut SensorReading {
+        let index = match self
+            .readings
+            .iter()
+            .position(|reading| &reading.id == id)
+        {
+            Some(index) => index,
+            None => {
+                self.readings.push(SensorReading {
+                    id: id.clone(),
+                    active: false,
+                    value: None,
+                });
+                self.readings.len() - 1
+            }
+        };
+
+        &mut self.readings[index]
+    }
 
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
+    /// Sensors whose value differs from `previous`, with the new value.
+    pub fn changes_since(
+        &self,
+        previous: &PollResult,
+    ) -> Vec<(SensorId, bool)> {
+        self.readings
+            .iter()
+            .filter(|reading| reading.active != previous.get(&reading.id))
+            .map(|reading| (reading.id.clone(), reading.active))
+            .collect()
+    }
+}
 
-        println!("Received response: {:?}", response);
+impl SerialConnection {
+    pub fn new(
+        port_name: &str,
+        protocol: ProtocolVersion,
+        device: &DeviceConfig,
+    ) -> anyhow::Result<Self> {
+        let opened = serialport::new(port_name, device.baud_rate)
+            .timeout(device.timeout())
+            .open()?;
 
-        Ok(())
+        Ok(Self {
+            port: opened,
+            session: ProtocolSession::new(protocol),
+        })
     }
+}
 
-    pub fn send_unlock_front_door(&self) -> anyhow::Result<()> {
-        let mut guard = self
+impl DeviceTransport for SerialConnection {
+    fn describe(&self) -> String {
+        let port_name = self
             .port
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Poisoned mutex"))?;
-
-        println!("Sending: 0xA5");
+            .name()
+            .unwrap_or_else(|| "<unknown port>".to_owned());
 
-        guard.write(&[0xA5])?;
-
-        let mut response = [0u8; 1];
-        guard.read_exact(&mut response)?;
-
-        println!("Received response: {:?}", response);
-
-        Ok(())
+        format!("port {port_name}")
     }
 
-    pub fn widget(&mut self) -> impl Widget {
-        SerialConnectionWidget { connection: self }
+    fn transact(&mut self, command: Command) -> Result<Vec<u8>, ProtocolError> {
+        self.session
+            .transact(&mut self.port, command)
     }
 }
 
-pub struct SerialConnectionWidget<'a> {
-    connection: &'a mut SerialConnection,
-}
-
-impl Widget for SerialConnectionWidget<'_> {
-    fn ui(self, ui: &mut Ui) -> Response {
-        if let Ok(mut guard) = self.connection.port.lock() {
-            let port_name = guard
-                .name()
-                .unwrap_or_else(|| "<unknown port>".to_owned());
-
-            ui.label(format!("Connected to port {port_name}"))
-        } else {
-            ui.colored_label(Color32::RED, "Mutex Poisoned")
-        }
+impl Link for Box<dyn SerialPort> {
+    fn discard_input(&mut self) {
+        let _ = self.clear(ClearBuffer::Input);
     }
 }
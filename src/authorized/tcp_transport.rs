@@ -0,0 +1,73 @@
+use crate::authorized::protocol::{
+    Command, Link, ProtocolError, ProtocolSession, ProtocolVersion,
+};
+use crate::authorized::transport::DeviceTransport;
+use anyhow::Context;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Device behind a raw TCP bridge such as ser2net. The bytes on the socket
+/// are exactly the bytes on the serial line.
+#[derive(Debug)]
+pub struct TcpTransport {
+    address: String,
+    stream: TcpStream,
+    session: ProtocolSession,
+}
+
+impl TcpTransport {
+    pub fn connect(
+        address: &str,
+        protocol: ProtocolVersion,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let resolved = address
+            .to_socket_addrs()
+            .with_context(|| format!("Invalid address '{address}'"))?
+            .next()
+            .with_context(|| format!("'{address}' did not resolve"))?;
+
+        let stream = TcpStream::connect_timeout(&resolved, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            address: address.to_owned(),
+            stream,
+            session: ProtocolSession::new(protocol),
+        })
+    }
+}
+
+impl DeviceTransport for TcpTransport {
+    fn describe(&self) -> String {
+        format!("tcp://{}", self.address)
+    }
+
+    fn transact(&mut self, command: Command) -> Result<Vec<u8>, ProtocolError> {
+        self.session
+            .transact(&mut self.stream, command)
+    }
+}
+
+impl Link for TcpStream {
+    fn discard_input(&mut self) {
+        if self.set_nonblocking(true).is_err() {
+            return;
+        }
+
+        let mut buffer = [0u8; 64];
+        loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+
+        let _ = self.set_nonblocking(false);
+    }
+}
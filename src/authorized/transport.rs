@@ -0,0 +1,105 @@
+use crate::authorized::identity::{DeviceIdentity, Handshake, handshake};
+use crate::authorized::loopback::LoopbackTransport;
+use crate::authorized::protocol::{Command, ProtocolError, ProtocolVersion};
+use crate::authorized::serial_connection::{PollResult, SerialConnection};
+use crate::authorized::tcp_transport::TcpTransport;
+use crate::config::DeviceConfig;
+use crate::sensors::SensorMap;
+use crate::simulator::device::SimulatorHandle;
+use std::fmt::Debug;
+
+/// Anything that can carry device commands. Implementors only provide
+/// [`DeviceTransport::transact`], the commands are built on top of it.
+pub trait DeviceTransport: Send + Debug {
+    /// Human readable description of the other end, e.g. `port COM3`.
+    fn describe(&self) -> String;
+
+    /// Sends a command and returns the payload of the answer.
+    fn transact(&mut self, command: Command) -> Result<Vec<u8>, ProtocolError>;
+
+    fn hello(&mut self) -> Result<DeviceIdentity, ProtocolError> {
+        let response = self.transact(Command::Hello)?;
+        DeviceIdentity::parse(&response)
+    }
+
+    fn poll(
+        &mut self,
+        sensors: &SensorMap,
+    ) -> Result<PollResult, ProtocolError> {
+        let response = self.transact(Command::Poll)?;
+        sensors.decode(&response)
+    }
+
+    fn reset(&mut self) -> Result<(), ProtocolError> {
+        self.transact(Command::Reset)
+            .map(|_| ())
+    }
+
+    fn lock_back_door(&mut self) -> Result<(), ProtocolError> {
+        self.transact(Command::LockBackDoor)
+            .map(|_| ())
+    }
+
+    fn unlock_back_door(&mut self) -> Result<(), ProtocolError> {
+        self.transact(Command::UnlockBackDoor)
+            .map(|_| ())
+    }
+
+    fn lock_front_door(&mut self) -> Result<(), ProtocolError> {
+        self.transact(Command::LockFrontDoor)
+            .map(|_| ())
+    }
+
+    fn unlock_front_door(&mut self) -> Result<(), ProtocolError> {
+        self.transact(Command::UnlockFrontDoor)
+            .map(|_| ())
+    }
+}
+
+/// Where to find the device and how to reach it.
+#[derive(Debug, Clone)]
+pub enum TransportConfig {
+    Serial {
+        port_name: String,
+    },
+    /// Raw TCP socket, e.g. a ser2net bridge in front of the board.
+    Tcp {
+        address: String,
+    },
+    /// In-memory simulated board.
+    Loopback(SimulatorHandle),
+}
+
+impl TransportConfig {
+    /// Opens the link and runs the [`handshake`], a device that fails it is
+    /// closed again.
+    pub fn open(
+        &self,
+        protocol: ProtocolVersion,
+        device: &DeviceConfig,
+        sensors: &SensorMap,
+    ) -> anyhow::Result<(Box<dyn DeviceTransport>, Handshake)> {
+        let mut transport: Box<dyn DeviceTransport> = match self {
+            TransportConfig::Serial { port_name } => Box::new(
+                SerialConnection::new(port_name, protocol, device)?,
+            ),
+            TransportConfig::Tcp { address } => Box::new(
+                TcpTransport::connect(address, protocol, device.timeout())?,
+            ),
+            TransportConfig::Loopback(handle) => Box::new(
+                LoopbackTransport::new(handle.clone(), protocol),
+            ),
+        };
+
+        let handshake = handshake(transport.as_mut(), protocol, sensors)?;
+        Ok((transport, handshake))
+    }
+
+    pub fn target(&self) -> String {
+        match self {
+            TransportConfig::Serial { port_name } => port_name.clone(),
+            TransportConfig::Tcp { address } => address.clone(),
+            TransportConfig::Loopback(_) => "loopback".to_owned(),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::authorized::identity::{
+    DeviceIdentity, FRONT_DOOR_REFUSED, Handshake,
+};
+use crate::authorized::protocol::{ProtocolError, ProtocolVersion};
+use crate::authorized::reconnect::{Backoff, UsbIdentity};
+use crate::authorized::serial_connection::PollResult;
+use crate::authorized::transport::{DeviceTransport, TransportConfig};
+use crate::config::DeviceConfig;
+use crate::sensors::SensorMap;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum WorkerCommand {
+    Connect(TransportConfig, ProtocolVersion),
+    Disconnect,
+    /// New link settings, the open connection keeps its baud rate and
+    /// timeout until the next (re)connect.
+    Configure(DeviceConfig),
+    Poll,
+    Reset,
+    LockBackDoor,
+    UnlockBackDoor,
+    LockFrontDoor,
+    UnlockFrontDoor,
+}
+
+#[derive(Debug)]
+pub enum WorkerEvent {
+    Connected {
+        description: String,
+        handshake: Handshake,
+    },
+    ConnectFailed(String, String),
+    Disconnected,
+    /// The connection was lost, `attempt` is the number of the next try.
+    Reconnecting {
+        target: String,
+        attempt: u32,
+    },
+    Polled(PollResult),
+    PollFailed(String),
+    CommandFailed(String),
+}
+
+/// The last device we were successfully connected to.
+#[derive(Debug)]
+struct Target {
+    config: TransportConfig,
+    protocol: ProtocolVersion,
+    usb: Option<UsbIdentity>,
+    /// Serial number from the handshake, a reconnect must find the same
+    /// board.
+    serial_number: Option<u32>,
+}
+
+/// Owns the device transport and does all the blocking I/O, so the UI thread
+/// never waits on a port timeout.
+pub struct Worker {
+    commands: Receiver<WorkerCommand>,
+    events: Sender<WorkerEvent>,
+    connection: Option<Box<dyn DeviceTransport>>,
+    /// From the handshake of `connection`, `None` for legacy firmware.
+    identity: Option<DeviceIdentity>,
+    next_poll: Instant,
+    target: Option<Target>,
+    reconnect: Option<Backoff>,
+    device: DeviceConfig,
+    sensors: Arc<SensorMap>,
+}
+
+impl Worker {
+    pub fn new(
+        commands: Receiver<WorkerCommand>,
+        events: Sender<WorkerEvent>,
+        device: DeviceConfig,
+        sensors: Arc<SensorMap>,
+    ) -> Self {
+        Self {
+            commands,
+            events,
+            device,
+            sensors,
+            connection: None,
+            identity: None,
+            next_poll: Instant::now(),
+            target: None,
+            reconnect: None,
+        }
+    }
+
+    pub fn run(mut self) {
+        loop {
+            let wake_at = match (&self.connection, &self.reconnect) {
+                (Some(_), _) => Some(self.next_poll),
+                (None, Some(backoff)) => Some(backoff.next_attempt()),
+                (None, None) => None,
+            };
+            let timeout = wake_at.map_or(Duration::from_secs(60), |at| {
+                at.saturating_duration_since(Instant::now())
+            });
+
+            match self.commands.recv_timeout(timeout) {
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                // The handle was dropped, nobody is listening anymore
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            if self.connection.is_some() && Instant::now() >= self.next_poll {
+                self.poll();
+            }
+
+            if self.connection.is_none()
+                && self
+                    .reconnect
+                    .as_ref()
+                    .is_some_and(Backoff::is_due)
+            {
+                self.try_reconnect();
+            }
+        }
+    }
+
+    fn handle(&mut self, command: WorkerCommand) {
+        let result = match command {
+            WorkerCommand::Connect(config, protocol) => {
+                self.connect(config, protocol);
+                return;
+            }
+            WorkerCommand::Disconnect => {
+                self.disconnect();
+                return;
+            }
+            WorkerCommand::Configure(device) => {
+                self.device = device;
+                return;
+            }
+            WorkerCommand::Poll => {
+                self.poll();
+                return;
+            }
+            WorkerCommand::Reset => self.with_connection(|c| c.reset()),
+            WorkerCommand::LockBackDoor => {
+                self.with_connection(|c| c.lock_back_door())
+            }
+            WorkerCommand::UnlockBackDoor => {
+                self.with_connection(|c| c.unlock_back_door())
+            }
+            WorkerCommand::LockFrontDoor => {
+                self.with_connection(|c| c.lock_front_door())
+            }
+            // Legacy firmware would set off the alarm
+            WorkerCommand::UnlockFrontDoor
+                if self.connection.is_some() && self.identity.is_none() =>
+            {
+                Err(anyhow::anyhow!(FRONT_DOOR_REFUSED))
+            }
+            WorkerCommand::UnlockFrontDoor => {
+                self.with_connection(|c| c.unlock_front_door())
+            }
+        };
+
+        if let Err(err) = result {
+            self.emit(WorkerEvent::CommandFailed(format!(
+                "{err}"
+            )));
+        }
+    }
+
+    fn with_connection(
+        &mut self,
+        command: impl FnOnce(&mut dyn DeviceTransport) -> Result<(), ProtocolError>,
+    ) -> anyhow::Result<()> {
+        match &mut self.connection {
+            Some(connection) => Ok(command(connection.as_mut())?),
+            None => anyhow::bail!("No active connection"),
+        }
+    }
+
+    fn connect(&mut self, config: TransportConfig, protocol: ProtocolVersion) {
+        println!(
+            "Connecting to {} ({})",
+            config.target(),
+            protocol
+        );
+        self.disconnect();
+
+        match config.open(protocol, &self.device, &self.sensors) {
+            Ok((connection, handshake)) => {
+                let usb = match &config {
+                    TransportConfig::Serial { port_name } => {
+                        UsbIdentity::of_port(port_name)
+                    }
+                    _ => None,
+                };
+
+                let serial_number = handshake
+                    .identity
+                    .map(|identity| identity.serial_number);
+                self.target =
+                    Some(Target { config, protocol, usb, serial_number });
+                self.attach(connection, handshake);
+            }
+
+            Err(err) => {
+                eprintln!("Failed to connect: {}", err);
+                self.emit(WorkerEvent::ConnectFailed(
+                    config.target(),
+                    err.to_string(),
+                ));
+            }
+        }
+    }
+
+    fn attach(
+        &mut self,
+        connection: Box<dyn DeviceTransport>,
+        handshake: Handshake,
+    ) {
+        let description = connection.describe();
+        self.connection = Some(connection);
+        self.identity = handshake.identity;
+        self.next_poll = Instant::now();
+        self.emit(WorkerEvent::Connected { description, handshake });
+    }
+
+    /// Drops the connection on operator request, no reconnect afterwards.
+    fn disconnect(&mut self) {
+        self.target = None;
+        let was_active =
+            self.connection.take().is_some() || self.reconnect.take().is_some();
+
+        if was_active {
+            self.emit(WorkerEvent::Disconnected);
+        }
+    }
+
+    /// Drops a broken connection and starts trying to get it back.
+    fn connection_lost(&mut self) {
+        self.connection = None;
+
+        let Some(target) = &self.target else {
+            self.emit(WorkerEvent::Disconnected);
+            return;
+        };
+
+        match &mut self.reconnect {
+            // Reopened fine but died again before the first good poll
+            Some(backoff) => {
+                backoff.failed();
+                let event = WorkerEvent::Reconnecting {
+                    target: target.config.target(),
+                    attempt: backoff.attempt() + 1,
+                };
+                self.emit(event);
+            }
+            None => {
+                self.reconnect = Some(Backoff::new());
+                self.try_reconnect();
+            }
+        }
+    }
+
+    fn try_reconnect(&mut self) {
+        let (Some(target), Some(backoff)) =
+            (&mut self.target, &mut self.reconnect)
+        else {
+            return;
+        };
+
+        // USB adapters may come back under a different name
+        if let (Some(usb), TransportConfig::Serial { port_name }) =
+            (&target.usb, &mut target.config)
+            && let Some(current_name) = usb.find_port()
+        {
+            *port_name = current_name;
+        }
+
+        let opened = target
+            .config
+            .open(
+                target.protocol,
+                &self.device,
+                &self.sensors,
+            )
+            .and_then(|(connection, handshake)| {
+                let found = handshake
+                    .identity
+                    .map(|identity| identity.serial_number);
+                if let (Some(expected), Some(found)) =
+                    (target.serial_number, found)
+                    && expected != found
+                {
+                    anyhow::bail!(
+                        "Found board #{found:08} instead of #{expected:08}"
+                    );
+                }
+
+                Ok((connection, handshake))
+            });
+
+        match opened {
+            Ok((connection, handshake)) => {
+                println!(
+                    "Reconnected to {} after {} attempt(s)",
+                    connection.describe(),
+                    backoff.attempt() + 1
+                );
+                self.attach(connection, handshake);
+            }
+
+            Err(err) => {
+                let delay = backoff.failed();
+                eprintln!(
+                    "Reconnect attempt {} failed, next in {:?}: {}",
+                    backoff.attempt(),
+                    delay,
+                    err
+                );
+
+                let event = WorkerEvent::Reconnecting {
+                    target: target.config.target(),
+                    attempt: backoff.attempt() + 1,
+                };
+                self.emit(event);
+            }
+        }
+    }
+
+    fn poll(&mut self) {
+        let Some(connection) = &mut self.connection else {
+            return;
+        };
+
+        self.next_poll = Instant::now() + self.device.poll_interval();
+
+        match connection.poll(&self.sensors) {
+            Ok(res) => {
+                // Only a successful poll proves the reconnect worked
+                self.reconnect = None;
+                self.emit(WorkerEvent::Polled(res));
+            }
+            Err(err) => {
+                eprintln!("{:?}", err);
+                self.emit(WorkerEvent::PollFailed(err.to_string()));
+                self.connection_lost();
+            }
+        }
+    }
+
+    fn emit(&self, event: WorkerEvent) {
+        // The receiving side only goes away on shutdown
+        let _ = self.events.send(event);
+    }
+}
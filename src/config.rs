@@ -0,0 +1,588 @@
+use crate::alarm::AlarmTimings;
+use crate::auth::lockout::LockoutPolicy;
+use crate::auth::users::Role;
+use anyhow::{Context, bail};
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often [`ConfigWatcher`] looks at the file.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything tunable without a rebuild, read from `data/config.toml`.
+/// Missing keys take their defaults, unknown keys are an error so typos do
+/// not go unnoticed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub device: DeviceConfig,
+    pub alarm: AlarmConfig,
+    pub sessions: SessionConfig,
+    pub lockout: LockoutConfig,
+    pub daemon: DaemonConfig,
+    pub http: HttpConfig,
+    pub mqtt: MqttConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub poll_interval_ms: u64,
+    pub baud_rate: u32,
+    /// Read and connect timeout of serial and TCP links.
+    pub timeout_ms: u64,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 250,
+            baud_rate: 9600,
+            timeout_ms: 3000,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlarmConfig {
+    pub exit_delay_secs: u32,
+    pub entry_delay_secs: u32,
+    pub siren_secs: u32,
+    /// Time on battery before the power-loss alarm.
+    pub power_loss_grace_secs: u32,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        let timings = AlarmTimings::default();
+        let secs = |delta: TimeDelta| delta.num_seconds() as u32;
+
+        Self {
+            exit_delay_secs: secs(timings.exit_delay),
+            entry_delay_secs: secs(timings.entry_delay),
+            siren_secs: secs(timings.siren_duration),
+            power_loss_grace_secs: secs(timings.power_loss_grace),
+        }
+    }
+}
+
+impl AlarmConfig {
+    pub fn timings(&self) -> AlarmTimings {
+        let secs = |secs: u32| TimeDelta::seconds(secs as i64);
+
+        AlarmTimings {
+            exit_delay: secs(self.exit_delay_secs),
+            entry_delay: secs(self.entry_delay_secs),
+            siren_duration: secs(self.siren_secs),
+            power_loss_grace: secs(self.power_loss_grace_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Session length of new users.
+    pub default_minutes: u32,
+    /// Longest session length an admin may give a user.
+    pub max_minutes: u32,
+    /// Without input for this long the session locks.
+    pub idle_lock_secs: u32,
+    /// How long before locking or expiring the operator is warned.
+    pub expiry_warning_secs: u32,
+    /// Time to enter the second factor after a correct password.
+    pub second_factor_secs: u32,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            default_minutes: 5,
+            max_minutes: 24 * 60,
+            idle_lock_secs: 120,
+            expiry_warning_secs: 30,
+            second_factor_secs: 120,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn idle_lock(&self) -> TimeDelta {
+        TimeDelta::seconds(self.idle_lock_secs as i64)
+    }
+
+    pub fn expiry_warning(&self) -> TimeDelta {
+        TimeDelta::seconds(self.expiry_warning_secs as i64)
+    }
+
+    pub fn second_factor(&self) -> TimeDelta {
+        TimeDelta::seconds(self.second_factor_secs as i64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    pub free_attempts: u32,
+    pub global_free_attempts: u32,
+    pub base_lockout_secs: u32,
+    pub max_lockout_secs: u32,
+    /// 0 never raises the alarm.
+    pub alarm_after: u32,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        let policy = LockoutPolicy::default();
+
+        Self {
+            free_attempts: policy.free_attempts,
+            global_free_attempts: policy.global_free_attempts,
+            base_lockout_secs: policy.base_lockout.num_seconds() as u32,
+            max_lockout_secs: policy.max_lockout.num_seconds() as u32,
+            alarm_after: policy.alarm_after,
+        }
+    }
+}
+
+impl LockoutConfig {
+    pub fn policy(&self) -> LockoutPolicy {
+        LockoutPolicy {
+            free_attempts: self.free_attempts,
+            global_free_attempts: self.global_free_attempts,
+            base_lockout: TimeDelta::seconds(self.base_lockout_secs as i64),
+            max_lockout: TimeDelta::seconds(self.max_lockout_secs as i64),
+            alarm_after: self.alarm_after,
+        }
+    }
+}
+
+/// Only used by `control --headless`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub log_format: LogFormat,
+    /// How often worker results are collected and the alarms ticked.
+    pub tick_ms: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            log_format: LogFormat::Plain,
+            tick_ms: 100,
+        }
+    }
+}
+
+impl DaemonConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+}
+
+/// The JSON API for other systems, in the window and headless.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address to serve on, e.g. `127.0.0.1:8080`. `None` serves nothing.
+    pub listen: Option<String>,
+}
+
+/// Bridge to an MQTT broker, in the window and headless.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// `host:port` of the broker. `None` bridges nothing.
+    pub broker: Option<String>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// First level of every topic.
+    pub topic_prefix: String,
+    /// Announces the entities to Home Assistant.
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    /// What commands from the broker may do. `None` does not listen for
+    /// any.
+    pub command_role: Option<Role>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: None,
+            client_id: "control".to_owned(),
+            username: None,
+            password: None,
+            topic_prefix: "control".to_owned(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_owned(),
+            command_role: None,
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Host and port of [`Self::broker`].
+    pub fn broker_address(&self) -> Option<(&str, u16)> {
+        let (host, port) = self
+            .broker
+            .as_deref()?
+            .rsplit_once(':')?;
+        let port = port.parse().ok()?;
+
+        (!host.is_empty()).then_some((host, port))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Timestamp and level in front of every line.
+    Plain,
+    /// `<priority>` prefixes journald turns into log levels, no timestamp.
+    Journald,
+}
+
+impl Config {
+    /// Defaults when the file does not exist, anything else wrong with it is
+    /// an error.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config: Self = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).with_context(|| {
+                format!("Failed to parse {}", path.display())
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Self::default()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        config
+            .validate()
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.validate()?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let temp = path.with_extension("toml.tmp");
+        std::fs::write(&temp, toml::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Lists every value out of range, not just the first.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        let mut check = |key: &str, value: u64, range: RangeInclusive<u64>| {
+            if !range.contains(&value) {
+                errors.push(format!(
+                    "{key} must be between {} and {}, got {value}",
+                    range.start(),
+                    range.end()
+                ));
+            }
+        };
+
+        let device = &self.device;
+        check(
+            "device.poll_interval_ms",
+            device.poll_interval_ms,
+            50..=60_000,
+        );
+        check(
+            "device.baud_rate",
+            device.baud_rate as u64,
+            300..=4_000_000,
+        );
+        check(
+            "device.timeout_ms",
+            device.timeout_ms,
+            100..=60_000,
+        );
+
+        let alarm = &self.alarm;
+        check(
+            "alarm.exit_delay_secs",
+            alarm.exit_delay_secs as u64,
+            0..=600,
+        );
+        check(
+            "alarm.entry_delay_secs",
+            alarm.entry_delay_secs as u64,
+            0..=600,
+        );
+        check(
+            "alarm.siren_secs",
+            alarm.siren_secs as u64,
+            10..=3600,
+        );
+        check(
+            "alarm.power_loss_grace_secs",
+            alarm.power_loss_grace_secs as u64,
+            0..=600,
+        );
+
+        let sessions = &self.sessions;
+        check(
+            "sessions.max_minutes",
+            sessions.max_minutes as u64,
+            1..=7 * 24 * 60,
+        );
+        check(
+            "sessions.default_minutes",
+            sessions.default_minutes as u64,
+            1..=sessions.max_minutes as u64,
+        );
+        check(
+            "sessions.idle_lock_secs",
+            sessions.idle_lock_secs as u64,
+            10..=86_400,
+        );
+        check(
+            "sessions.expiry_warning_secs",
+            sessions.expiry_warning_secs as u64,
+            0..=sessions
+                .idle_lock_secs
+                .saturating_sub(1) as u64,
+        );
+        check(
+            "sessions.second_factor_secs",
+            sessions.second_factor_secs as u64,
+            30..=600,
+        );
+
+        let lockout = &self.lockout;
+        check(
+            "lockout.free_attempts",
+            lockout.free_attempts as u64,
+            1..=100,
+        );
+        check(
+            "lockout.global_free_attempts",
+            lockout.global_free_attempts as u64,
+            1..=1000,
+        );
+        check(
+            "lockout.base_lockout_secs",
+            lockout.base_lockout_secs as u64,
+            1..=3600,
+        );
+        check(
+            "lockout.max_lockout_secs",
+            lockout.max_lockout_secs as u64,
+            lockout.base_lockout_secs as u64..=7 * 24 * 3600,
+        );
+        check(
+            "lockout.alarm_after",
+            lockout.alarm_after as u64,
+            0..=100,
+        );
+
+        check(
+            "daemon.tick_ms",
+            self.daemon.tick_ms,
+            10..=10_000,
+        );
+
+        if let Some(listen) = &self.http.listen
+            && listen.parse::<SocketAddr>().is_err()
+        {
+            errors.push(format!(
+                "http.listen must be an address like 127.0.0.1:8080, got '{listen}'"
+            ));
+        }
+
+        let mqtt = &self.mqtt;
+        if let Some(broker) = &mqtt.broker
+            && mqtt.broker_address().is_none()
+        {
+            errors.push(format!(
+                "mqtt.broker must be host:port like localhost:1883, got '{broker}'"
+            ));
+        }
+        if mqtt.client_id.is_empty() {
+            errors.push("mqtt.client_id must not be empty".to_owned());
+        }
+        for (key, topic) in [
+            ("mqtt.topic_prefix", &mqtt.topic_prefix),
+            (
+                "mqtt.discovery_prefix",
+                &mqtt.discovery_prefix,
+            ),
+        ] {
+            if topic.is_empty()
+                || topic.starts_with('/')
+                || topic.ends_with('/')
+                || topic.contains(['+', '#'])
+            {
+                errors.push(format!(
+                    "{key} must be a topic without wildcards or outer slashes, got '{topic}'"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            bail!("{}", errors.join("\n"))
+        }
+    }
+}
+
+/// Notices edits of the config file, so it can be reloaded without a
+/// restart.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    next_check: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            modified: modified(&path),
+            path,
+            next_check: Instant::now() + WATCH_INTERVAL,
+        }
+    }
+
+    /// Whether the file changed since the last call. Cheap enough to call
+    /// every frame, the file is only looked at every few seconds.
+    pub fn changed(&mut self) -> bool {
+        if Instant::now() < self.next_check {
+            return false;
+        }
+        self.next_check = Instant::now() + WATCH_INTERVAL;
+
+        let modified = modified(&self.path);
+        let changed = modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn every_value_out_of_range_is_reported() {
+        let mut config = Config::default();
+        config.device.poll_interval_ms = 10;
+        config.alarm.siren_secs = 5;
+        config.sessions.default_minutes = config.sessions.max_minutes + 1;
+        config.sessions.expiry_warning_secs = config.sessions.idle_lock_secs;
+        config.lockout.max_lockout_secs = 0;
+        config.http.listen = Some("localhost".to_owned());
+        config.mqtt.topic_prefix = "control/#".to_owned();
+
+        let message = config
+            .validate()
+            .unwrap_err()
+            .to_string();
+        for key in [
+            "device.poll_interval_ms",
+            "alarm.siren_secs",
+            "sessions.default_minutes",
+            "sessions.expiry_warning_secs",
+            "lockout.max_lockout_secs",
+            "http.listen",
+            "mqtt.topic_prefix",
+        ] {
+            assert!(
+                message.contains(key),
+                "{key} missing from {message}"
+            );
+        }
+        assert_eq!(message.lines().count(), 7);
+    }
+
+    #[test]
+    fn edits_are_noticed_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        // Explicit times, some file systems only keep whole seconds
+        let write = |contents: &str, secs: u64| {
+            std::fs::write(&path, contents).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                )
+                .unwrap();
+        };
+        let mut watcher = ConfigWatcher::new(&path);
+        let mut changed = || {
+            watcher.next_check = Instant::now();
+            watcher.changed()
+        };
+
+        write("[device]\npoll_interval_ms = 500\n", 1);
+        assert!(changed());
+        assert!(!changed());
+        assert_eq!(
+            Config::load(&path)
+                .unwrap()
+                .device
+                .poll_interval_ms,
+            500
+        );
+
+        write("[device]\npoll_interval_ms = 5\n", 2);
+        assert!(changed());
+        assert!(Config::load(&path).is_err());
+
+        write("[device]\npol_interval_ms = 500\n", 3);
+        assert!(changed());
+        assert!(Config::load(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(changed());
+        assert_eq!(
+            Config::load(&path).unwrap(),
+            Config::default()
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! `control --headless`: devices, alarms, battery watch, event log, the
+//! HTTP API, the MQTT bridge and notifications without a window, to run as
+//! a service.
+//! Everything worth knowing goes to stdout.
+
+use crate::DATA_DIR;
+use crate::auth::dual_control::DualControlPolicy;
+use crate::auth::duress::DuressPolicy;
+use crate::auth::lockout::LoginGuard;
+use crate::auth::users::{Role, UserStore};
+use crate::config::{Config, ConfigWatcher, LogFormat};
+use crate::devices::{self, Device};
+use crate::events::log::EventLog;
+use crate::events::{Event, EventKind};
+use crate::http::{self, Control, HttpServer};
+use crate::mqtt::{self, MqttBridge};
+use crate::notice::{Level, Notice};
+use crate::notify::{NotificationPolicy, Notifier};
+use crate::power::PowerMonitor;
+use crate::sensors::SensorMap;
+use anyhow::{Context, anyhow, bail};
+use chrono::{Local, Utc};
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: control --headless [--config FILE]";
+const ADD_USER_USAGE: &str = "Usage: control --add-user NAME ROLE [--config FILE], the password is read from stdin";
+/// Time the workers get to close their links and report it on shutdown.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// `control --add-user NAME ROLE`: a user without the window, e.g. the
+/// first admin of a headless install.
+pub fn add_user(args: &[String]) -> anyhow::Result<()> {
+    let mut config_path = Path::new(DATA_DIR).join("config.toml");
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config_path =
+                    PathBuf::from(args.next().context(ADD_USER_USAGE)?);
+            }
+            other => positional.push(other),
+        }
+    }
+    let [username, role] = positional[..] else {
+        bail!(ADD_USER_USAGE);
+    };
+    let role = Role::deserialize(
+        role.to_lowercase()
+            .as_str()
+            .into_deserializer(),
+    )
+    .map_err(|err: serde::de::value::Error| {
+        anyhow!("{err}\n{ADD_USER_USAGE}")
+    })?;
+
+    let config = Config::load(&config_path)?;
+    let mut users = UserStore::open(Path::new(DATA_DIR).join("users.json"))?;
+    users.set_max_session_minutes(config.sessions.max_minutes);
+
+    eprint!("Password for {username}: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+
+    users.add(
+        username,
+        password.trim_end_matches(['\r', '\n']),
+        role,
+        config.sessions.default_minutes,
+    )?;
+    println!("Added {username} ({role})");
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let mut config_path = Path::new(DATA_DIR).join("config.toml");
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config_path = PathBuf::from(args.next().context(USAGE)?);
+            }
+            other => bail!("Unknown argument '{other}'\n{USAGE}"),
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, stop.clone())?;
+    }
+
+    let mut config = Config::load(&config_path)?;
+    let mut config_watcher = ConfigWatcher::new(&config_path);
+    let sensors = Arc::new(SensorMap::load(
+        &Path::new(DATA_DIR).join("sensors.toml"),
+    )?);
+    let entries = devices::load(&Path::new(DATA_DIR).join("devices.json"))?;
+    let mut events = EventLog::open(DATA_DIR)?;
+    let recorded = events.subscribe();
+    let mut power = PowerMonitor::new()?;
+    let mut users = UserStore::open(Path::new(DATA_DIR).join("users.json"))?;
+    users.set_max_session_minutes(config.sessions.max_minutes);
+    let mut login_guard = LoginGuard::open(
+        Path::new(DATA_DIR).join("lockout.json"),
+        config.lockout.policy(),
+    )?;
+    let dual_control = DualControlPolicy::load(
+        &Path::new(DATA_DIR).join("dual_control.json"),
+    )?;
+    let mut notifications = NotificationPolicy::load(
+        &Path::new(DATA_DIR).join("notifications.toml"),
+    )?;
+    notifications.add_duress_hooks(DuressPolicy::load(
+        &Path::new(DATA_DIR).join("duress.json"),
+    )?);
+    let mut notifier = Notifier::new(&notifications, &mut events);
+    let mut duress_alerts = Vec::new();
+    let mut server: Option<HttpServer> = None;
+    http::configure(&mut server, &config.http)?;
+    let mut bridge: Option<MqttBridge> = None;
+    mqtt::configure(&mut bridge, &config.mqtt)?;
+    let log = |config: &Config, notice: Notice| {
+        print_line(
+            config.daemon.log_format,
+            notice.level,
+            &notice.message,
+        );
+    };
+
+    let mut devices = Vec::new();
+    for entry in entries {
+        if entry.endpoint.is_none() {
+            log(
+                &config,
+                Notice::warning(format!(
+                    "{} has no endpoint in devices.json, it stays disconnected",
+                    entry.name
+                )),
+            );
+        }
+
+        devices.push(Device::start(entry, &config, &sensors));
+    }
+    log(
+        &config,
+        Notice::info(format!(
+            "Started with {} device(s)",
+            devices.len()
+        )),
+    );
+    if let Some(server) = &server {
+        log(
+            &config,
+            Notice::info(format!(
+                "Serving the HTTP API on {}",
+                server.address()
+            )),
+        );
+    }
+    if let Some(broker) = &config.mqtt.broker {
+        log(
+            &config,
+            Notice::info(format!(
+                "Bridging to the MQTT broker at {broker}"
+            )),
+        );
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        if config_watcher.changed() {
+            match Config::load(&config_path) {
+                Ok(reloaded) => {
+                    for device in &mut devices {
+                        device.configure(&reloaded);
+                    }
+                    login_guard.set_policy(reloaded.lockout.policy());
+                    users
+                        .set_max_session_minutes(reloaded.sessions.max_minutes);
+                    if let Err(err) =
+                        http::configure(&mut server, &reloaded.http)
+                    {
+                        log(
+                            &reloaded,
+                            Notice::error(format!("{err:#}")),
+                        );
+                    }
+                    if let Err(err) =
+                        mqtt::configure(&mut bridge, &reloaded.mqtt)
+                    {
+                        log(
+                            &reloaded,
+                            Notice::error(format!("{err:#}")),
+                        );
+                    }
+                    config = reloaded;
+                    log(
+                        &config,
+                        Notice::info(format!(
+                            "Reloaded {}",
+                            config_path.display()
+                        )),
+                    );
+                }
+                Err(err) => log(
+                    &config,
+                    Notice::error(format!(
+                        "{err:#}, keeping the previous settings"
+                    )),
+                ),
+            }
+        }
+
+        let now = Utc::now();
+        let mut notices: Vec<Notice> = power
+            .update(&mut devices, &mut events, now)
+            .into_iter()
+            .collect();
+        for device in &mut devices {
+            notices.extend(device.update(&sensors, &mut events, now));
+        }
+
+        if let Some(server) = &mut server {
+            let mut control = Control {
+                devices: &mut devices,
+                sensors: &sensors,
+                events: &mut events,
+                users: &mut users,
+                login_guard: &mut login_guard,
+                dual_control: &dual_control,
+                on_battery: power.on_battery(),
+                duress_alerts: &mut duress_alerts,
+            };
+            notices.extend(server.update(&mut control, now));
+        }
+        if let Some(bridge) = &mut bridge {
+            // Nothing of it is in the event log, so the info ones count too
+            for notice in bridge.update(
+                &devices,
+                &sensors,
+                &mut events,
+                &dual_control,
+                power.on_battery(),
+            ) {
+                log(&config, notice);
+            }
+        }
+
+        // Hidden from the journal, see `print_events`
+        for alert in duress_alerts.drain(..) {
+            events.record(EventKind::Duress {
+                user: alert.user,
+                action: alert.action,
+            });
+        }
+        // Deliveries are not events, each one is worth a line
+        for notice in notifier.update(&sensors) {
+            log(&config, notice);
+        }
+
+        print_events(&config, &recorded);
+        // Info notices only repeat an event printed above
+        for notice in notices {
+            if notice.level > Level::Info {
+                log(&config, notice);
+            }
+        }
+
+        std::thread::sleep(config.daemon.tick());
+    }
+
+    log(&config, Notice::info("Shutting down"));
+    drop(server);
+    drop(bridge);
+    for device in &mut devices {
+        device.api.close_connection();
+    }
+    std::thread::sleep(SHUTDOWN_GRACE);
+
+    let now = Utc::now();
+    for device in &mut devices {
+        device.update(&sensors, &mut events, now);
+    }
+    print_events(&config, &recorded);
+
+    Ok(())
+}
+
+fn print_events(config: &Config, recorded: &Receiver<Event>) {
+    // Someone with access to the journal may be the one forcing the
+    // operator
+    for event in recorded
+        .try_iter()
+        .filter(|event| !event.kind.is_hidden())
+    {
+        let message = match &event.device {
+            Some(device) => format!("{device}: {}", event.kind),
+            None => event.kind.to_string(),
+        };
+        print_line(
+            config.daemon.log_format,
+            Level::Info,
+            &message,
+        );
+    }
+}
+
+fn print_line(format: LogFormat, level: Level, message: &str) {
+    match format {
+        LogFormat::Plain => println!(
+            "{} {level:<5} {message}",
+            Local::now().format("%Y-%m-%d %H:%M:%S")
+        ),
+        LogFormat::Journald => {
+            // sd-daemon(3) priorities
+            let priority = match level {
+                Level::Info => 6,
+                Level::Warning => 4,
+                Level::Error => 3,
+            };
+            println!("<{priority}>{message}");
+        }
+    }
+}
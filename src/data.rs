@@ -1,35 +1,178 @@
-use crate::authorized::api::Api;
-use crate::authorized::serial_connection::PollResult;
-use battery::Manager;
+use crate::auth::dual_control::{DualControlPolicy, PendingApproval};
+use crate::auth::duress::DuressAlert;
+use crate::auth::lockout::LoginGuard;
+use crate::auth::totp;
+use crate::auth::users::{Role, UserStore};
+use crate::authorized::api::TransportKind;
+use crate::authorized::protocol::ProtocolVersion;
+use crate::config::Config;
+use crate::devices::{Device, Endpoint};
+use crate::events::Event;
+use crate::events::log::EventLog;
+use crate::events::query::EventQuery;
+use crate::http::HttpServer;
+use crate::mqtt::MqttBridge;
+use crate::power::PowerMonitor;
+use crate::sensors::SensorMap;
 use chrono::{DateTime, TimeDelta, Utc};
-
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
-pub enum AuthLevel {
-    #[default]
-    View,
-    Modify,
-}
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct AppState {
     pub input_username: String,
     pub input_password: String,
-    pub api: Api,
-    pub last_poll_result: PollResult,
-    pub battery_manager: Manager,
+    pub input_password_confirm: String,
+    pub devices: Vec<Device>,
+    /// Device the detail view shows and commands go to.
+    pub selected_device: Option<usize>,
+    pub devices_path: PathBuf,
+    pub device_form: DeviceForm,
+    pub power: PowerMonitor,
     pub current_session: Option<SessionData>,
-    pub alarm_end_time: Option<DateTime<Utc>>,
+    pub events: EventLog,
+    pub history: HistoryView,
+    pub users: UserStore,
+    pub login_guard: LoginGuard,
+    pub dual_control: DualControlPolicy,
+    pub pending_approval: Option<PendingApproval>,
+    pub approver_username: String,
+    pub approver_password: String,
+    /// Only asked of approvers with a second factor.
+    pub approver_code: String,
+    pub user_form: UserForm,
+    pub pending_login: Option<PendingLogin>,
+    pub input_code: String,
+    pub totp_setup: Option<TotpSetup>,
+    /// Filled by the UI, logged and sent out by the main loop.
+    pub duress_alerts: Vec<DuressAlert>,
+    pub config: Config,
+    pub config_path: PathBuf,
+    /// What the settings panel edits until it is applied.
+    pub config_draft: Config,
+    pub sensors: Arc<SensorMap>,
+    /// `None` unless `http.listen` is set.
+    pub http: Option<HttpServer>,
+    /// `None` unless `mqtt.broker` is set.
+    pub mqtt: Option<MqttBridge>,
+}
+
+/// Password was right, waiting for the second factor.
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub username: String,
+    pub expires_at: DateTime<Utc>,
+    pub duress: bool,
 }
 
-pub enum ValueRef<'a, T> {
-    Editable(&'a mut T),
-    Readonly(&'a T),
+/// The enrollment window: a fresh secret until the user confirms a code
+/// from their app, then the recovery codes to write down.
+#[derive(Debug, Clone)]
+pub struct TotpSetup {
+    pub secret: Vec<u8>,
+    pub recovery_codes: Vec<String>,
+    pub code: String,
+    pub enrolled: bool,
+}
+
+impl TotpSetup {
+    pub fn new() -> Self {
+        Self {
+            secret: totp::generate_secret(),
+            recovery_codes: totp::generate_recovery_codes(),
+            code: String::new(),
+            enrolled: false,
+        }
+    }
+}
+
+/// Inputs of the admin's "add user" form.
+#[derive(Debug, Clone)]
+pub struct UserForm {
+    pub username: String,
+    pub password: String,
+    pub role: Role,
+    pub session_minutes: u32,
+}
+
+impl UserForm {
+    pub fn new(session_minutes: u32) -> Self {
+        Self {
+            username: String::new(),
+            password: String::new(),
+            role: Role::Guard,
+            session_minutes,
+        }
+    }
+}
+
+/// Inputs of the admin's "add device" form.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceForm {
+    pub name: String,
+    /// `None` adds a device the operator connects by hand.
+    pub transport: Option<TransportKind>,
+    /// Port name or `host:port`, depending on the transport.
+    pub address: String,
+    pub protocol: ProtocolVersion,
+}
+
+impl DeviceForm {
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        let address = self.address.trim().to_owned();
+
+        Some(match self.transport? {
+            TransportKind::Serial => Endpoint::Serial(address),
+            TransportKind::Tcp => Endpoint::Tcp(address),
+            TransportKind::Loopback => Endpoint::Simulator,
+        })
+    }
+}
+
+/// Filter inputs and the loaded rows of the event history panel.
+#[derive(Debug, Default)]
+pub struct HistoryView {
+    pub query: EventQuery,
+    pub user: String,
+    pub text: String,
+    /// Local time, `YYYY-MM-DD HH:MM`.
+    pub since: String,
+    pub until: String,
+    pub rows: Vec<Event>,
+    /// Events recorded since the panel was first shown, matching ones are
+    /// appended to `rows` instead of querying the files again.
+    pub recorded: Option<Receiver<Event>>,
+    /// Answer of the query running in the background.
+    pub loading: Option<Receiver<anyhow::Result<Vec<Event>>>>,
+    /// Recorded while `loading`, added once the answer is in.
+    pub tail: Vec<Event>,
+    /// When to query the files again, a moment after the last filter edit.
+    pub reload_at: Option<Instant>,
+    pub selected: Option<u64>,
+    pub scroll_to: Option<u64>,
+    pub error: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct SessionData {
     pub username: String,
-    pub auth_level: AuthLevel,
+    pub role: Role,
     pub begin_timestamp: DateTime<Utc>,
     pub timeout_time: TimeDelta,
+    /// Logged in with the duress password, disarming signals duress too.
+    pub duress: bool,
+    /// Last keyboard or mouse input, for the idle lock.
+    pub last_activity: DateTime<Utc>,
+    /// Controls are hidden until the password is entered again.
+    pub locked: bool,
+    /// Deadline the expiry warning was already shown for.
+    pub warned_for: Option<DateTime<Utc>>,
+}
+
+impl SessionData {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.begin_timestamp + self.timeout_time
+    }
 }
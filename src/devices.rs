@@ -0,0 +1,284 @@
+use crate::alarm::{AlarmCause, AlarmState, AlarmStateMachine, Transition};
+use crate::authorized::api::{Api, ApiUpdate};
+use crate::authorized::protocol::ProtocolVersion;
+use crate::authorized::serial_connection::PollResult;
+use crate::authorized::transport::TransportConfig;
+use crate::config::Config;
+use crate::events::EventKind;
+use crate::events::log::EventLog;
+use crate::notice::Notice;
+use crate::sensors::{SensorMap, Severity};
+use crate::simulator::device::SimulatorHandle;
+use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One line of `data/devices.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub name: String,
+    /// Connected to at start-up, `None` waits for the operator.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub endpoint: Option<Endpoint>,
+    #[serde(default)]
+    pub protocol: ProtocolVersion,
+}
+
+/// Where a device is reached, the serializable part of a
+/// [`TransportConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Serial(String),
+    Tcp(String),
+    Simulator,
+}
+
+impl Endpoint {
+    pub fn transport(&self, sensors: &Arc<SensorMap>) -> TransportConfig {
+        match self {
+            Endpoint::Serial(port_name) => {
+                TransportConfig::Serial { port_name: port_name.clone() }
+            }
+            Endpoint::Tcp(address) => {
+                TransportConfig::Tcp { address: address.clone() }
+            }
+            Endpoint::Simulator => {
+                TransportConfig::Loopback(SimulatorHandle::new(sensors.clone()))
+            }
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Serial(port_name) => write!(f, "serial {port_name}"),
+            Endpoint::Tcp(address) => write!(f, "tcp {address}"),
+            Endpoint::Simulator => write!(f, "simulator"),
+        }
+    }
+}
+
+/// A controller with its own worker, poll results and alarm.
+#[derive(Debug)]
+pub struct Device {
+    pub name: String,
+    pub endpoint: Option<Endpoint>,
+    pub api: Api,
+    pub last_poll_result: PollResult,
+    pub alarm: AlarmStateMachine,
+}
+
+impl Device {
+    /// Starts the worker and connects if the entry says where to.
+    pub fn start(
+        entry: DeviceEntry,
+        config: &Config,
+        sensors: &Arc<SensorMap>,
+    ) -> Self {
+        let mut api = Api::new(config.device.clone(), sensors.clone());
+        api.set_protocol(entry.protocol);
+        if let Some(endpoint) = &entry.endpoint {
+            api.connect(endpoint.transport(sensors));
+        }
+
+        Self {
+            name: entry.name,
+            endpoint: entry.endpoint,
+            api,
+            last_poll_result: PollResult::default(),
+            // The panel has always been live from start-up, keep it that way
+            alarm: AlarmStateMachine::new(
+                AlarmState::Armed,
+                config.alarm.timings(),
+            ),
+        }
+    }
+
+    /// Drains the worker: logs sensor changes, feeds the alarm and moves
+    /// its timers on. Call it regularly, the window does once per frame.
+    pub fn update(
+        &mut self,
+        sensors: &SensorMap,
+        events: &mut EventLog,
+        now: DateTime<Utc>,
+    ) -> Vec<Notice> {
+        let mut notices = Vec::new();
+
+        for update in self.api.update() {
+            match update {
+                ApiUpdate::Polled(res) => {
+                    for (sensor, active) in
+                        res.changes_since(&self.last_poll_result)
+                    {
+                        if active && let Some(def) = sensors.find(&sensor) {
+                            let message =
+                                format!("{}: {}", self.name, def.name);
+                            match def.severity {
+                                Severity::Info => {}
+                                Severity::Warning => {
+                                    notices.push(Notice::warning(message));
+                                }
+                                Severity::Critical => {
+                                    notices.push(Notice::error(message));
+                                }
+                            }
+                        }
+
+                        events.record_device(
+                            &self.name,
+                            EventKind::Sensor { sensor, active },
+                        );
+                    }
+
+                    for cause in AlarmCause::active_in(&res, sensors) {
+                        if let Some(transition) = self.alarm.sensor(cause, now)
+                        {
+                            notices.push(record_transition(
+                                events,
+                                &self.name,
+                                &transition,
+                            ));
+                        }
+                    }
+
+                    self.last_poll_result = res;
+                }
+
+                ApiUpdate::Connection(change) => {
+                    events.record_device(
+                        &self.name,
+                        EventKind::Connection(change),
+                    );
+                }
+
+                ApiUpdate::Warning(warning) => {
+                    notices.push(Notice::warning(format!(
+                        "{}: {warning}",
+                        self.name
+                    )));
+                }
+
+                ApiUpdate::Error(err) => {
+                    notices.push(Notice::error(format!(
+                        "{}: {err}",
+                        self.name
+                    )));
+                }
+            }
+        }
+
+        if let Some(transition) = self.alarm.tick(now) {
+            notices.push(record_transition(
+                events,
+                &self.name,
+                &transition,
+            ));
+        }
+
+        notices
+    }
+
+    /// Takes over reloaded timings and link settings.
+    pub fn configure(&mut self, config: &Config) {
+        self.alarm
+            .set_timings(config.alarm.timings());
+        self.api
+            .configure(config.device.clone());
+    }
+
+    pub fn entry(&self) -> DeviceEntry {
+        DeviceEntry {
+            name: self.name.clone(),
+            endpoint: self.endpoint.clone(),
+            protocol: self.api.protocol(),
+        }
+    }
+}
+
+/// Logs an alarm transition of `device` and says how loudly to announce it.
+pub fn record_transition(
+    events: &mut EventLog,
+    device: &str,
+    transition: &Transition,
+) -> Notice {
+    events.record_device(device, EventKind::alarm(transition));
+
+    match &transition.to {
+        AlarmState::Triggered { cause, .. } => {
+            Notice::error(format!("{device}: ALARM: {cause}!"))
+        }
+        AlarmState::EntryDelay { cause, .. } => Notice::warning(format!(
+            "{device}: entry delay started by {cause}"
+        )),
+        to => Notice::info(format!(
+            "{device}: alarm {}",
+            to.name().to_lowercase()
+        )),
+    }
+}
+
+/// A single device called `Main` when the file does not exist, which is
+/// what the panel used to be.
+pub fn load(path: &Path) -> anyhow::Result<Vec<DeviceEntry>> {
+    let entries: Vec<DeviceEntry> = match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![DeviceEntry {
+                name: "Main".to_owned(),
+                endpoint: None,
+                protocol: ProtocolVersion::default(),
+            }]);
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+
+    for (index, entry) in entries.iter().enumerate() {
+        validate_name(&entry.name, &entries[..index])
+            .with_context(|| format!("Invalid {}", path.display()))?;
+    }
+
+    Ok(entries)
+}
+
+pub fn save(path: &Path, entries: &[DeviceEntry]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(
+        &temp,
+        serde_json::to_vec_pretty(entries)?,
+    )
+    .with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Names show up in the event log and must tell devices apart.
+pub fn validate_name(name: &str, others: &[DeviceEntry]) -> anyhow::Result<()> {
+    if name.trim().is_empty() || name.trim() != name {
+        bail!("Device name '{name}' must not be empty or padded");
+    }
+
+    if others
+        .iter()
+        .any(|other| other.name == name)
+    {
+        bail!("Device name '{name}' is used twice");
+    }
+
+    Ok(())
+}
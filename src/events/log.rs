@@ -0,0 +1,540 @@
+use crate::events::query::EventQuery;
+use crate::events::{Event, EventKind};
+use anyhow::Context;
+use chrono::Utc;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+const FILE_STEM: &str = "events";
+/// How much of a file [`last_id`] reads at a time, from the end.
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+/// When the current file gets rotated and how many old ones are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_file_bytes: u64,
+    pub max_files: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: 5 * 1024 * 1024,
+            max_files: 10,
+        }
+    }
+}
+
+/// Append-only JSON-lines store. `events.jsonl` is written to, full files
+/// move to `events.1.jsonl`, `events.2.jsonl`, ... with the oldest dropped.
+#[derive(Debug)]
+pub struct EventLog {
+    dir: PathBuf,
+    rotation: Rotation,
+    file: File,
+    file_size: u64,
+    next_id: u64,
+    subscribers: Vec<Sender<Event>>,
+}
+
+impl EventLog {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Self::open_with(dir, Rotation::default())
+    }
+
+    pub fn open_with(
+        dir: impl Into<PathBuf>,
+        rotation: Rotation,
+    ) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let (file, file_size) = open_current(&dir)?;
+        let mut log = Self {
+            dir,
+            rotation,
+            file,
+            file_size,
+            next_id: 1,
+            subscribers: Vec::new(),
+        };
+
+        // Ids only go up, the newest event tells the next one
+        let files = log.reader().files_oldest_first();
+        for path in files.iter().rev() {
+            if let Some(id) = last_id(path)? {
+                log.next_id = id + 1;
+                break;
+            }
+        }
+
+        Ok(log)
+    }
+
+    /// Appends an event stamped with the current time. Write failures are
+    /// reported on stderr, losing a log line must not take the panel down.
+    pub fn record(&mut self, kind: EventKind) -> Event {
+        self.push(None, kind)
+    }
+
+    /// [`EventLog::record`] for something that happened on one device.
+    pub fn record_device(&mut self, device: &str, kind: EventKind) -> Event {
+        self.push(Some(device.to_owned()), kind)
+    }
+
+    fn push(&mut self, device: Option<String>, kind: EventKind) -> Event {
+        let event = Event {
+            id: self.next_id,
+            timestamp: Utc::now(),
+            device,
+            kind,
+        };
+        self.next_id += 1;
+
+        if let Err(err) = self.append(&event) {
+            eprintln!(
+                "Failed to write event {}: {err:?}",
+                event.id
+            );
+        }
+
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+
+        event
+    }
+
+    /// Every event recorded from now on, hidden ones included. Dropping the
+    /// receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Queries the files without the log, e.g. from another thread.
+    pub fn reader(&self) -> EventReader {
+        EventReader {
+            dir: self.dir.clone(),
+            rotation: self.rotation,
+        }
+    }
+
+    fn append(&mut self, event: &Event) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        if self.file_size > 0
+            && self.file_size + line.len() as u64 > self.rotation.max_file_bytes
+        {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.file_size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let oldest = rotated_path(&self.dir, self.rotation.max_files);
+        if oldest.exists() {
+            std::fs::remove_file(&oldest)?;
+        }
+
+        for index in (1..self.rotation.max_files).rev() {
+            let from = rotated_path(&self.dir, index);
+            if from.exists() {
+                std::fs::rename(
+                    &from,
+                    rotated_path(&self.dir, index + 1),
+                )?;
+            }
+        }
+
+        if self.rotation.max_files > 0 {
+            std::fs::rename(
+                current_path(&self.dir),
+                rotated_path(&self.dir, 1),
+            )?;
+        } else {
+            std::fs::remove_file(current_path(&self.dir))?;
+        }
+
+        (self.file, self.file_size) = open_current(&self.dir)?;
+        Ok(())
+    }
+}
+
+/// Read side of an [`EventLog`], cheap to clone and send.
+#[derive(Debug, Clone)]
+pub struct EventReader {
+    dir: PathBuf,
+    rotation: Rotation,
+}
+
+impl EventReader {
+    /// Events matching `query`, oldest first.
+    pub fn query(&self, query: &EventQuery) -> anyhow::Result<Vec<Event>> {
+        let mut found = VecDeque::new();
+
+        for path in self.files_oldest_first() {
+            for event in read_file(&path)? {
+                if !query.matches(&event) {
+                    continue;
+                }
+
+                found.push_back(event);
+                // Only the newest `limit` events are wanted
+                if query
+                    .limit
+                    .is_some_and(|limit| found.len() > limit)
+                {
+                    found.pop_front();
+                }
+            }
+        }
+
+        Ok(found.into())
+    }
+
+    fn files_oldest_first(&self) -> Vec<PathBuf> {
+        (1..=self.rotation.max_files)
+            .rev()
+            .map(|index| rotated_path(&self.dir, index))
+            .chain([current_path(&self.dir)])
+            .filter(|path| path.exists())
+            .collect()
+    }
+}
+
+fn current_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{FILE_STEM}.jsonl"))
+}
+
+fn rotated_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("{FILE_STEM}.{index}.jsonl"))
+}
+
+fn open_current(dir: &Path) -> anyhow::Result<(File, u64)> {
+    let path = current_path(dir);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+
+    Ok((file, size))
+}
+
+/// Id of the last event in the file that parses, read from the end so
+/// startup does not go through the whole history.
+fn last_id(path: &Path) -> anyhow::Result<Option<u64>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to open {}", path.display()));
+        }
+    };
+
+    let mut end = file.metadata()?.len();
+    // Start of the line the previous chunk began in the middle of
+    let mut partial = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(TAIL_CHUNK);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut partial);
+        end = start;
+
+        let lines = if start == 0 {
+            &chunk[..]
+        } else {
+            let Some(newline) = chunk
+                .iter()
+                .position(|byte| *byte == b'\n')
+            else {
+                partial = chunk;
+                continue;
+            };
+            partial = chunk[..newline].to_vec();
+            &chunk[newline + 1..]
+        };
+
+        // A torn last line after a crash is skipped like in `read_file`
+        if let Some(id) = lines
+            .split(|byte| *byte == b'\n')
+            .rev()
+            .find_map(|line| serde_json::from_slice::<Event>(line).ok())
+            .map(|event| event.id)
+        {
+            return Ok(Some(id));
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        // Rotated away by the log while a reader was listing the files
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to open {}", path.display()));
+        }
+    };
+
+    let mut events = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // A torn last line after a crash should not hide everything else
+        match serde_json::from_str(&line) {
+            Ok(event) => events.push(event),
+            Err(err) => eprintln!(
+                "Skipping {}:{}: {err}",
+                path.display(),
+                number + 1
+            ),
+        }
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventType;
+    use crate::sensors::SensorId;
+
+    #[test]
+    fn reopening_continues_after_the_newest_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        log.record(EventKind::Login { user: "admin".to_owned() });
+        // Hidden from queries, but its id is taken all the same
+        let duress = log.record(EventKind::Duress {
+            user: "guard".to_owned(),
+            action: "login".to_owned(),
+        });
+        drop(log);
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let next = log.record(EventKind::Login { user: "admin".to_owned() });
+        assert_eq!(next.id, duress.id + 1);
+    }
+
+    #[test]
+    fn reopening_skips_torn_lines_and_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for _ in 0..3 {
+            log.record(login("admin"));
+        }
+        drop(log);
+
+        let mut current = OpenOptions::new()
+            .append(true)
+            .open(current_path(dir.path()))
+            .unwrap();
+        current
+            .write_all(b"{\"id\":99,\"timest\n")
+            .unwrap();
+        drop(current);
+        let mut log = EventLog::open(dir.path()).unwrap();
+        assert_eq!(log.record(login("admin")).id, 4);
+        drop(log);
+
+        // Rotated away, nothing written to the new file yet
+        std::fs::rename(
+            current_path(dir.path()),
+            rotated_path(dir.path(), 1),
+        )
+        .unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        assert_eq!(log.record(login("admin")).id, 5);
+    }
+
+    #[test]
+    fn the_last_id_is_found_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let long = "x".repeat(TAIL_CHUNK as usize);
+        log.record(login(&long));
+        log.record(login(&long));
+        drop(log);
+
+        assert_eq!(
+            last_id(&current_path(dir.path())).unwrap(),
+            Some(2)
+        );
+    }
+
+    /// Every event gets a file of its own.
+    fn one_per_file(max_files: usize) -> Rotation {
+        Rotation { max_file_bytes: 1, max_files }
+    }
+
+    fn login(user: &str) -> EventKind {
+        EventKind::Login { user: user.to_owned() }
+    }
+
+    fn ids(events: &[Event]) -> Vec<u64> {
+        events
+            .iter()
+            .map(|event| event.id)
+            .collect()
+    }
+
+    #[test]
+    fn full_files_rotate_and_the_oldest_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open_with(dir.path(), one_per_file(2)).unwrap();
+        for _ in 0..5 {
+            log.record(login("admin"));
+        }
+
+        assert!(current_path(dir.path()).exists());
+        assert!(rotated_path(dir.path(), 2).exists());
+        assert!(!rotated_path(dir.path(), 3).exists());
+        let all = log
+            .reader()
+            .query(&EventQuery::default())
+            .unwrap();
+        assert_eq!(ids(&all), [3, 4, 5]);
+    }
+
+    #[test]
+    fn queries_read_across_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log =
+            EventLog::open_with(dir.path(), one_per_file(10)).unwrap();
+        for _ in 0..4 {
+            log.record(login("admin"));
+        }
+
+        let all = log
+            .reader()
+            .query(&EventQuery::default())
+            .unwrap();
+        assert_eq!(ids(&all), [1, 2, 3, 4]);
+
+        let newest = log
+            .reader()
+            .query(&EventQuery { limit: Some(2), ..EventQuery::default() })
+            .unwrap();
+        assert_eq!(ids(&newest), [3, 4]);
+
+        drop(log);
+        let mut log =
+            EventLog::open_with(dir.path(), one_per_file(10)).unwrap();
+        assert_eq!(log.record(login("admin")).id, 5);
+    }
+
+    #[test]
+    fn queries_filter_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let door = SensorId::new("front_door");
+        log.record(login("admin"));
+        let before = Utc::now();
+        log.record_device(
+            "Lab",
+            EventKind::Sensor { sensor: door.clone(), active: true },
+        );
+        log.record_device(
+            "Shop",
+            EventKind::Sensor {
+                sensor: SensorId::new("window"),
+                active: false,
+            },
+        );
+        log.record(EventKind::Command {
+            user: "guard".to_owned(),
+            command: "alarm disarm".to_owned(),
+            approved_by: Some("admin".to_owned()),
+        });
+        log.record(EventKind::Duress {
+            user: "guard".to_owned(),
+            action: "login".to_owned(),
+        });
+
+        let find =
+            |query: EventQuery| ids(&log.reader().query(&query).unwrap());
+        assert_eq!(
+            find(EventQuery::default()),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(
+            find(EventQuery {
+                include_hidden: true,
+                ..EventQuery::default()
+            }),
+            [1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            find(EventQuery {
+                types: vec![EventType::Sensor],
+                ..EventQuery::default()
+            }),
+            [2, 3]
+        );
+        assert_eq!(
+            find(EventQuery {
+                sensors: vec![door],
+                ..EventQuery::default()
+            }),
+            [2]
+        );
+        assert_eq!(
+            find(EventQuery {
+                devices: vec!["Shop".to_owned()],
+                ..EventQuery::default()
+            }),
+            [3]
+        );
+        // The approver is involved as much as the one who ran it
+        assert_eq!(
+            find(EventQuery {
+                user: Some("admin".to_owned()),
+                ..EventQuery::default()
+            }),
+            [1, 4]
+        );
+        assert_eq!(
+            find(EventQuery {
+                text: Some("DISARM".to_owned()),
+                ..EventQuery::default()
+            }),
+            [4]
+        );
+        assert_eq!(
+            find(EventQuery {
+                since: Some(before),
+                until: Some(Utc::now()),
+                types: vec![EventType::Login],
+                ..EventQuery::default()
+            }),
+            Vec::<u64>::new()
+        );
+    }
+}
@@ -0,0 +1,222 @@
+pub mod log;
+pub mod query;
+
+use crate::alarm::Transition;
+use crate::auth::users::Role;
+use crate::authorized::api::ConnectionChange;
+use crate::sensors::SensorId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// One line of the event log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Controller the event is about, `None` for the console itself.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub device: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A sensor changed its value between two polls.
+    Sensor {
+        sensor: SensorId,
+        active: bool,
+    },
+    /// Something an operator did, e.g. `unlock_front_door` or `alarm arm`.
+    Command {
+        user: String,
+        command: String,
+        /// Second operator of a two-person command.
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        approved_by: Option<String>,
+    },
+    Login {
+        user: String,
+    },
+    /// A wrong password or an attempt while locked out.
+    LoginFailed {
+        user: String,
+        failures: u32,
+        locked_until: Option<DateTime<Utc>>,
+    },
+    /// Someone tried something their role does not allow.
+    AccessDenied {
+        user: String,
+        role: Role,
+        action: String,
+    },
+    Connection(ConnectionChange),
+    Battery {
+        on_battery: bool,
+    },
+    Alarm {
+        from: String,
+        to: String,
+        reason: String,
+    },
+    /// A duress password was used. Never shown in the UI, see
+    /// [`EventKind::is_hidden`].
+    Duress {
+        user: String,
+        action: String,
+    },
+}
+
+impl EventKind {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            EventKind::Sensor { .. } => EventType::Sensor,
+            EventKind::Command { .. } => EventType::Command,
+            EventKind::AccessDenied { .. } => EventType::AccessDenied,
+            EventKind::Login { .. } | EventKind::LoginFailed { .. } => {
+                EventType::Login
+            }
+            EventKind::Connection(_) => EventType::Connection,
+            EventKind::Battery { .. } => EventType::Battery,
+            EventKind::Alarm { .. } => EventType::Alarm,
+            EventKind::Duress { .. } => EventType::Duress,
+        }
+    }
+
+    /// Whoever is forced to work the console must not see it.
+    pub fn is_hidden(&self) -> bool {
+        matches!(self, EventKind::Duress { .. })
+    }
+
+    pub fn alarm(transition: &Transition) -> Self {
+        EventKind::Alarm {
+            from: transition.from.to_string(),
+            to: transition.to.to_string(),
+            reason: transition.reason.to_string(),
+        }
+    }
+
+    /// Whether `name` did or approved what the event is about.
+    pub fn involves(&self, name: &str) -> bool {
+        match self {
+            EventKind::Command { user, approved_by, .. } => {
+                user == name || approved_by.as_deref() == Some(name)
+            }
+            EventKind::AccessDenied { user, .. }
+            | EventKind::Login { user }
+            | EventKind::LoginFailed { user, .. }
+            | EventKind::Duress { user, .. } => user == name,
+            _ => false,
+        }
+    }
+
+    pub fn sensor(&self) -> Option<&SensorId> {
+        match self {
+            EventKind::Sensor { sensor, .. } => Some(sensor),
+            _ => None,
+        }
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventKind::Sensor { sensor, active: true } => {
+                write!(f, "{sensor} became active")
+            }
+            EventKind::Sensor { sensor, active: false } => {
+                write!(f, "{sensor} cleared")
+            }
+            EventKind::Command { user, command, approved_by: None } => {
+                write!(f, "{user}: {command}")
+            }
+            EventKind::Command {
+                user,
+                command,
+                approved_by: Some(approver),
+            } => write!(
+                f,
+                "{user}: {command} (approved by {approver})"
+            ),
+            EventKind::Login { user } => write!(f, "{user} logged in"),
+            EventKind::LoginFailed { user, failures, locked_until } => {
+                write!(
+                    f,
+                    "Failed login as {user} ({failures} in a row)"
+                )?;
+                match locked_until {
+                    Some(until) => write!(f, ", locked until {until}"),
+                    None => Ok(()),
+                }
+            }
+            EventKind::AccessDenied { user, role, action } => {
+                write!(
+                    f,
+                    "{user} ({role}) was denied: {action}"
+                )
+            }
+            EventKind::Connection(change) => write!(f, "{change}"),
+            EventKind::Battery { on_battery: true } => {
+                write!(f, "Running on battery")
+            }
+            EventKind::Battery { on_battery: false } => {
+                write!(f, "Running on charger")
+            }
+            EventKind::Alarm { from, to, reason } => {
+                write!(f, "Alarm {from} -> {to} ({reason})")
+            }
+            EventKind::Duress { user, action } => {
+                write!(f, "DURESS: {user}: {action}")
+            }
+        }
+    }
+}
+
+/// [`EventKind`] without the data, for filtering. Named like the `type` of
+/// the event lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    Sensor,
+    Command,
+    Login,
+    AccessDenied,
+    Connection,
+    Battery,
+    Alarm,
+    Duress,
+}
+
+impl EventType {
+    /// What the history panel offers, duress events never show up there.
+    pub const ALL: [EventType; 7] = [
+        EventType::Sensor,
+        EventType::Command,
+        EventType::Login,
+        EventType::AccessDenied,
+        EventType::Connection,
+        EventType::Battery,
+        EventType::Alarm,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            EventType::Sensor => "Sensor",
+            EventType::Command => "Command",
+            EventType::Login => "Login",
+            EventType::AccessDenied => "Denied",
+            EventType::Connection => "Connection",
+            EventType::Battery => "Battery",
+            EventType::Alarm => "Alarm",
+            EventType::Duress => "Duress",
+        }
+    }
+}
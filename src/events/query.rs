@@ -0,0 +1,85 @@
+use crate::events::{Event, EventType};
+use crate::sensors::SensorId;
+use chrono::{DateTime, Utc};
+
+/// Filter for [`EventReader::query`](crate::events::log::EventReader::query).
+/// Empty lists and `None` mean "don't filter on this".
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub types: Vec<EventType>,
+    pub sensors: Vec<SensorId>,
+    pub devices: Vec<String>,
+    pub user: Option<String>,
+    /// Case-insensitive substring of the event description.
+    pub text: Option<String>,
+    /// Keep only the newest this many matches.
+    pub limit: Option<usize>,
+    /// Also match [hidden](crate::events::EventKind::is_hidden) events.
+    pub include_hidden: bool,
+}
+
+impl EventQuery {
+    pub fn matches(&self, event: &Event) -> bool {
+        if event.kind.is_hidden() && !self.include_hidden {
+            return false;
+        }
+
+        if self
+            .since
+            .is_some_and(|since| event.timestamp < since)
+            || self
+                .until
+                .is_some_and(|until| event.timestamp > until)
+        {
+            return false;
+        }
+
+        if !self.types.is_empty()
+            && !self
+                .types
+                .contains(&event.kind.event_type())
+        {
+            return false;
+        }
+
+        if !self.sensors.is_empty()
+            && !event
+                .kind
+                .sensor()
+                .is_some_and(|sensor| self.sensors.contains(sensor))
+        {
+            return false;
+        }
+
+        if !self.devices.is_empty()
+            && !event
+                .device
+                .as_ref()
+                .is_some_and(|device| self.devices.contains(device))
+        {
+            return false;
+        }
+
+        if let Some(user) = &self.user
+            && !event.kind.involves(user)
+        {
+            return false;
+        }
+
+        if let Some(text) = &self.text {
+            let text = text.to_lowercase();
+            if !event
+                .kind
+                .to_string()
+                .to_lowercase()
+                .contains(&text)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
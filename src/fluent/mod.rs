@@ -1,2 +0,0 @@
-pub mod containers;
-pub mod bit_inspect;
@@ -0,0 +1,1159 @@
+//! JSON API for other systems on the network, see the README for the
+//! routes. Requests are read, their passwords checked and the history read
+//! by a few worker threads. The rest is answered by [`HttpServer::update`]
+//! in the main loop, so they see and change the same devices, users and
+//! alarms as the operator. `/api/stream` turns into a WebSocket on a thread
+//! of its own, fed from the event log until the client's session ends.
+//! Plain HTTP/1.1, one request per connection.
+
+mod routes;
+mod status;
+
+pub use routes::Control;
+
+use crate::auth::users::{Authenticated, User};
+use crate::config::HttpConfig;
+use crate::events::Event;
+use crate::events::log::EventReader;
+use crate::events::query::EventQuery;
+use crate::notice::Notice;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+/// Threads reading requests, a slow client holds up only one of them.
+const WORKERS: usize = 4;
+/// How long a request waits for the main loop before giving up.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Request line and headers, anything longer is not meant for us.
+const MAX_HEAD: usize = 16 * 1024;
+/// Bodies are a few credentials at most.
+const MAX_BODY: usize = 64 * 1024;
+/// A client has this long to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often workers look whether the server is gone, and streams whether
+/// the client sent something.
+const POLL: Duration = Duration::from_millis(100);
+/// A quiet stream is pinged this often, to notice clients that are gone.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Other(String),
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Post => write!(f, "POST"),
+            Method::Other(method) => write!(f, "{method}"),
+        }
+    }
+}
+
+/// A request as the main loop sees it, already read off the socket.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// Percent-decoded path segments, `/api/status` is `["api", "status"]`.
+    pub path: Vec<String>,
+    pub query: Vec<(String, String)>,
+    /// From `Authorization: Bearer ...`, or the `token` parameter for
+    /// browsers, which cannot set headers on a WebSocket.
+    pub token: Option<String>,
+    pub body: String,
+    /// `Sec-WebSocket-Key` of a WebSocket upgrade.
+    pub websocket_key: Option<String>,
+    /// Every username and password in the body, already checked.
+    pub checked: Vec<CheckedPassword>,
+}
+
+/// A password from a request body, checked on the worker: Argon2 would
+/// hold up the main loop for every login.
+#[derive(Debug, Clone)]
+pub struct CheckedPassword {
+    pub username: String,
+    pub password: String,
+    pub result: Option<Authenticated>,
+    /// Second factor sent along with the password.
+    pub code: Option<String>,
+    /// Hash of the recovery code `code` turned out to be, see
+    /// [`find_recovery_code`](crate::auth::users::find_recovery_code).
+    pub recovery_code: Option<String>,
+}
+
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// The request is fine, the device or alarm is not in a state for it.
+    Conflict(String),
+    LockedOut(String),
+    /// The main loop did not answer in time.
+    Unavailable,
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::BadRequest(_) => 400,
+            HttpError::Unauthorized(_) => 401,
+            HttpError::Forbidden(_) => 403,
+            HttpError::NotFound(_) => 404,
+            HttpError::Conflict(_) => 409,
+            HttpError::LockedOut(_) => 429,
+            HttpError::Unavailable => 503,
+        }
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::BadRequest(message)
+            | HttpError::Unauthorized(message)
+            | HttpError::Forbidden(message)
+            | HttpError::NotFound(message)
+            | HttpError::Conflict(message)
+            | HttpError::LockedOut(message) => write!(f, "{message}"),
+            HttpError::Unavailable => write!(f, "Control is not responding"),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+#[derive(Debug)]
+pub enum Body {
+    Json(String),
+    /// Events to send over the WebSocket the request asked for.
+    Stream(Receiver<Event>),
+    /// History the worker looks up, reading the files would hold up the
+    /// main loop.
+    Events(EventReader, EventQuery),
+}
+
+pub type Reply = Result<Body, HttpError>;
+
+#[derive(Debug)]
+struct PendingRequest {
+    request: Request,
+    reply: Sender<Reply>,
+}
+
+/// A logged-in API client. The role is looked up again on every request,
+/// so changes in the Users panel apply right away.
+#[derive(Debug, Clone)]
+pub struct ApiSession {
+    pub username: String,
+    pub expires_at: DateTime<Utc>,
+    /// Logged in with the duress password, see [`crate::auth::duress`].
+    pub duress: bool,
+}
+
+/// A WebSocket subscriber, see [`HttpServer::update`].
+#[derive(Debug)]
+pub struct Subscriber {
+    pub token: String,
+    pub filter: EventQuery,
+    pub events: Sender<Event>,
+}
+
+/// The users as of the last [`HttpServer::update`], for the workers.
+type Users = Arc<Mutex<Arc<Vec<User>>>>;
+
+/// The listening socket and the clients logged in through it.
+pub struct HttpServer {
+    address: String,
+    /// Where it ended up listening, the port may have been 0.
+    local_address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+    requests: Receiver<PendingRequest>,
+    users: Users,
+    sessions: HashMap<String, ApiSession>,
+    subscribers: Vec<Subscriber>,
+    /// Everything recorded, subscribed on the first update.
+    feed: Option<Receiver<Event>>,
+}
+
+impl std::fmt::Debug for HttpServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpServer")
+            .field("address", &self.local_address)
+            .field("sessions", &self.sessions.len())
+            .field("subscribers", &self.subscribers.len())
+            .finish()
+    }
+}
+
+impl HttpServer {
+    pub fn start(address: &str) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(address).with_context(|| {
+            format!("Failed to serve the HTTP API on {address}")
+        })?;
+        // Polled, so the workers notice when the server is dropped
+        listener.set_nonblocking(true)?;
+        let local_address = listener.local_addr()?;
+
+        let listener = Arc::new(listener);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (request_tx, request_rx) = mpsc::channel();
+        let users = Users::default();
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let listener = listener.clone();
+                let stopped = stopped.clone();
+                let requests = request_tx.clone();
+                let users = users.clone();
+                std::thread::spawn(move || {
+                    accept(&listener, &stopped, &requests, &users)
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            address: address.to_owned(),
+            local_address,
+            stopped,
+            workers,
+            requests: request_rx,
+            users,
+            sessions: HashMap::new(),
+            subscribers: Vec::new(),
+            feed: None,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Answers the requests that came in since the last call and passes
+    /// new events on to the WebSockets. Call it regularly, the window does
+    /// once per frame.
+    pub fn update(
+        &mut self,
+        control: &mut Control,
+        now: DateTime<Utc>,
+    ) -> Vec<Notice> {
+        let feed = self
+            .feed
+            .get_or_insert_with(|| control.events.subscribe());
+        let mut users = lock(&self.users);
+        if users.as_slice() != control.users.users() {
+            *users = Arc::new(control.users.users().to_vec());
+        }
+        drop(users);
+        self.sessions.retain(|_, session| {
+            session.expires_at > now
+                && control
+                    .users
+                    .find(&session.username)
+                    .is_some()
+        });
+
+        let mut notices = Vec::new();
+        while let Ok(pending) = self.requests.try_recv() {
+            let reply = routes::handle(
+                &pending.request,
+                &mut self.sessions,
+                &mut self.subscribers,
+                control,
+                &mut notices,
+                now,
+            );
+            // The client may have given up waiting
+            let _ = pending.reply.send(reply);
+        }
+
+        // Dropping the sender closes the WebSocket: logged out, expired or
+        // removed from the Users panel
+        self.subscribers.retain(|subscriber| {
+            self.sessions
+                .contains_key(&subscriber.token)
+        });
+        for event in feed.try_iter() {
+            self.subscribers.retain(|subscriber| {
+                !subscriber.filter.matches(&event)
+                    || subscriber
+                        .events
+                        .send(event.clone())
+                        .is_ok()
+            });
+        }
+
+        notices
+    }
+}
+
+/// Starts, stops or moves the server to match the config. A failed bind
+/// leaves no server running.
+pub fn configure(
+    server: &mut Option<HttpServer>,
+    config: &HttpConfig,
+) -> anyhow::Result<()> {
+    if server.as_ref().map(HttpServer::address) == config.listen.as_deref() {
+        return Ok(());
+    }
+
+    // Frees the port first, the new address may use the same one
+    *server = None;
+    if let Some(listen) = &config.listen {
+        *server = Some(HttpServer::start(listen)?);
+    }
+
+    Ok(())
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped
+            .store(true, Ordering::Relaxed);
+        // Requests still waiting for the main loop get no answer
+        self.requests = mpsc::channel().1;
+        // The port is free again once they are gone
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A worker: serves connections one after the other until the server is
+/// dropped.
+fn accept(
+    listener: &TcpListener,
+    stopped: &AtomicBool,
+    requests: &Sender<PendingRequest>,
+    users: &Users,
+) {
+    while !stopped.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((connection, _)) => serve(connection, requests, users),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL);
+            }
+            Err(err) => {
+                eprintln!("Failed to accept an HTTP connection: {err}");
+                std::thread::sleep(POLL);
+            }
+        }
+    }
+}
+
+fn lock(users: &Users) -> std::sync::MutexGuard<'_, Arc<Vec<User>>> {
+    users
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Runs on a worker: reads the request, checks its passwords, hands it to
+/// the main loop and writes the answer back.
+fn serve(
+    mut connection: TcpStream,
+    requests: &Sender<PendingRequest>,
+    users: &Users,
+) {
+    // Accepted sockets inherit non-blocking mode on some systems
+    if let Err(err) = connection
+        .set_nonblocking(false)
+        .and_then(|()| connection.set_read_timeout(Some(READ_TIMEOUT)))
+    {
+        eprintln!("Failed to set up an HTTP connection: {err}");
+        return;
+    }
+
+    let mut websocket_key = None;
+    let reply = match read_request(&mut connection) {
+        Ok(mut parsed) => {
+            websocket_key = parsed.websocket_key.clone();
+            let users = lock(users).clone();
+            parsed.checked = routes::check_passwords(&parsed, &users);
+
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let pending = PendingRequest { request: parsed, reply: reply_tx };
+
+            match requests.send(pending) {
+                Ok(()) => reply_rx
+                    .recv_timeout(REPLY_TIMEOUT)
+                    .unwrap_or(Err(HttpError::Unavailable)),
+                Err(_) => Err(HttpError::Unavailable),
+            }
+        }
+        Err(err) => Err(err),
+    };
+
+    let reply = match reply {
+        Ok(Body::Json(body)) => Ok(body),
+        Ok(Body::Events(reader, query)) => reader
+            .query(&query)
+            .map(|events| {
+                serde_json::to_string(&events)
+                    .expect("API replies are always valid JSON")
+            })
+            .map_err(|err| HttpError::Conflict(format!("{err:#}"))),
+        Ok(Body::Stream(events)) => match websocket_key {
+            Some(key) => {
+                std::thread::spawn(move || stream(connection, &key, events));
+                return;
+            }
+            None => Err(HttpError::BadRequest(
+                "This is a WebSocket, connect with one".to_owned(),
+            )),
+        },
+        Err(err) => Err(err),
+    };
+
+    let (status, body) = match reply {
+        Ok(body) => (200, body),
+        Err(err) => (
+            err.status(),
+            serde_json::json!({ "error": err.to_string() }).to_string(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    if let Err(err) = connection
+        .write_all(head.as_bytes())
+        .and_then(|()| connection.write_all(body.as_bytes()))
+    {
+        eprintln!("Failed to answer an HTTP request: {err}");
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn read_request(connection: &mut TcpStream) -> Result<Request, HttpError> {
+    let bad = |message: String| HttpError::BadRequest(message);
+
+    // A byte at a time up to the blank line, the body and any WebSocket
+    // frames after it stay in the socket
+    let started = Instant::now();
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD || started.elapsed() > READ_TIMEOUT {
+            return Err(bad(
+                "Request headers too long or too slow".to_owned(),
+            ));
+        }
+        match connection.read(&mut byte) {
+            Ok(0) => {
+                return Err(bad(
+                    "Connection closed mid-request".to_owned()
+                ));
+            }
+            Ok(_) => head.push(byte[0]),
+            Err(err) => {
+                return Err(bad(format!(
+                    "Unreadable request: {err}"
+                )));
+            }
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed
+        .parse(&head)
+        .map_err(|err| bad(format!("Malformed request: {err}")))?;
+    let (Some(method), Some(url)) = (parsed.method, parsed.path) else {
+        return Err(bad("Malformed request line".to_owned()));
+    };
+    let method = match method {
+        "GET" => Method::Get,
+        "POST" => Method::Post,
+        other => Method::Other(other.to_owned()),
+    };
+
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let path = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| decode(segment, false))
+        .collect::<Result<_, _>>()?;
+
+    let query: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair
+                .split_once('=')
+                .unwrap_or((pair, ""));
+            Ok((decode(key, true)?, decode(value, true)?))
+        })
+        .collect::<Result<_, HttpError>>()?;
+
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+    };
+
+    let token = header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+        .or_else(|| {
+            query
+                .iter()
+                .find(|(key, _)| key == "token")
+                .map(|(_, token)| token.clone())
+        });
+
+    let websocket_key = header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        .then(|| header("Sec-WebSocket-Key"))
+        .flatten()
+        .map(str::to_owned);
+
+    // The body of an upgrade is the rest of the connection
+    let mut body = String::new();
+    if websocket_key.is_none() {
+        if header("Transfer-Encoding").is_some() {
+            return Err(bad(
+                "Send the body with a Content-Length".to_owned(),
+            ));
+        }
+        let length = header("Content-Length")
+            .map(|length| length.trim().parse::<usize>())
+            .transpose()
+            .map_err(|_| bad("Invalid Content-Length".to_owned()))?
+            .unwrap_or(0);
+        if length > MAX_BODY {
+            return Err(bad(format!(
+                "Bodies are at most {MAX_BODY} bytes"
+            )));
+        }
+        if header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+        {
+            let _ = connection.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+
+        let mut bytes = vec![0; length];
+        connection
+            .read_exact(&mut bytes)
+            .map_err(|err| bad(format!("Unreadable body: {err}")))?;
+        body = String::from_utf8(bytes)
+            .map_err(|_| bad("The body is not UTF-8".to_owned()))?;
+    }
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        token,
+        body,
+        websocket_key,
+        checked: Vec::new(),
+    })
+}
+
+/// Takes over the connection and sends every event as a text message
+/// until the client closes it or [`HttpServer::update`] drops the sender.
+/// Reads give up after [`POLL`] to look for events in between, pings are
+/// answered by tungstenite on the next flush.
+fn stream(mut connection: TcpStream, key: &str, events: Receiver<Event>) {
+    let upgrade = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if connection
+        .write_all(upgrade.as_bytes())
+        .and_then(|()| connection.set_read_timeout(Some(POLL)))
+        .is_err()
+    {
+        return;
+    }
+
+    let mut socket = WebSocket::from_raw_socket(connection, Role::Server, None);
+    let mut last_sent = Instant::now();
+    loop {
+        match socket.read() {
+            Ok(Message::Close(_)) => {
+                // Sends the close reply queued by the read
+                let _ = socket.flush();
+                return;
+            }
+            // Requests are not taken over the stream
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if err.kind() == ErrorKind::WouldBlock
+                    || err.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+
+        loop {
+            let event = match events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return;
+                }
+            };
+
+            let message = Message::text(
+                serde_json::to_string(&event)
+                    .expect("Events are always valid JSON"),
+            );
+            if socket.send(message).is_err() {
+                return;
+            }
+            last_sent = Instant::now();
+        }
+
+        // A failed write is how a client that vanished shows up
+        if last_sent.elapsed() >= PING_INTERVAL {
+            if socket
+                .send(Message::Ping(Default::default()))
+                .is_err()
+            {
+                return;
+            }
+            last_sent = Instant::now();
+        }
+        if socket.flush().is_err() {
+            return;
+        }
+    }
+}
+
+/// Undoes percent-encoding, and `+` for spaces in query strings.
+fn decode(input: &str, query: bool) -> Result<String, HttpError> {
+    let malformed = || HttpError::BadRequest(format!("Malformed URL: {input}"));
+
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let [first, tail @ ..] = rest {
+        match first {
+            b'%' => {
+                let hex = tail.get(..2).ok_or_else(malformed)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| malformed())?;
+                bytes.push(
+                    u8::from_str_radix(hex, 16).map_err(|_| malformed())?,
+                );
+                rest = &tail[2..];
+                continue;
+            }
+            b'+' if query => bytes.push(b' '),
+            other => bytes.push(*other),
+        }
+        rest = tail;
+    }
+
+    String::from_utf8(bytes).map_err(|_| malformed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm::OperatorAction;
+    use crate::auth::dual_control::{DualAction, DualControlPolicy};
+    use crate::auth::lockout::{LockoutPolicy, LoginGuard};
+    use crate::auth::totp;
+    use crate::auth::users::{Role, UserStore};
+    use crate::config::Config;
+    use crate::devices::{Device, DeviceEntry};
+    use crate::events::EventKind;
+    use crate::events::log::EventLog;
+    use crate::sensors::SensorMap;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    const WAIT: Duration = Duration::from_secs(10);
+    const PASSWORD: &str = "correct horse";
+
+    /// A server on a free port in front of a device called `Lab`, with an
+    /// `admin` and a `viewer`.
+    struct Rig {
+        server: HttpServer,
+        devices: Vec<Device>,
+        sensors: Arc<SensorMap>,
+        events: EventLog,
+        recorded: Receiver<Event>,
+        users: UserStore,
+        login_guard: LoginGuard,
+        dual_control: DualControlPolicy,
+        /// Users, events and lockouts, removed with the rig.
+        _dir: TempDir,
+    }
+
+    impl Rig {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+
+            let sensors = Arc::new(SensorMap::default());
+            let entry = DeviceEntry {
+                name: "Lab".to_owned(),
+                endpoint: None,
+                protocol: Default::default(),
+            };
+            let mut users =
+                UserStore::open(dir.path().join("users.json")).unwrap();
+            users
+                .add("admin", PASSWORD, Role::Admin, 60)
+                .unwrap();
+            users
+                .add("viewer", PASSWORD, Role::Viewer, 60)
+                .unwrap();
+            let mut events = EventLog::open(dir.path()).unwrap();
+
+            Self {
+                server: HttpServer::start("127.0.0.1:0").unwrap(),
+                devices: vec![Device::start(
+                    entry,
+                    &Config::default(),
+                    &sensors,
+                )],
+                sensors,
+                recorded: events.subscribe(),
+                events,
+                users,
+                login_guard: LoginGuard::open(
+                    dir.path().join("lockout.json"),
+                    LockoutPolicy::default(),
+                )
+                .unwrap(),
+                dual_control: DualControlPolicy::default(),
+                _dir: dir,
+            }
+        }
+
+        /// Answers requests until `client` is done talking to the server.
+        fn run<T: Send + 'static>(
+            &mut self,
+            client: impl FnOnce(Client) -> T + Send + 'static,
+        ) -> T {
+            let address = self.server.local_address;
+            let client = std::thread::spawn(move || {
+                client(Client {
+                    base: format!("http://{address}"),
+                    agent: ureq::Agent::new_with_config(
+                        ureq::Agent::config_builder()
+                            .http_status_as_error(false)
+                            .build(),
+                    ),
+                })
+            });
+
+            let deadline = Instant::now() + WAIT;
+            let mut duress_alerts = Vec::new();
+            while !client.is_finished() {
+                assert!(Instant::now() < deadline, "Timed out");
+                self.server.update(
+                    &mut Control {
+                        devices: &mut self.devices,
+                        sensors: &self.sensors,
+                        events: &mut self.events,
+                        users: &mut self.users,
+                        login_guard: &mut self.login_guard,
+                        dual_control: &self.dual_control,
+                        on_battery: false,
+                        duress_alerts: &mut duress_alerts,
+                    },
+                    Utc::now(),
+                );
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            client.join().unwrap()
+        }
+    }
+
+    struct Client {
+        base: String,
+        agent: ureq::Agent,
+    }
+
+    impl Client {
+        fn get(&self, path: &str, token: Option<&str>) -> (u16, String) {
+            let mut request = self
+                .agent
+                .get(format!("{}{path}", self.base));
+            if let Some(token) = token {
+                request = request.header(
+                    "Authorization",
+                    format!("Bearer {token}"),
+                );
+            }
+            Self::answer(request.call())
+        }
+
+        fn post(
+            &self,
+            path: &str,
+            token: Option<&str>,
+            body: serde_json::Value,
+        ) -> (u16, String) {
+            let mut request = self
+                .agent
+                .post(format!("{}{path}", self.base));
+            if let Some(token) = token {
+                request = request.header(
+                    "Authorization",
+                    format!("Bearer {token}"),
+                );
+            }
+            Self::answer(request.send(body.to_string()))
+        }
+
+        fn answer(
+            response: Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+        ) -> (u16, String) {
+            let mut response = response.unwrap();
+            let body = response
+                .body_mut()
+                .read_to_string()
+                .unwrap();
+            (response.status().as_u16(), body)
+        }
+
+        fn login(&self, username: &str, password: &str) -> (u16, String) {
+            let (status, body) = self.post(
+                "/api/login",
+                None,
+                serde_json::json!({ "username": username, "password": password }),
+            );
+            let token = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|reply| Some(reply["token"].as_str()?.to_owned()))
+                .unwrap_or(body);
+            (status, token)
+        }
+    }
+
+    #[test]
+    fn only_logged_in_clients_see_the_status() {
+        let mut rig = Rig::new();
+        let (anonymous, wrong, status) = rig.run(|client| {
+            let anonymous = client.get("/api/status", None).0;
+            let wrong = client
+                .login("admin", "wrong password")
+                .0;
+            let (login, token) = client.login("admin", PASSWORD);
+            assert_eq!(login, 200, "{token}");
+            (
+                anonymous,
+                wrong,
+                client.get("/api/status", Some(&token)),
+            )
+        });
+
+        assert_eq!(anonymous, 401);
+        assert_eq!(wrong, 401);
+        assert_eq!(status.0, 200);
+        let status: serde_json::Value =
+            serde_json::from_str(&status.1).unwrap();
+        assert_eq!(status["devices"][0]["name"], "Lab");
+
+        let failed = rig
+            .recorded
+            .try_iter()
+            .filter(|event| {
+                matches!(
+                    event.kind,
+                    EventKind::LoginFailed { .. }
+                )
+            })
+            .count();
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn commands_above_the_role_are_refused_and_logged() {
+        let mut rig = Rig::new();
+        let (arm, history, unknown) = rig.run(|client| {
+            let (_, token) = client.login("viewer", PASSWORD);
+            (
+                client
+                    .post(
+                        "/api/devices/Lab/arm",
+                        Some(&token),
+                        serde_json::json!({}),
+                    )
+                    .0,
+                client
+                    .get("/api/events", Some(&token))
+                    .0,
+                client
+                    .get("/api/nothing", Some(&token))
+                    .0,
+            )
+        });
+
+        assert_eq!(arm, 403);
+        assert_eq!(history, 200);
+        assert_eq!(unknown, 404);
+        assert!(
+            rig.recorded
+                .try_iter()
+                .any(|event| matches!(
+                    event.kind,
+                    EventKind::AccessDenied { ref user, role: Role::Viewer, .. }
+                        if user == "viewer"
+                ))
+        );
+        assert!(!rig.devices[0].alarm.state().is_alarm());
+    }
+
+    #[test]
+    fn only_accepted_alarm_actions_are_logged() {
+        let mut rig = Rig::new();
+        let (first, again) = rig.run(|client| {
+            let (_, token) = client.login("admin", PASSWORD);
+            let disarm = || {
+                client
+                    .post(
+                        "/api/devices/Lab/disarm",
+                        Some(&token),
+                        serde_json::json!({}),
+                    )
+                    .0
+            };
+            (disarm(), disarm())
+        });
+
+        assert_eq!(first, 200);
+        assert_eq!(again, 409);
+        let commands = rig
+            .recorded
+            .try_iter()
+            .filter(|event| matches!(event.kind, EventKind::Command { .. }))
+            .count();
+        assert_eq!(commands, 1);
+    }
+
+    #[test]
+    fn approvers_with_a_second_factor_need_their_code() {
+        const SECRET: &[u8] = b"12345678901234567890";
+        let mut rig = Rig::new();
+        rig.dual_control.actions = vec![DualAction::CancelAlarm];
+        rig.users
+            .add("second", PASSWORD, Role::Admin, 60)
+            .unwrap();
+        rig.users
+            .enroll_totp("second", SECRET, &[], 0)
+            .unwrap();
+        rig.devices[0]
+            .alarm
+            .operator(OperatorAction::Panic, Utc::now())
+            .unwrap();
+
+        let step = Utc::now().timestamp() as u64 / totp::STEP_SECS;
+        let code = |step| {
+            totp::format_code(
+                totp::hotp(SECRET, step, totp::DIGITS),
+                totp::DIGITS,
+            )
+        };
+        let (right, wrong) = (code(step), code(step + 10));
+        let (missing, invalid, approved) = rig.run(move |client| {
+            let (_, token) = client.login("admin", PASSWORD);
+            let disarm = |code: Option<&str>| {
+                client
+                    .post(
+                        "/api/devices/Lab/disarm",
+                        Some(&token),
+                        serde_json::json!({ "approver": {
+                            "username": "second",
+                            "password": PASSWORD,
+                            "code": code,
+                        }}),
+                    )
+                    .0
+            };
+            (
+                disarm(None),
+                disarm(Some(&wrong)),
+                disarm(Some(&right)),
+            )
+        });
+
+        assert_eq!(missing, 401);
+        assert_eq!(invalid, 401);
+        assert_eq!(approved, 200);
+        let failed = rig
+            .recorded
+            .try_iter()
+            .filter(|event| {
+                matches!(
+                    event.kind,
+                    EventKind::LoginFailed { .. }
+                )
+            })
+            .count();
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let mut rig = Rig::new();
+        rig.users
+            .enroll_totp(
+                "viewer",
+                b"12345678901234567890",
+                &["abcd-efgh".to_owned()],
+                0,
+            )
+            .unwrap();
+
+        let (first, again) = rig.run(|client| {
+            let login = || {
+                client
+                    .post(
+                        "/api/login",
+                        None,
+                        serde_json::json!({
+                            "username": "viewer",
+                            "password": PASSWORD,
+                            "code": "abcd-efgh",
+                        }),
+                    )
+                    .0
+            };
+            (login(), login())
+        });
+
+        assert_eq!(first, 200);
+        assert_eq!(again, 401);
+        let user = rig.users.find("viewer").unwrap();
+        assert!(
+            user.totp
+                .as_ref()
+                .unwrap()
+                .recovery_codes
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn the_history_is_filtered() {
+        let mut rig = Rig::new();
+        let (status, body) = rig.run(|client| {
+            client.login("viewer", PASSWORD);
+            let (_, token) = client.login("admin", PASSWORD);
+            client.get(
+                "/api/events?type=login&user=admin",
+                Some(&token),
+            )
+        });
+
+        assert_eq!(status, 200, "{body}");
+        let events: Vec<Event> = serde_json::from_str(&body).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].kind,
+            EventKind::Login { user: "admin".to_owned() }
+        );
+    }
+
+    #[test]
+    fn streams_filter_by_device_and_type_and_answer_pings() {
+        let mut rig = Rig::new();
+        let (first, pong) = rig.run(|client| {
+            let (_, token) = client.login("admin", PASSWORD);
+            let address = client.base.trim_start_matches("http://");
+            let connection = TcpStream::connect(address).unwrap();
+            connection
+                .set_read_timeout(Some(WAIT))
+                .unwrap();
+            let (mut socket, _) = tungstenite::client(
+                format!(
+                    "ws://{address}/api/stream?token={token}&device=Lab&type=alarm"
+                ),
+                connection,
+            )
+            .unwrap();
+
+            // A login, then a command and the alarm change it makes, only
+            // the last one is asked for
+            client.login("viewer", PASSWORD);
+            client.post(
+                "/api/devices/Lab/disarm",
+                Some(&token),
+                serde_json::json!({}),
+            );
+            let first = loop {
+                if let Message::Text(text) = socket.read().unwrap() {
+                    break text.to_string();
+                }
+            };
+
+            socket
+                .send(Message::Ping(tungstenite::Bytes::from_static(
+                    b"still there?",
+                )))
+                .unwrap();
+            let pong = loop {
+                if let Message::Pong(payload) = socket.read().unwrap() {
+                    break payload.to_vec();
+                }
+            };
+
+            socket.close(None).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(_) => {}
+                    Err(tungstenite::Error::ConnectionClosed) => break,
+                    Err(err) => panic!("Not closed cleanly: {err}"),
+                }
+            }
+
+            (first, pong)
+        });
+
+        let first: Event = serde_json::from_str(&first).unwrap();
+        assert_eq!(first.device.as_deref(), Some("Lab"));
+        assert!(matches!(
+            first.kind,
+            EventKind::Alarm { .. }
+        ));
+        assert_eq!(pong, b"still there?");
+    }
+}
@@ -0,0 +1,855 @@
+use crate::alarm::{AlarmCause, OperatorAction};
+use crate::auth::dual_control::{
+    ApprovalError, DualAction, DualControlPolicy, PendingApproval,
+};
+use crate::auth::duress::DuressAlert;
+use crate::auth::lockout::LoginGuard;
+use crate::auth::permissions::{Permission, PermissionDenied};
+use crate::auth::users::{
+    Authenticated, Role, User, UserStore, check_password, find_recovery_code,
+};
+use crate::authorized::api::Api;
+use crate::devices::{self, Device};
+use crate::events::log::EventLog;
+use crate::events::query::EventQuery;
+use crate::events::{EventKind, EventType};
+use crate::http::status::{DeviceStatus, Status};
+use crate::http::{
+    ApiSession, Body, CheckedPassword, HttpError, Method, Reply, Request,
+    Subscriber,
+};
+use crate::notice::Notice;
+use crate::sensors::SensorMap;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+/// Events `GET /api/events` returns when no `limit` is given.
+const DEFAULT_EVENT_LIMIT: usize = 100;
+
+/// What the API works on, borrowed from the window's or the daemon's state
+/// for one [`HttpServer::update`](crate::http::HttpServer::update).
+pub struct Control<'a> {
+    pub devices: &'a mut [Device],
+    pub sensors: &'a SensorMap,
+    pub events: &'a mut EventLog,
+    pub users: &'a mut UserStore,
+    pub login_guard: &'a mut LoginGuard,
+    pub dual_control: &'a DualControlPolicy,
+    pub on_battery: bool,
+    /// Logged and sent out by the caller, like the window's.
+    pub duress_alerts: &'a mut Vec<DuressAlert>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+    /// Authenticator or recovery code, for users with a second factor.
+    #[serde(default)]
+    code: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CommandBody {
+    /// Second operator of a two-person command.
+    #[serde(default)]
+    approver: Option<Credentials>,
+}
+
+#[derive(Debug, Serialize)]
+struct LoginReply {
+    token: String,
+    role: Role,
+    expires_at: DateTime<Utc>,
+}
+
+/// The user behind a request's token.
+#[derive(Debug, Clone)]
+struct Caller {
+    username: String,
+    role: Role,
+    duress: bool,
+}
+
+/// What `POST /api/devices/{name}/{command}` can do.
+#[derive(Debug, Clone, Copy)]
+enum DeviceCommand {
+    Lock {
+        command: &'static str,
+        send: fn(&Api, Role) -> anyhow::Result<()>,
+    },
+    Critical(DualAction),
+    Alarm(OperatorAction),
+}
+
+impl DeviceCommand {
+    fn parse(command: &str) -> Option<Self> {
+        Some(match command {
+            "lock_back_door" => DeviceCommand::Lock {
+                command: "lock_back_door",
+                send: Api::send_lock_back_door,
+            },
+            "lock_front_door" => DeviceCommand::Lock {
+                command: "lock_front_door",
+                send: Api::send_lock_front_door,
+            },
+            "unlock_back_door" => {
+                DeviceCommand::Critical(DualAction::UnlockBackDoor)
+            }
+            "unlock_front_door" => {
+                DeviceCommand::Critical(DualAction::UnlockFrontDoor)
+            }
+            "reset" => DeviceCommand::Critical(DualAction::Reset),
+            "arm" => DeviceCommand::Alarm(OperatorAction::Arm),
+            "disarm" => DeviceCommand::Alarm(OperatorAction::Disarm),
+            "panic" => DeviceCommand::Alarm(OperatorAction::Panic),
+            "acknowledge" => DeviceCommand::Alarm(OperatorAction::Acknowledge),
+            "silence" => DeviceCommand::Alarm(OperatorAction::Silence),
+            _ => return None,
+        })
+    }
+}
+
+pub fn handle(
+    request: &Request,
+    sessions: &mut HashMap<String, ApiSession>,
+    subscribers: &mut Vec<Subscriber>,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    now: DateTime<Utc>,
+) -> Reply {
+    let path: Vec<&str> = request
+        .path
+        .iter()
+        .map(String::as_str)
+        .collect();
+
+    match (&request.method, path.as_slice()) {
+        (Method::Post, ["api", "login"]) => {
+            login(request, sessions, control, notices, now)
+        }
+        (Method::Post, ["api", "logout"]) => {
+            if let Some(token) = &request.token {
+                sessions.remove(token);
+            }
+            Ok(Body::Json("{}".to_owned()))
+        }
+        (Method::Get, ["api", "status"]) => {
+            authenticate(request, sessions, control)?;
+            to_json(&Status {
+                on_battery: control.on_battery,
+                devices: control
+                    .devices
+                    .iter()
+                    .map(|device| DeviceStatus::new(device, control.sensors))
+                    .collect(),
+            })
+        }
+        (Method::Get, ["api", "devices", name]) => {
+            authenticate(request, sessions, control)?;
+            let device = find_device(control, name)?;
+            to_json(&DeviceStatus::new(
+                &control.devices[device],
+                control.sensors,
+            ))
+        }
+        (Method::Post, ["api", "devices", name, command]) => {
+            let caller = authenticate(request, sessions, control)?;
+            device_command(
+                request, &caller, control, notices, name, command, now,
+            )
+        }
+        (Method::Get, ["api", "events"]) => {
+            let caller = authenticate(request, sessions, control)?;
+            caller.require(
+                control,
+                Permission::ViewHistory,
+                "view history",
+            )?;
+            events(request, control)
+        }
+        (Method::Get, ["api", "stream"]) => {
+            let caller = authenticate(request, sessions, control)?;
+            caller.require(
+                control,
+                Permission::ViewHistory,
+                "view history",
+            )?;
+            stream(request, subscribers)
+        }
+        _ => Err(HttpError::NotFound(format!(
+            "No route for {} /{}",
+            request.method,
+            request.path.join("/")
+        ))),
+    }
+}
+
+/// Same checks as the login form: lockout, password, second factor. A
+/// duress password logs in like the real one.
+fn login(
+    request: &Request,
+    sessions: &mut HashMap<String, ApiSession>,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    now: DateTime<Utc>,
+) -> Reply {
+    let credentials: Credentials = parse_body(request)?;
+    let Authenticated { user, duress } = verify_credentials(
+        request,
+        control,
+        notices,
+        credentials.username.trim(),
+        &credentials.password,
+        now,
+    )?;
+
+    if user.has_totp() {
+        verify_code(
+            request,
+            control,
+            notices,
+            &user.username,
+            credentials.code.as_deref(),
+            now,
+        )?;
+    }
+
+    control
+        .login_guard
+        .succeeded(&user.username);
+    control
+        .events
+        .record(EventKind::Login { user: user.username.clone() });
+    if duress {
+        control.duress_alerts.push(DuressAlert {
+            user: user.username.clone(),
+            action: "login".to_owned(),
+        });
+    }
+
+    let reply = LoginReply {
+        token: new_token(),
+        role: user.role,
+        expires_at: now + user.session_timeout(),
+    };
+    sessions.insert(
+        reply.token.clone(),
+        ApiSession {
+            username: user.username,
+            expires_at: reply.expires_at,
+            duress,
+        },
+    );
+
+    to_json(&reply)
+}
+
+fn authenticate(
+    request: &Request,
+    sessions: &mut HashMap<String, ApiSession>,
+    control: &Control,
+) -> Result<Caller, HttpError> {
+    let unauthorized = || {
+        HttpError::Unauthorized(
+            "Log in at /api/login and send the token as `Authorization: Bearer`"
+                .to_owned(),
+        )
+    };
+
+    let token = request
+        .token
+        .as_ref()
+        .ok_or_else(unauthorized)?;
+    let session = sessions
+        .get(token)
+        .ok_or_else(unauthorized)?;
+
+    // Removed from the Users panel since
+    let Some(user) = control.users.find(&session.username) else {
+        sessions.remove(token);
+        return Err(unauthorized());
+    };
+
+    Ok(Caller {
+        username: user.username.clone(),
+        role: user.role,
+        duress: session.duress,
+    })
+}
+
+impl Caller {
+    /// Denials are logged like the window's.
+    fn require(
+        &self,
+        control: &mut Control,
+        permission: Permission,
+        action: &str,
+    ) -> Result<(), HttpError> {
+        self.role
+            .require(permission)
+            .map_err(|denied| self.denied(control, &denied, action))
+    }
+
+    fn denied(
+        &self,
+        control: &mut Control,
+        denied: &PermissionDenied,
+        action: &str,
+    ) -> HttpError {
+        control
+            .events
+            .record(EventKind::AccessDenied {
+                user: self.username.clone(),
+                role: denied.role,
+                action: action.to_owned(),
+            });
+        HttpError::Forbidden(denied.to_string())
+    }
+
+    fn record_command(
+        &self,
+        control: &mut Control,
+        device: usize,
+        command: &str,
+        approved_by: Option<String>,
+    ) {
+        control.events.record_device(
+            &control.devices[device].name,
+            EventKind::Command {
+                user: self.username.clone(),
+                command: command.to_owned(),
+                approved_by,
+            },
+        );
+    }
+}
+
+fn device_command(
+    request: &Request,
+    caller: &Caller,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    name: &str,
+    command: &str,
+    now: DateTime<Utc>,
+) -> Reply {
+    let device = find_device(control, name)?;
+    let Some(command) = DeviceCommand::parse(command) else {
+        return Err(HttpError::NotFound(format!(
+            "Unknown command '{command}'"
+        )));
+    };
+
+    let command = match command {
+        DeviceCommand::Alarm(OperatorAction::Disarm)
+            if control.devices[device]
+                .alarm
+                .state()
+                .is_alarm() =>
+        {
+            DeviceCommand::Critical(DualAction::CancelAlarm)
+        }
+        command => command,
+    };
+
+    match command {
+        DeviceCommand::Lock { command, send } => {
+            send_command(
+                caller, control, device, command, send, None,
+            )?;
+        }
+        DeviceCommand::Critical(action) => {
+            caller.require(
+                control,
+                action.permission(),
+                action.command(),
+            )?;
+            let approved_by = if control.dual_control.requires(action) {
+                Some(approve(
+                    request, caller, control, notices, device, action, now,
+                )?)
+            } else {
+                None
+            };
+            run_critical(
+                caller,
+                control,
+                notices,
+                device,
+                action,
+                approved_by,
+                now,
+            )?;
+        }
+        DeviceCommand::Alarm(action) => {
+            caller.require(
+                control,
+                Permission::for_alarm(action),
+                &format!("alarm {action}"),
+            )?;
+            run_alarm(
+                caller, control, notices, device, action, None, now,
+            )?;
+        }
+    }
+
+    to_json(&DeviceStatus::new(
+        &control.devices[device],
+        control.sensors,
+    ))
+}
+
+/// The second operator comes along in the request body, there is nobody
+/// to wait for.
+fn approve(
+    request: &Request,
+    caller: &Caller,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    device: usize,
+    action: DualAction,
+    now: DateTime<Utc>,
+) -> Result<String, HttpError> {
+    let body: CommandBody = if request.body.trim().is_empty() {
+        CommandBody::default()
+    } else {
+        parse_body(request)?
+    };
+    let Some(credentials) = body.approver else {
+        return Err(HttpError::Forbidden(format!(
+            "{action} needs a second operator, send their username and password as `approver`"
+        )));
+    };
+
+    let Authenticated { user: approver, duress } = verify_credentials(
+        request,
+        control,
+        notices,
+        credentials.username.trim(),
+        &credentials.password,
+        now,
+    )?;
+
+    // Approving takes as much as logging in would
+    if approver.has_totp() {
+        verify_code(
+            request,
+            control,
+            notices,
+            &approver.username,
+            credentials.code.as_deref(),
+            now,
+        )?;
+    }
+
+    if duress {
+        control.duress_alerts.push(DuressAlert {
+            user: approver.username.clone(),
+            action: format!("approve {}", action.command()),
+        });
+    }
+
+    let pending = PendingApproval::new(
+        action,
+        &control.devices[device].name,
+        &caller.username,
+        control.dual_control,
+        now,
+    );
+    if let Err(err) = pending.check_approver(&approver, now) {
+        if let ApprovalError::NotAllowed(denied) = &err {
+            control
+                .events
+                .record(EventKind::AccessDenied {
+                    user: approver.username,
+                    role: denied.role,
+                    action: format!("approve {}", action.command()),
+                });
+        }
+
+        return Err(HttpError::Forbidden(err.to_string()));
+    }
+    control
+        .login_guard
+        .succeeded(&approver.username);
+
+    Ok(approver.username)
+}
+
+fn run_critical(
+    caller: &Caller,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    device: usize,
+    action: DualAction,
+    approved_by: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<(), HttpError> {
+    let send: fn(&Api, Role) -> anyhow::Result<()> = match action {
+        DualAction::UnlockBackDoor => Api::send_unlock_back_door,
+        DualAction::UnlockFrontDoor => Api::send_unlock_front_door,
+        DualAction::Reset => Api::send_reset,
+        DualAction::CancelAlarm => {
+            return run_alarm(
+                caller,
+                control,
+                notices,
+                device,
+                OperatorAction::Disarm,
+                approved_by,
+                now,
+            );
+        }
+    };
+
+    send_command(
+        caller,
+        control,
+        device,
+        action.command(),
+        send,
+        approved_by,
+    )
+}
+
+/// Queues a command for the device, the [`Api`] checks the role.
+fn send_command(
+    caller: &Caller,
+    control: &mut Control,
+    device: usize,
+    command: &str,
+    send: fn(&Api, Role) -> anyhow::Result<()>,
+    approved_by: Option<String>,
+) -> Result<(), HttpError> {
+    match send(
+        &control.devices[device].api,
+        caller.role,
+    ) {
+        Ok(()) => {
+            caller.record_command(control, device, command, approved_by);
+            Ok(())
+        }
+        Err(err) => match err.downcast_ref::<PermissionDenied>() {
+            Some(denied) => Err(caller.denied(control, denied, command)),
+            None => Err(HttpError::Conflict(format!("{err:#}"))),
+        },
+    }
+}
+
+fn run_alarm(
+    caller: &Caller,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    device: usize,
+    action: OperatorAction,
+    approved_by: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<(), HttpError> {
+    let command = format!("alarm {action}");
+    if action == OperatorAction::Disarm && caller.duress {
+        control.duress_alerts.push(DuressAlert {
+            user: caller.username.clone(),
+            action: command.clone(),
+        });
+    }
+
+    // Refused actions are not commands anyone ran
+    let transition = control.devices[device]
+        .alarm
+        .operator(action, now)
+        .map_err(|err| HttpError::Conflict(err.to_string()))?;
+    caller.record_command(control, device, &command, approved_by);
+    notices.push(devices::record_transition(
+        control.events,
+        &control.devices[device].name,
+        &transition,
+    ));
+
+    Ok(())
+}
+
+/// `GET /api/events?device=Gate&type=alarm,sensor&since=...&limit=50`.
+/// Times are RFC 3339.
+fn events(request: &Request, control: &Control) -> Reply {
+    let time = |name: &str| -> Result<Option<DateTime<Utc>>, HttpError> {
+        request
+            .param(name)
+            .map(|value| {
+                DateTime::parse_from_rfc3339(value)
+                    .map(|time| time.to_utc())
+                    .map_err(|err| {
+                        HttpError::BadRequest(format!(
+                            "{name}: {err}, expected e.g. 2025-01-31T18:00:00Z"
+                        ))
+                    })
+            })
+            .transpose()
+    };
+    let limit = match request.param("limit") {
+        Some(limit) => limit.parse().map_err(|_| {
+            HttpError::BadRequest(format!(
+                "limit: '{limit}' is not a number"
+            ))
+        })?,
+        None => DEFAULT_EVENT_LIMIT,
+    };
+
+    let query = EventQuery {
+        since: time("since")?,
+        until: time("until")?,
+        limit: Some(limit),
+        ..filter(request)?
+    };
+
+    Ok(Body::Events(
+        control.events.reader(),
+        query,
+    ))
+}
+
+/// `GET /api/stream?device=Gate&type=alarm,sensor` as a WebSocket, events
+/// from now on.
+fn stream(request: &Request, subscribers: &mut Vec<Subscriber>) -> Reply {
+    let filter = filter(request)?;
+    let token = request
+        .token
+        .clone()
+        .expect("authenticated requests have a token");
+
+    let (events_tx, events_rx) = mpsc::channel();
+    subscribers.push(Subscriber { token, filter, events: events_tx });
+
+    Ok(Body::Stream(events_rx))
+}
+
+/// The parameters `/api/events` and `/api/stream` share, lists are comma
+/// separated.
+fn filter(request: &Request) -> Result<EventQuery, HttpError> {
+    let list = |name: &str| -> Vec<String> {
+        request
+            .param(name)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|item| item.trim().to_owned())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let types = list("type")
+        .into_iter()
+        .map(|name| {
+            EventType::deserialize(name.as_str().into_deserializer()).map_err(
+                |err: serde::de::value::Error| {
+                    HttpError::BadRequest(format!("type: {err}"))
+                },
+            )
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(EventQuery {
+        types,
+        devices: list("device"),
+        user: request.param("user").map(str::to_owned),
+        text: request.param("text").map(str::to_owned),
+        ..EventQuery::default()
+    })
+}
+
+/// Runs on the worker before the request is handled, for the login body
+/// and the `approver` of a command, their recovery codes included.
+pub fn check_passwords(
+    request: &Request,
+    users: &[User],
+) -> Vec<CheckedPassword> {
+    let login = parse_body::<Credentials>(request).ok();
+    let approver = parse_body::<CommandBody>(request)
+        .ok()
+        .and_then(|body| body.approver);
+
+    login
+        .into_iter()
+        .chain(approver)
+        .map(|credentials| {
+            let username = credentials.username.trim().to_owned();
+            let result =
+                check_password(users, &username, &credentials.password);
+            // Only for the right password, or anyone could make us hash
+            let recovery_code = result
+                .as_ref()
+                .zip(credentials.code.as_deref())
+                .and_then(|(checked, code)| {
+                    find_recovery_code(&checked.user, code)
+                });
+
+            CheckedPassword {
+                username,
+                password: credentials.password,
+                result,
+                code: credentials.code,
+                recovery_code,
+            }
+        })
+        .collect()
+}
+
+/// Password check behind the lockout, failures are counted and logged and
+/// may raise the alarm, like in the window. The password itself was
+/// checked by [`check_passwords`].
+fn verify_credentials(
+    request: &Request,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    username: &str,
+    password: &str,
+    now: DateTime<Utc>,
+) -> Result<Authenticated, HttpError> {
+    control
+        .login_guard
+        .check(username, now)
+        .map_err(|locked| HttpError::LockedOut(locked.to_string()))?;
+
+    // Only if the password has not been changed since
+    let authenticated = request
+        .checked
+        .iter()
+        .find(|checked| {
+            checked.username == username && checked.password == password
+        })
+        .and_then(|checked| checked.result.as_ref())
+        .and_then(|checked| {
+            let user = control.users.find(username)?;
+            (user.password_hash == checked.user.password_hash
+                && user.duress_hash == checked.user.duress_hash)
+                .then(|| Authenticated {
+                    user: user.clone(),
+                    duress: checked.duress,
+                })
+        });
+
+    match authenticated {
+        Some(authenticated) => Ok(authenticated),
+        None => {
+            count_failed_login(control, notices, username, now);
+            Err(HttpError::Unauthorized(
+                "Invalid credentials".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Authenticator or recovery code of a user with a second factor, a wrong
+/// one counts like a wrong password. Recovery codes were matched by
+/// [`check_passwords`].
+fn verify_code(
+    request: &Request,
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    username: &str,
+    code: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<(), HttpError> {
+    let Some(code) = code else {
+        return Err(HttpError::Unauthorized(format!(
+            "{username} needs a code from their authenticator app"
+        )));
+    };
+
+    let recovery_code = request
+        .checked
+        .iter()
+        .find(|checked| {
+            checked.username == username
+                && checked.code.as_deref() == Some(code)
+        })
+        .and_then(|checked| checked.recovery_code.as_deref());
+
+    match control
+        .users
+        .use_second_factor(username, code, recovery_code, now)
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => {
+            count_failed_login(control, notices, username, now);
+            Err(HttpError::Unauthorized(
+                "Invalid code".to_owned(),
+            ))
+        }
+        Err(err) => Err(HttpError::Unauthorized(format!(
+            "{err:#}"
+        ))),
+    }
+}
+
+fn count_failed_login(
+    control: &mut Control,
+    notices: &mut Vec<Notice>,
+    username: &str,
+    now: DateTime<Utc>,
+) {
+    let failure = control
+        .login_guard
+        .failed(username, now);
+    control
+        .events
+        .record(EventKind::LoginFailed {
+            user: username.to_owned(),
+            failures: failure.failures,
+            locked_until: failure.locked_until,
+        });
+
+    if !failure.raise_alarm {
+        return;
+    }
+
+    for device in control.devices.iter_mut() {
+        if let Some(transition) = device
+            .alarm
+            .sensor(AlarmCause::FailedLogins, now)
+        {
+            notices.push(devices::record_transition(
+                control.events,
+                &device.name,
+                &transition,
+            ));
+        }
+    }
+}
+
+fn find_device(control: &Control, name: &str) -> Result<usize, HttpError> {
+    control
+        .devices
+        .iter()
+        .position(|device| device.name == name)
+        .ok_or_else(|| HttpError::NotFound(format!("No device named '{name}'")))
+}
+
+fn parse_body<T: DeserializeOwned>(request: &Request) -> Result<T, HttpError> {
+    serde_json::from_str(&request.body).map_err(|err| {
+        HttpError::BadRequest(format!("Invalid JSON body: {err}"))
+    })
+}
+
+fn to_json(value: &impl Serialize) -> Reply {
+    Ok(Body::Json(
+        serde_json::to_string(value)
+            .expect("API replies are always valid JSON"),
+    ))
+}
+
+/// 256 random bits, hex encoded.
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,109 @@
+//! What `GET /api/status` and `GET /api/devices/{name}` answer with.
+
+use crate::alarm::AlarmState;
+use crate::devices::Device;
+use crate::sensors::SensorMap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub on_battery: bool,
+    pub devices: Vec<DeviceStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub name: String,
+    pub connection: Connection,
+    pub alarm: Alarm,
+    /// Every sensor of the map, from the last poll.
+    pub sensors: Vec<Sensor>,
+}
+
+impl DeviceStatus {
+    pub fn new(device: &Device, sensors: &SensorMap) -> Self {
+        let poll = &device.last_poll_result;
+
+        Self {
+            name: device.name.clone(),
+            connection: Connection::new(device),
+            alarm: Alarm::new(device.alarm.state()),
+            sensors: sensors
+                .sensors()
+                .iter()
+                .map(|def| Sensor {
+                    id: def.id.to_string(),
+                    name: def.name.clone(),
+                    group: def.group.clone(),
+                    active: poll.get(&def.id),
+                    value: poll.value(&def.id),
+                    unit: def
+                        .analog
+                        .as_ref()
+                        .map(|analog| analog.unit.clone()),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Connection {
+    /// `disconnected`, `connecting`, `connected` or `reconnecting`.
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// From the handshake, see [`crate::authorized::identity`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+}
+
+impl Connection {
+    fn new(device: &Device) -> Self {
+        let status = device.api.status();
+
+        Self {
+            state: status.name(),
+            target: status.target().map(str::to_owned),
+            identity: device
+                .api
+                .identity()
+                .map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alarm {
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<String>,
+    /// When a timer moves the state on by itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+impl Alarm {
+    fn new(state: &AlarmState) -> Self {
+        Self {
+            state: state.name(),
+            cause: state
+                .cause()
+                .map(|cause| cause.to_string()),
+            deadline: state.deadline(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sensor {
+    pub id: String,
+    pub name: String,
+    pub group: String,
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
@@ -0,0 +1,156 @@
+//! Home Assistant MQTT discovery: a retained config topic per entity, so
+//! the devices show up without any YAML.
+
+use crate::auth::dual_control::DualControlPolicy;
+use crate::config::MqttConfig;
+use crate::devices::Device;
+use crate::mqtt::{COMMANDS, slug, status_topic};
+use crate::sensors::SensorMap;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize)]
+struct Entity<'a> {
+    name: &'a str,
+    unique_id: String,
+    availability_topic: &'a str,
+    device: HaDevice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_press: Option<&'a str>,
+}
+
+/// Groups the entities in Home Assistant.
+#[derive(Debug, Clone, Serialize)]
+struct HaDevice {
+    identifiers: [String; 1],
+    name: String,
+    /// The controller a panel is reached through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via_device: Option<String>,
+}
+
+/// Config topics with their payloads. The controller is a Home Assistant
+/// device with the battery, each panel one with its sensors, alarm,
+/// connection and, if commands are taken, a button per command not under
+/// two-person control.
+pub fn configs(
+    config: &MqttConfig,
+    devices: &[(&Device, String)],
+    sensors: &SensorMap,
+    dual_control: &DualControlPolicy,
+) -> HashMap<String, String> {
+    let node = slug(&config.client_id);
+    let prefix = &config.topic_prefix;
+    let availability = status_topic(config);
+    let mut configs = HashMap::new();
+    let mut add = |component: &str, object_id: &str, entity: Entity| {
+        configs.insert(
+            format!(
+                "{}/{component}/{node}/{object_id}/config",
+                config.discovery_prefix
+            ),
+            serde_json::to_string(&entity)
+                .expect("Entities are always valid JSON"),
+        );
+    };
+    let entity = |name, object_id: &str, device| Entity {
+        name,
+        unique_id: format!("{node}_{object_id}"),
+        availability_topic: &availability,
+        device,
+        state_topic: None,
+        unit_of_measurement: None,
+        command_topic: None,
+        payload_press: None,
+    };
+
+    let controller = HaDevice {
+        identifiers: [node.clone()],
+        name: config.client_id.clone(),
+        via_device: None,
+    };
+    add(
+        "binary_sensor",
+        "on_battery",
+        Entity {
+            state_topic: Some(format!("{prefix}/battery")),
+            ..entity("On battery", "on_battery", controller)
+        },
+    );
+
+    for (device, slug) in devices {
+        let base = format!("{prefix}/{slug}");
+        let panel = HaDevice {
+            identifiers: [format!("{node}_{slug}")],
+            name: device.name.clone(),
+            via_device: Some(node.clone()),
+        };
+
+        for (object, name) in [("alarm", "Alarm"), ("connection", "Connection")]
+        {
+            let object_id = format!("{slug}_{object}");
+            add(
+                "sensor",
+                &object_id,
+                Entity {
+                    state_topic: Some(format!("{base}/{object}")),
+                    ..entity(name, &object_id, panel.clone())
+                },
+            );
+        }
+
+        for def in sensors.sensors() {
+            let topic = format!("{base}/sensor/{}", def.id);
+            let object_id = format!("{slug}_{}", def.id);
+            add(
+                "binary_sensor",
+                &object_id,
+                Entity {
+                    state_topic: Some(topic.clone()),
+                    ..entity(&def.name, &object_id, panel.clone())
+                },
+            );
+
+            if let Some(analog) = &def.analog {
+                let object_id = format!("{object_id}_value");
+                add(
+                    "sensor",
+                    &object_id,
+                    Entity {
+                        state_topic: Some(format!("{topic}/value")),
+                        unit_of_measurement: (!analog.unit.is_empty())
+                            .then_some(analog.unit.as_str()),
+                        ..entity(&def.name, &object_id, panel.clone())
+                    },
+                );
+            }
+        }
+
+        if config.command_role.is_some() {
+            for command in COMMANDS.iter().filter(|command| {
+                command
+                    .dual
+                    .is_none_or(|action| !dual_control.requires(action))
+            }) {
+                let object_id = format!("{slug}_{}", command.name);
+                add(
+                    "button",
+                    &object_id,
+                    Entity {
+                        command_topic: Some(format!("{base}/command")),
+                        payload_press: Some(command.name),
+                        ..entity(command.label, &object_id, panel.clone())
+                    },
+                );
+            }
+        }
+    }
+
+    configs
+}
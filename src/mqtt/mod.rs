@@ -0,0 +1,874 @@
+//! Bridge to an MQTT broker for home automation and SCADA tools. Sensors,
+//! alarms, connections and the battery go out as retained topics, door
+//! commands and resets come in on `<prefix>/<device>/command`. See the
+//! README for the topics.
+
+mod discovery;
+
+use crate::auth::dual_control::{DualAction, DualControlPolicy};
+use crate::auth::permissions::PermissionDenied;
+use crate::auth::users::Role;
+use crate::authorized::api::Api;
+use crate::config::MqttConfig;
+use crate::devices::Device;
+use crate::events::EventKind;
+use crate::events::log::EventLog;
+use crate::notice::Notice;
+use crate::sensors::SensorMap;
+use anyhow::Context;
+use rumqttc::{
+    Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// Commands from the broker are logged as this user.
+const USER: &str = "mqtt";
+/// Publishes waiting for the connection at most, enough for a first round
+/// of a few devices with discovery.
+const QUEUE_CAPACITY: usize = 1024;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait between connection attempts while the broker is away.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// What `<prefix>/<device>/command` takes as payload.
+#[derive(Debug, Clone, Copy)]
+struct DoorCommand {
+    name: &'static str,
+    label: &'static str,
+    send: fn(&Api, Role) -> anyhow::Result<()>,
+    /// Refused while under two-person control, nobody on a broker can
+    /// approve it.
+    dual: Option<DualAction>,
+}
+
+const COMMANDS: [DoorCommand; 5] = [
+    DoorCommand {
+        name: "lock_back_door",
+        label: "Lock back door",
+        send: Api::send_lock_back_door,
+        dual: None,
+    },
+    DoorCommand {
+        name: "lock_front_door",
+        label: "Lock front door",
+        send: Api::send_lock_front_door,
+        dual: None,
+    },
+    DoorCommand {
+        name: "unlock_back_door",
+        label: "Unlock back door",
+        send: Api::send_unlock_back_door,
+        dual: Some(DualAction::UnlockBackDoor),
+    },
+    DoorCommand {
+        name: "unlock_front_door",
+        label: "Unlock front door",
+        send: Api::send_unlock_front_door,
+        dual: Some(DualAction::UnlockFrontDoor),
+    },
+    DoorCommand {
+        name: "reset",
+        label: "Reset device",
+        send: Api::send_reset,
+        dual: Some(DualAction::Reset),
+    },
+];
+
+/// What the connection thread tells [`MqttBridge::update`].
+#[derive(Debug)]
+enum Update {
+    Connected,
+    Failed(String),
+    Message {
+        topic: String,
+        payload: Vec<u8>,
+        retained: bool,
+    },
+}
+
+/// The broker connection and what it was last sent.
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: Client,
+    updates: Receiver<Update>,
+    connected: bool,
+    /// Reported once, not on every retry.
+    error: Option<String>,
+    /// Retained payloads the broker has from us, by topic.
+    published: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for MqttBridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttBridge")
+            .field("broker", &self.config.broker)
+            .field("connected", &self.connected)
+            .field("published", &self.published.len())
+            .finish()
+    }
+}
+
+impl MqttBridge {
+    /// Connects in the background, an unreachable broker is reported by
+    /// [`Self::update`] and tried again.
+    pub fn start(config: &MqttConfig) -> anyhow::Result<Self> {
+        let (host, port) = config
+            .broker_address()
+            .with_context(|| {
+                format!(
+                    "'{}' is not an MQTT broker address",
+                    config
+                        .broker
+                        .as_deref()
+                        .unwrap_or_default()
+                )
+            })?;
+
+        let mut options = MqttOptions::new(&config.client_id, host, port);
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_last_will(LastWill::new(
+                status_topic(config),
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &config.username {
+            options.set_credentials(
+                username,
+                config
+                    .password
+                    .clone()
+                    .unwrap_or_default(),
+            );
+        }
+
+        let (client, connection) = Client::new(options, QUEUE_CAPACITY);
+        let (update_tx, update_rx) = mpsc::channel();
+        std::thread::spawn(move || run(connection, &update_tx));
+
+        Ok(Self {
+            config: config.clone(),
+            client,
+            updates: update_rx,
+            connected: false,
+            error: None,
+            published: HashMap::new(),
+        })
+    }
+
+    /// Runs the commands that came in and publishes what changed since the
+    /// last call. Call it regularly, the window does once per frame.
+    pub fn update(
+        &mut self,
+        devices: &[Device],
+        sensors: &SensorMap,
+        events: &mut EventLog,
+        dual_control: &DualControlPolicy,
+        on_battery: bool,
+    ) -> Vec<Notice> {
+        let broker = self
+            .config
+            .broker
+            .clone()
+            .unwrap_or_default();
+        let mut notices = Vec::new();
+
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                Update::Connected => {
+                    self.connected = true;
+                    self.error = None;
+                    // The broker may have restarted and lost what it
+                    // retained
+                    self.published.clear();
+                    notices.push(Notice::info(format!(
+                        "Connected to the MQTT broker at {broker}"
+                    )));
+                    notices.extend(self.subscribe());
+                    notices.extend(clashes(devices));
+                }
+                Update::Failed(err) => {
+                    self.connected = false;
+                    if self.error.as_ref() != Some(&err) {
+                        notices.push(Notice::warning(format!(
+                            "MQTT broker at {broker}: {err}"
+                        )));
+                        self.error = Some(err);
+                    }
+                }
+                // Replayed on every subscribe, running them again would
+                // unlock a door on each reconnect
+                Update::Message { retained: true, .. } => {}
+                Update::Message { topic, payload, .. } => {
+                    notices.extend(self.command(
+                        &topic,
+                        &payload,
+                        devices,
+                        events,
+                        dual_control,
+                    ));
+                }
+            }
+        }
+
+        if self.connected {
+            self.publish(
+                devices,
+                sensors,
+                dual_control,
+                on_battery,
+            );
+        }
+
+        notices
+    }
+
+    fn subscribe(&self) -> Option<Notice> {
+        self.config.command_role?;
+
+        let topic = format!("{}/+/command", self.config.topic_prefix);
+        self.client
+            .try_subscribe(&topic, QoS::AtLeastOnce)
+            .err()
+            .map(|err| {
+                Notice::error(format!(
+                    "Failed to subscribe to {topic}: {err}"
+                ))
+            })
+    }
+
+    /// Publishes topics whose payload changed and empties those of devices
+    /// that are gone. What does not fit in the queue is tried again next
+    /// time.
+    fn publish(
+        &mut self,
+        devices: &[Device],
+        sensors: &SensorMap,
+        dual_control: &DualControlPolicy,
+        on_battery: bool,
+    ) {
+        let bridged = bridged(devices);
+        let mut wanted = states(
+            &self.config,
+            &bridged,
+            sensors,
+            on_battery,
+        );
+        if self.config.discovery {
+            wanted.extend(discovery::configs(
+                &self.config,
+                &bridged,
+                sensors,
+                dual_control,
+            ));
+        }
+
+        for (topic, payload) in &wanted {
+            if self.published.get(topic) != Some(payload)
+                && self.send(topic, payload)
+            {
+                self.published
+                    .insert(topic.clone(), payload.clone());
+            }
+        }
+
+        let gone: Vec<String> = self
+            .published
+            .keys()
+            .filter(|topic| !wanted.contains_key(*topic))
+            .cloned()
+            .collect();
+        for topic in gone {
+            // An empty retained message deletes the topic
+            if self.send(&topic, "") {
+                self.published.remove(&topic);
+            }
+        }
+    }
+
+    fn send(&self, topic: &str, payload: &str) -> bool {
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+            .is_ok()
+    }
+
+    fn command(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        devices: &[Device],
+        events: &mut EventLog,
+        dual_control: &DualControlPolicy,
+    ) -> Option<Notice> {
+        let role = self.config.command_role?;
+        let slug = topic
+            .strip_prefix(&self.config.topic_prefix)?
+            .strip_prefix('/')?
+            .strip_suffix("/command")?;
+        let Some((device, _)) = bridged(devices)
+            .into_iter()
+            .find(|(_, bridged)| bridged == slug)
+        else {
+            return Some(Notice::warning(format!(
+                "MQTT: no device for {topic}"
+            )));
+        };
+
+        let payload = String::from_utf8_lossy(payload);
+        let Some(command) = COMMANDS
+            .iter()
+            .find(|command| command.name == payload.trim())
+        else {
+            return Some(Notice::warning(format!(
+                "{}: unknown MQTT command '{payload}'",
+                device.name
+            )));
+        };
+
+        if let Some(action) = command.dual
+            && dual_control.requires(action)
+        {
+            return Some(Notice::warning(format!(
+                "{}: {} from MQTT refused, it needs a second operator",
+                device.name, command.name
+            )));
+        }
+
+        match (command.send)(&device.api, role) {
+            Ok(()) => {
+                events.record_device(
+                    &device.name,
+                    EventKind::Command {
+                        user: USER.to_owned(),
+                        command: command.name.to_owned(),
+                        approved_by: None,
+                    },
+                );
+                None
+            }
+            Err(err) => match err.downcast_ref::<PermissionDenied>() {
+                Some(denied) => {
+                    events.record(EventKind::AccessDenied {
+                        user: USER.to_owned(),
+                        role: denied.role,
+                        action: command.name.to_owned(),
+                    });
+                    Some(Notice::warning(format!(
+                        "{}: {} from MQTT refused: {denied}",
+                        device.name, command.name
+                    )))
+                }
+                None => Some(Notice::warning(format!(
+                    "{}: {} from MQTT failed: {err:#}",
+                    device.name, command.name
+                ))),
+            },
+        }
+    }
+}
+
+/// Starts, restarts or stops the bridge to match `config`.
+pub fn configure(
+    bridge: &mut Option<MqttBridge>,
+    config: &MqttConfig,
+) -> anyhow::Result<()> {
+    let wanted = config
+        .broker
+        .is_some()
+        .then_some(config);
+    if bridge
+        .as_ref()
+        .map(|bridge| &bridge.config)
+        == wanted
+    {
+        return Ok(());
+    }
+
+    *bridge = None;
+    if let Some(config) = wanted {
+        *bridge = Some(MqttBridge::start(config)?);
+    }
+
+    Ok(())
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        // The last will only covers going away without saying goodbye
+        self.send(&status_topic(&self.config), "offline");
+        let _ = self.client.try_disconnect();
+    }
+}
+
+/// Runs on its own thread until the bridge disconnects or is gone.
+fn run(mut connection: Connection, updates: &Sender<Update>) {
+    for event in connection.iter() {
+        let update = match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => Update::Connected,
+            Ok(Event::Incoming(Packet::Publish(publish))) => Update::Message {
+                topic: publish.topic,
+                payload: publish.payload.to_vec(),
+                retained: publish.retain,
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => continue,
+            Err(err) => {
+                if updates
+                    .send(Update::Failed(err.to_string()))
+                    .is_err()
+                {
+                    return;
+                }
+                // The next event is the next attempt
+                std::thread::sleep(RETRY_DELAY);
+                continue;
+            }
+        };
+
+        if updates.send(update).is_err() {
+            return;
+        }
+    }
+}
+
+/// `online` while connected, `offline` otherwise.
+fn status_topic(config: &MqttConfig) -> String {
+    format!("{}/status", config.topic_prefix)
+}
+
+/// A name as a topic level and Home Assistant id: `Server room` is
+/// `server_room`. Letters outside ASCII become their code point in hex.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '-' => c.to_string(),
+            'A'..='Z' => c.to_ascii_lowercase().to_string(),
+            c if c.is_ascii() => "_".to_owned(),
+            c => format!("{:x}", c as u32),
+        })
+        .collect()
+}
+
+/// Devices with their [`slug`], the second of two with the same one is left
+/// out.
+fn bridged(devices: &[Device]) -> Vec<(&Device, String)> {
+    let mut seen = HashSet::new();
+    devices
+        .iter()
+        .map(|device| (device, slug(&device.name)))
+        .filter(|(_, slug)| seen.insert(slug.clone()))
+        .collect()
+}
+
+fn clashes(devices: &[Device]) -> Vec<Notice> {
+    let bridged = bridged(devices);
+    devices
+        .iter()
+        .filter(|device| {
+            !bridged
+                .iter()
+                .any(|(bridged, _)| std::ptr::eq(*bridged, *device))
+        })
+        .map(|device| {
+            Notice::warning(format!(
+                "{}: not on MQTT, another device has the topic {}",
+                device.name,
+                slug(&device.name)
+            ))
+        })
+        .collect()
+}
+
+fn on_off(active: bool) -> String {
+    if active { "ON" } else { "OFF" }.to_owned()
+}
+
+fn states(
+    config: &MqttConfig,
+    devices: &[(&Device, String)],
+    sensors: &SensorMap,
+    on_battery: bool,
+) -> HashMap<String, String> {
+    let prefix = &config.topic_prefix;
+    let mut topics = HashMap::from([
+        (
+            status_topic(config),
+            "online".to_owned(),
+        ),
+        (
+            format!("{prefix}/battery"),
+            on_off(on_battery),
+        ),
+    ]);
+
+    for (device, slug) in devices {
+        let base = format!("{prefix}/{slug}");
+        topics.insert(
+            format!("{base}/connection"),
+            device.api.status().name().to_owned(),
+        );
+        topics.insert(
+            format!("{base}/alarm"),
+            device.alarm.state().name().to_owned(),
+        );
+
+        let poll = &device.last_poll_result;
+        for def in sensors.sensors() {
+            let topic = format!("{base}/sensor/{}", def.id);
+            if let Some(value) = poll.value(&def.id) {
+                topics.insert(
+                    format!("{topic}/value"),
+                    value.to_string(),
+                );
+            }
+            topics.insert(topic, on_off(poll.get(&def.id)));
+        }
+    }
+
+    topics
+}
+
+/// Most of these need a broker, run them with a local mosquitto and
+/// `cargo test -- --ignored`. `MQTT_TEST_BROKER` points them elsewhere.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::devices::{DeviceEntry, Endpoint};
+    use crate::events::Event as Recorded;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    /// A simulated device called `Lab` with topics of its own.
+    struct Rig {
+        config: MqttConfig,
+        sensors: Arc<SensorMap>,
+        devices: Vec<Device>,
+        events: EventLog,
+        recorded: Receiver<Recorded>,
+        dual_control: DualControlPolicy,
+        /// The event log, removed with the rig.
+        _dir: TempDir,
+    }
+
+    impl Rig {
+        fn new(test: &str) -> Self {
+            let id = format!("{test}_{}", std::process::id());
+            let sensors = Arc::new(SensorMap::default());
+            let entry = DeviceEntry {
+                name: "Lab".to_owned(),
+                endpoint: Some(Endpoint::Simulator),
+                protocol: Default::default(),
+            };
+            let dir = tempfile::tempdir().unwrap();
+            let mut events = EventLog::open(dir.path()).unwrap();
+
+            Self {
+                config: MqttConfig {
+                    broker: Some(
+                        std::env::var("MQTT_TEST_BROKER")
+                            .unwrap_or_else(|_| "127.0.0.1:1883".to_owned()),
+                    ),
+                    client_id: id.clone(),
+                    topic_prefix: format!("test/{id}"),
+                    discovery_prefix: format!("test/{id}/discovery"),
+                    command_role: Some(Role::Supervisor),
+                    ..MqttConfig::default()
+                },
+                devices: vec![Device::start(
+                    entry,
+                    &Config::default(),
+                    &sensors,
+                )],
+                sensors,
+                recorded: events.subscribe(),
+                events,
+                dual_control: DualControlPolicy {
+                    actions: vec![DualAction::UnlockFrontDoor],
+                    window_secs: 60,
+                },
+                _dir: dir,
+            }
+        }
+
+        /// Updates the devices and the bridge until `done` says so.
+        fn run(
+            &mut self,
+            bridge: &mut MqttBridge,
+            mut done: impl FnMut(&Self, &MqttBridge, &[Notice]) -> bool,
+        ) {
+            let deadline = Instant::now() + WAIT;
+            let mut notices = Vec::new();
+
+            while Instant::now() < deadline {
+                for device in &mut self.devices {
+                    device.update(
+                        &self.sensors,
+                        &mut self.events,
+                        chrono::Utc::now(),
+                    );
+                }
+                notices.extend(bridge.update(
+                    &self.devices,
+                    &self.sensors,
+                    &mut self.events,
+                    &self.dual_control,
+                    false,
+                ));
+
+                if done(self, bridge, &notices) {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+
+            panic!("Gave up waiting, got {notices:?}");
+        }
+
+        /// Until the broker has heard the device is connected.
+        fn start(&mut self, bridge: &mut MqttBridge) {
+            let topic = self.topic("lab/connection");
+            self.run(bridge, |_, bridge, _| {
+                bridge
+                    .published
+                    .get(&topic)
+                    .is_some_and(|state| state == "connected")
+            });
+        }
+
+        /// Commands logged since the last call.
+        fn commands(&self) -> Vec<String> {
+            self.recorded
+                .try_iter()
+                .filter_map(|event| match event.kind {
+                    EventKind::Command { user, command, .. } => {
+                        assert_eq!(user, USER);
+                        Some(command)
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn topic(&self, rest: &str) -> String {
+            format!("{}/{rest}", self.config.topic_prefix)
+        }
+    }
+
+    /// Another client of the broker, with what it received by topic.
+    struct Watcher {
+        client: Client,
+        messages: Receiver<(String, String)>,
+        seen: HashMap<String, String>,
+    }
+
+    impl Watcher {
+        fn new(rig: &Rig) -> Self {
+            let (host, port) = rig.config.broker_address().unwrap();
+            let options = MqttOptions::new(
+                format!("{}_watcher", rig.config.client_id),
+                host,
+                port,
+            );
+            let (client, mut connection) = Client::new(options, 16);
+            client
+                .subscribe(rig.topic("#"), QoS::AtLeastOnce)
+                .unwrap();
+
+            let (message_tx, message_rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                for event in connection.iter() {
+                    let Ok(Event::Incoming(Packet::Publish(publish))) = event
+                    else {
+                        continue;
+                    };
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    if message_tx
+                        .send((publish.topic, payload.into_owned()))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+
+            Self {
+                client,
+                messages: message_rx,
+                seen: HashMap::new(),
+            }
+        }
+
+        /// The payload of `topic` once `done` is happy with it.
+        fn wait(&mut self, topic: &str, done: impl Fn(&str) -> bool) -> &str {
+            let deadline = Instant::now() + WAIT;
+            while !self
+                .seen
+                .get(topic)
+                .is_some_and(|payload| done(payload))
+            {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match self.messages.recv_timeout(left) {
+                    Ok((topic, payload)) => {
+                        self.seen.insert(topic, payload);
+                    }
+                    Err(_) => panic!(
+                        "Nothing on {topic}, got {:?}",
+                        self.seen
+                    ),
+                }
+            }
+
+            &self.seen[topic]
+        }
+
+        fn publish(&self, topic: &str, payload: &str, retain: bool) {
+            self.client
+                .publish(topic, QoS::AtLeastOnce, retain, payload)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn slugs_are_single_topic_levels() {
+        assert_eq!(slug("Server room"), "server_room");
+        assert_eq!(slug("Gate/2 #1"), "gate_2__1");
+        assert_eq!(slug("Склад"), "42143a43b430434");
+    }
+
+    #[test]
+    #[ignore = "needs an MQTT broker"]
+    fn publishes_retained_states_and_discovery() {
+        let mut rig = Rig::new("states");
+        let mut bridge = MqttBridge::start(&rig.config).unwrap();
+        rig.start(&mut bridge);
+
+        // Subscribed after the fact, so all of it was retained
+        let mut watcher = Watcher::new(&rig);
+        watcher.wait(&rig.topic("status"), |state| {
+            state == "online"
+        });
+        watcher.wait(&rig.topic("lab/connection"), |state| {
+            state == "connected"
+        });
+        watcher.wait(&rig.topic("lab/alarm"), |state| {
+            state == "Armed"
+        });
+        watcher.wait(
+            &rig.topic("lab/sensor/front_door"),
+            |state| state == "OFF",
+        );
+        watcher.wait(
+            &rig.topic("lab/sensor/door_reed_voltage/value"),
+            |value| value.parse::<f64>().is_ok(),
+        );
+
+        let node = slug(&rig.config.client_id);
+        let discovery = |entity: &str| {
+            format!(
+                "{}/{entity}/config",
+                rig.config.discovery_prefix
+            )
+        };
+        let sensor = watcher.wait(
+            &discovery(&format!(
+                "binary_sensor/{node}/lab_front_door"
+            )),
+            |_| true,
+        );
+        assert!(sensor.contains(&rig.topic("lab/sensor/front_door")));
+        let button = watcher.wait(
+            &discovery(&format!(
+                "button/{node}/lab_lock_front_door"
+            )),
+            |_| true,
+        );
+        assert!(button.contains(&rig.topic("lab/command")));
+        // Under two-person control, pressing it would only be refused
+        assert!(
+            !watcher
+                .seen
+                .contains_key(&discovery(&format!(
+                    "button/{node}/lab_unlock_front_door"
+                )))
+        );
+
+        drop(bridge);
+        watcher.wait(&rig.topic("status"), |state| {
+            state == "offline"
+        });
+    }
+
+    #[test]
+    #[ignore = "needs an MQTT broker"]
+    fn runs_commands_unless_they_need_a_second_operator() {
+        let mut rig = Rig::new("commands");
+        let mut bridge = MqttBridge::start(&rig.config).unwrap();
+        rig.start(&mut bridge);
+
+        let watcher = Watcher::new(&rig);
+        let topic = rig.topic("lab/command");
+        watcher.publish(&topic, "unlock_front_door", false);
+        watcher.publish(&topic, "unlock_back_door", false);
+
+        let mut commands = Vec::new();
+        rig.run(&mut bridge, |rig, _, notices| {
+            commands.extend(rig.commands());
+            !commands.is_empty()
+                && notices.iter().any(|notice| {
+                    notice
+                        .message
+                        .contains("needs a second operator")
+                })
+        });
+        assert_eq!(commands, ["unlock_back_door"]);
+    }
+
+    #[test]
+    #[ignore = "needs an MQTT broker"]
+    fn ignores_retained_commands() {
+        let mut rig = Rig::new("retained");
+        let mut watcher = Watcher::new(&rig);
+        let topic = rig.topic("lab/command");
+        watcher.publish(&topic, "lock_back_door", true);
+
+        let mut bridge = MqttBridge::start(&rig.config).unwrap();
+        rig.start(&mut bridge);
+        let started = Instant::now();
+        rig.run(&mut bridge, |_, _, _| {
+            started.elapsed() > Duration::from_secs(1)
+        });
+        assert!(rig.commands().is_empty());
+
+        // An empty retained message clears it again
+        watcher.publish(&topic, "", true);
+        watcher.wait(&topic, str::is_empty);
+    }
+
+    #[test]
+    #[ignore = "needs an MQTT broker"]
+    fn empties_the_topics_of_removed_devices() {
+        let mut rig = Rig::new("removed");
+        let mut bridge = MqttBridge::start(&rig.config).unwrap();
+        rig.start(&mut bridge);
+        let mut watcher = Watcher::new(&rig);
+        watcher.wait(&rig.topic("lab/alarm"), |state| {
+            state == "Armed"
+        });
+
+        rig.devices.clear();
+        rig.run(&mut bridge, |_, bridge, _| {
+            bridge
+                .published
+                .keys()
+                .all(|topic| !topic.contains("/lab"))
+        });
+        watcher.wait(&rig.topic("lab/alarm"), str::is_empty);
+    }
+}
@@ -0,0 +1,693 @@
+//! Notifications for people away from the window: email, webhooks and
+//! local programs on alarms, chosen sensors, battery, lost devices and
+//! duress. Set up in `data/notifications.toml`, see the README.
+
+mod sinks;
+mod template;
+
+use crate::auth::duress::DuressPolicy;
+use crate::authorized::api::ConnectionChange;
+use crate::events::log::EventLog;
+use crate::events::{Event, EventKind};
+use crate::notice::Notice;
+use crate::notify::sinks::{EmailSink, ExecSink, Sink, WebhookSink};
+use crate::sensors::{SensorId, SensorMap};
+use anyhow::{Context, bail};
+use chrono::{SecondsFormat, Utc};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// What a sink can be set up to be told about, the `on` list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    AlarmTriggered,
+    /// One of the sink's `sensors` became active.
+    #[serde(skip)]
+    Sensor,
+    OnBattery,
+    /// A connected device lost its link, not an operator disconnect.
+    Disconnected,
+    Duress,
+}
+
+impl Trigger {
+    pub fn name(self) -> &'static str {
+        match self {
+            Trigger::AlarmTriggered => "alarm_triggered",
+            Trigger::Sensor => "sensor",
+            Trigger::OnBattery => "on_battery",
+            Trigger::Disconnected => "disconnected",
+            Trigger::Duress => "duress",
+        }
+    }
+}
+
+/// An event worth telling someone about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub trigger: Trigger,
+    /// e.g. `Alarm triggered: Lab`.
+    pub title: String,
+    pub event: Event,
+}
+
+impl Message {
+    fn new(trigger: Trigger, event: Event, sensors: &SensorMap) -> Self {
+        let label = match (&event.kind, trigger) {
+            (EventKind::Sensor { sensor, .. }, _) => {
+                let name = sensors
+                    .find(sensor)
+                    .map_or(sensor.as_str(), |def| &def.name);
+                format!("{name} active")
+            }
+            (_, Trigger::AlarmTriggered) => "Alarm triggered".to_owned(),
+            (_, Trigger::OnBattery) => "Running on battery".to_owned(),
+            (_, Trigger::Disconnected) => "Connection lost".to_owned(),
+            _ => "Duress".to_owned(),
+        };
+        let title = match &event.device {
+            Some(device) => format!("{label}: {device}"),
+            None => label,
+        };
+
+        Self { trigger, title, event }
+    }
+
+    /// The sensor of a [`Trigger::Sensor`] message.
+    pub fn sensor(&self) -> Option<&SensorId> {
+        match &self.event.kind {
+            EventKind::Sensor { sensor, .. } => Some(sensor),
+            _ => None,
+        }
+    }
+
+    /// Value of a template placeholder, see [`template::FIELDS`].
+    pub fn field(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "trigger" => self.trigger.name().to_owned(),
+            "title" => self.title.clone(),
+            "device" => self
+                .event
+                .device
+                .clone()
+                .unwrap_or_default(),
+            "description" => self.event.kind.to_string(),
+            "time" => self
+                .event
+                .timestamp
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            "event" => serde_json::to_string(&self.event)
+                .expect("Events are always valid JSON"),
+            _ => return None,
+        })
+    }
+
+    /// What a hook gets, the duress ones keep their old names.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env: Vec<_> = template::FIELDS
+            .iter()
+            .filter_map(|name| {
+                let value = self.field(name)?;
+                Some((
+                    format!("CONTROL_{}", name.to_uppercase()),
+                    value,
+                ))
+            })
+            .collect();
+        if let EventKind::Duress { user, action } = &self.event.kind {
+            env.push((
+                "CONTROL_DURESS_USER".to_owned(),
+                user.clone(),
+            ));
+            env.push((
+                "CONTROL_DURESS_ACTION".to_owned(),
+                action.clone(),
+            ));
+        }
+
+        env
+    }
+}
+
+/// Who gets told about what, `data/notifications.toml`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationPolicy {
+    /// Seconds to wait before each retry of a failed delivery, the
+    /// delivery is given up after the last one.
+    pub retry_secs: Vec<u64>,
+    pub email: Vec<EmailSink>,
+    pub webhook: Vec<WebhookSink>,
+    pub exec: Vec<ExecSink>,
+}
+
+impl Default for NotificationPolicy {
+    fn default() -> Self {
+        Self {
+            retry_secs: vec![10, 60, 300],
+            email: Vec::new(),
+            webhook: Vec::new(),
+            exec: Vec::new(),
+        }
+    }
+}
+
+impl NotificationPolicy {
+    /// Nobody is notified when the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy: Self = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).with_context(|| {
+                format!("Failed to parse {}", path.display())
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        policy
+            .validate()
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(policy)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let sample = Message::new(
+            Trigger::AlarmTriggered,
+            Event {
+                id: 1,
+                timestamp: Utc::now(),
+                device: Some("Sample".to_owned()),
+                kind: EventKind::Alarm {
+                    from: "Armed".to_owned(),
+                    to: "Triggered".to_owned(),
+                    reason: "sample".to_owned(),
+                },
+            },
+            &SensorMap::default(),
+        );
+        for sink in self.sinks() {
+            let kind = sink.kind();
+            let (on, sensors) = sink.when();
+            if on.is_empty() && sensors.is_empty() {
+                bail!("{kind} {sink} has neither on nor sensors");
+            }
+            sink.validate(&sample)
+                .with_context(|| format!("{kind} {sink}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// The hooks of `data/duress.json` from before this file, they run on
+    /// duress like an `[[exec]]` with `on = ["duress"]`.
+    pub fn add_duress_hooks(&mut self, duress: DuressPolicy) {
+        self.exec.extend(
+            duress
+                .hooks
+                .into_iter()
+                .map(|command| ExecSink {
+                    on: vec![Trigger::Duress],
+                    sensors: Vec::new(),
+                    command,
+                }),
+        );
+    }
+
+    fn sinks(&self) -> Vec<Sink> {
+        self.email
+            .iter()
+            .cloned()
+            .map(Sink::Email)
+            .chain(
+                self.webhook
+                    .iter()
+                    .cloned()
+                    .map(Sink::Webhook),
+            )
+            .chain(
+                self.exec
+                    .iter()
+                    .cloned()
+                    .map(Sink::Exec),
+            )
+            .collect()
+    }
+}
+
+/// How one delivery attempt went.
+#[derive(Debug)]
+struct Report {
+    sink: String,
+    title: String,
+    /// Duress, only stderr may hear of it.
+    hidden: bool,
+    attempt: usize,
+    result: Result<(), String>,
+    /// `None` once it was the last attempt.
+    retry_in: Option<Duration>,
+}
+
+/// Watches the event log and hands each notification to a thread of its
+/// own, so a slow mail server holds up neither the window nor the others.
+pub struct Notifier {
+    sinks: Vec<Arc<Sink>>,
+    retry_delays: Arc<[Duration]>,
+    events: Receiver<Event>,
+    /// Devices with a link up, so a lost link is reported once and an
+    /// operator's disconnect not at all.
+    connected: HashSet<Option<String>>,
+    report_sender: Sender<Report>,
+    reports: Receiver<Report>,
+}
+
+impl Notifier {
+    pub fn new(policy: &NotificationPolicy, events: &mut EventLog) -> Self {
+        let (report_sender, reports) = mpsc::channel();
+
+        Self {
+            sinks: policy
+                .sinks()
+                .into_iter()
+                .map(Arc::new)
+                .collect(),
+            retry_delays: policy
+                .retry_secs
+                .iter()
+                .map(|secs| Duration::from_secs(*secs))
+                .collect(),
+            events: events.subscribe(),
+            connected: HashSet::new(),
+            report_sender,
+            reports,
+        }
+    }
+
+    /// Sends what happened since the last call and reports how earlier
+    /// deliveries went.
+    pub fn update(&mut self, sensors: &SensorMap) -> Vec<Notice> {
+        let events: Vec<Event> = self.events.try_iter().collect();
+        for event in events {
+            let Some(trigger) = self.trigger(&event) else {
+                continue;
+            };
+            let message = Arc::new(Message::new(trigger, event, sensors));
+
+            for sink in self
+                .sinks
+                .iter()
+                .filter(|sink| sink.wants(&message))
+            {
+                let sink = sink.clone();
+                let message = message.clone();
+                let delays = self.retry_delays.clone();
+                let reports = self.report_sender.clone();
+                std::thread::spawn(move || {
+                    deliver(&sink, &message, &delays, &reports)
+                });
+            }
+        }
+
+        let mut notices = Vec::new();
+        for report in self.reports.try_iter() {
+            let notice = match (&report.result, report.retry_in) {
+                (Ok(()), _) if report.attempt == 1 => Notice::info(format!(
+                    "Notified {}: {}",
+                    report.sink, report.title
+                )),
+                (Ok(()), _) => Notice::info(format!(
+                    "Notified {}: {} (attempt {})",
+                    report.sink, report.title, report.attempt
+                )),
+                (Err(err), Some(delay)) => Notice::warning(format!(
+                    "Failed to notify {} of '{}', retrying in {} s: {err}",
+                    report.sink,
+                    report.title,
+                    delay.as_secs()
+                )),
+                (Err(err), None) => Notice::error(format!(
+                    "Gave up notifying {} of '{}' after {} attempt(s): {err}",
+                    report.sink, report.title, report.attempt
+                )),
+            };
+
+            // The screen and the journal stay calm under duress
+            if !report.hidden {
+                notices.push(notice);
+            } else if report.result.is_err() {
+                eprintln!("{}", notice.message);
+            }
+        }
+
+        notices
+    }
+
+    fn trigger(&mut self, event: &Event) -> Option<Trigger> {
+        match &event.kind {
+            // `to` is the state with its cause, e.g. `Triggered (fire)`
+            EventKind::Alarm { to, .. }
+                if to == "Triggered" || to.starts_with("Triggered (") =>
+            {
+                Some(Trigger::AlarmTriggered)
+            }
+            EventKind::Sensor { active: true, .. } => Some(Trigger::Sensor),
+            EventKind::Battery { on_battery: true } => Some(Trigger::OnBattery),
+            EventKind::Connection(ConnectionChange::Connected { .. }) => {
+                self.connected
+                    .insert(event.device.clone());
+                None
+            }
+            EventKind::Connection(ConnectionChange::Reconnecting {
+                ..
+            }) => self
+                .connected
+                .remove(&event.device)
+                .then_some(Trigger::Disconnected),
+            EventKind::Connection(_) => {
+                self.connected.remove(&event.device);
+                None
+            }
+            EventKind::Duress { .. } => Some(Trigger::Duress),
+            _ => None,
+        }
+    }
+}
+
+/// Tries until it works or the delays run out, reporting every attempt.
+fn deliver(
+    sink: &Sink,
+    message: &Message,
+    delays: &[Duration],
+    reports: &Sender<Report>,
+) {
+    for attempt in 1.. {
+        let result = sink
+            .deliver(message)
+            .map_err(|err| format!("{err:#}"));
+        let retry_in = match result {
+            Ok(()) => None,
+            Err(_) => delays.get(attempt - 1).copied(),
+        };
+
+        let report = Report {
+            sink: sink.to_string(),
+            title: message.title.clone(),
+            hidden: message.event.kind.is_hidden(),
+            attempt,
+            result,
+            retry_in,
+        };
+        // The notifier is gone, nobody is left to tell
+        if reports.send(report).is_err() {
+            return;
+        }
+
+        match retry_in {
+            Some(delay) => std::thread::sleep(delay),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notice::Level;
+    use crate::notify::sinks::Security;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    fn alarm(device: &str) -> Event {
+        Event {
+            id: 1,
+            timestamp: Utc::now(),
+            device: Some(device.to_owned()),
+            kind: EventKind::Alarm {
+                from: "Armed".to_owned(),
+                to: "Triggered (fire)".to_owned(),
+                reason: "sensor".to_owned(),
+            },
+        }
+    }
+
+    fn exec(on: Vec<Trigger>, sensors: &[&str], command: &[&str]) -> ExecSink {
+        ExecSink {
+            on,
+            sensors: sensors
+                .iter()
+                .map(|id| SensorId::new(*id))
+                .collect(),
+            command: command
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+        }
+    }
+
+    /// Updates the notifier until `count` notices came in.
+    fn notices(notifier: &mut Notifier, count: usize) -> Vec<Notice> {
+        let sensors = SensorMap::default();
+        let started = Instant::now();
+        let mut notices = Vec::new();
+        while notices.len() < count {
+            assert!(
+                started.elapsed() < WAIT,
+                "Only got {notices:?}"
+            );
+            notices.extend(notifier.update(&sensors));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        notices
+    }
+
+    #[test]
+    fn webhooks_post_the_template_as_json() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let sink = Sink::Webhook(WebhookSink {
+            on: vec![Trigger::AlarmTriggered],
+            sensors: Vec::new(),
+            url: format!("http://{}/hook", server.server_addr()),
+            headers: [("X-Token".to_owned(), "abc".to_owned())].into(),
+            body:
+                r#"{"text": "{{title}}, \"{{device}}\"", "event": {{event}}}"#
+                    .to_owned(),
+        });
+        let message = Message::new(
+            Trigger::AlarmTriggered,
+            alarm("Lab \"east\""),
+            &SensorMap::default(),
+        );
+        sink.validate(&message).unwrap();
+
+        let delivery = std::thread::spawn(move || sink.deliver(&message));
+        let mut request = server
+            .recv_timeout(WAIT)
+            .unwrap()
+            .unwrap();
+        let token = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("X-Token"))
+            .map(|header| header.value.to_string());
+        let mut body = String::new();
+        request
+            .as_reader()
+            .read_to_string(&mut body)
+            .unwrap();
+        request
+            .respond(tiny_http::Response::empty(204))
+            .unwrap();
+        delivery.join().unwrap().unwrap();
+
+        assert_eq!(token.as_deref(), Some("abc"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body["text"],
+            "Alarm triggered: Lab \"east\", \"Lab \"east\"\""
+        );
+        assert_eq!(body["event"]["to"], "Triggered (fire)");
+    }
+
+    #[test]
+    fn emails_go_through_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener
+            .local_addr()
+            .unwrap()
+            .to_string();
+        // Just enough of a mail server to take one message
+        let stand_in = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer
+                .write_all(b"220 stand-in\r\n")
+                .unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return data;
+                }
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        data.push_str(&line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go on\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer
+                        .write_all(b"221 bye\r\n")
+                        .unwrap();
+                    return data;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+        });
+
+        let sink = Sink::Email(EmailSink {
+            on: vec![Trigger::AlarmTriggered],
+            sensors: Vec::new(),
+            server,
+            security: Security::None,
+            username: None,
+            password: None,
+            from: "Control <control@example.com>".to_owned(),
+            to: vec!["ops@example.com".to_owned()],
+            subject: "[control] {{title}}".to_owned(),
+            body: "{{description}} at {{device}}".to_owned(),
+        });
+        let message = Message::new(
+            Trigger::AlarmTriggered,
+            alarm("Lab"),
+            &SensorMap::default(),
+        );
+        sink.validate(&message).unwrap();
+        sink.deliver(&message).unwrap();
+
+        let mail = stand_in.join().unwrap();
+        assert!(mail.contains("Subject: [control] Alarm triggered: Lab\r\n"));
+        assert!(mail.contains("To: ops@example.com\r\n"));
+        assert!(
+            mail.contains("Alarm Armed -> Triggered (fire) (sensor) at Lab")
+        );
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_then_given_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut events = EventLog::open(dir.path()).unwrap();
+        let policy = NotificationPolicy {
+            retry_secs: vec![0, 0],
+            exec: vec![exec(
+                vec![Trigger::OnBattery],
+                &[],
+                &["sh", "-c", "exit 3"],
+            )],
+            ..NotificationPolicy::default()
+        };
+        let mut notifier = Notifier::new(&policy, &mut events);
+
+        events.record(EventKind::Battery { on_battery: true });
+        let notices = notices(&mut notifier, 3);
+
+        let levels: Vec<Level> = notices
+            .iter()
+            .map(|notice| notice.level)
+            .collect();
+        assert_eq!(
+            levels,
+            [Level::Warning, Level::Warning, Level::Error]
+        );
+        assert!(notices[2].message.starts_with(
+            "Gave up notifying sh of 'Running on battery' after 3 attempt(s)"
+        ));
+    }
+
+    #[test]
+    fn lost_links_and_listed_sensors_are_sent_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut events = EventLog::open(dir.path()).unwrap();
+        let policy = NotificationPolicy {
+            exec: vec![exec(
+                vec![Trigger::Disconnected],
+                &["fire"],
+                &["true"],
+            )],
+            ..NotificationPolicy::default()
+        };
+        let mut notifier = Notifier::new(&policy, &mut events);
+
+        let connected = ConnectionChange::Connected {
+            target: "loopback".to_owned(),
+            identity: None,
+        };
+        let reconnecting = |attempt| ConnectionChange::Reconnecting {
+            target: "loopback".to_owned(),
+            attempt,
+        };
+        for kind in [
+            // An operator's disconnect is no news
+            EventKind::Connection(connected.clone()),
+            EventKind::Connection(ConnectionChange::Disconnected),
+            EventKind::Connection(connected),
+            EventKind::Connection(reconnecting(2)),
+            EventKind::Connection(reconnecting(3)),
+            EventKind::Sensor {
+                sensor: SensorId::new("front_door"),
+                active: true,
+            },
+            EventKind::Sensor {
+                sensor: SensorId::new("fire"),
+                active: true,
+            },
+            EventKind::Sensor {
+                sensor: SensorId::new("fire"),
+                active: false,
+            },
+        ] {
+            events.record_device("Lab", kind);
+        }
+        let mut notices = notices(&mut notifier, 2);
+        std::thread::sleep(Duration::from_millis(200));
+        notices.extend(notifier.update(&SensorMap::default()));
+
+        let fire = &SensorMap::default()
+            .find(&SensorId::new("fire"))
+            .unwrap()
+            .name
+            .clone();
+        let mut messages: Vec<String> = notices
+            .into_iter()
+            .map(|notice| notice.message)
+            .collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "Notified true: Connection lost: Lab".to_owned(),
+                format!("Notified true: {fire} active: Lab"),
+            ]
+        );
+    }
+}
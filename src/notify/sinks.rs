@@ -0,0 +1,288 @@
+//! Where notifications go: SMTP, HTTP webhooks and local programs.
+
+use crate::notify::template::render;
+use crate::notify::{Message, Trigger};
+use crate::sensors::SensorId;
+use anyhow::{Context, anyhow, bail};
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Longest a mail server or webhook may take for one attempt.
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    /// Plain text, only for a relay on the same machine.
+    None,
+    #[default]
+    Starttls,
+    /// TLS from the first byte, usually port 465.
+    Tls,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailSink {
+    #[serde(default)]
+    pub on: Vec<Trigger>,
+    /// Sensors whose activation is sent, e.g. `fire`.
+    #[serde(default)]
+    pub sensors: Vec<SensorId>,
+    /// `host:port` of the mail server.
+    pub server: String,
+    #[serde(default)]
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    #[serde(default = "default_text")]
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSink {
+    #[serde(default)]
+    pub on: Vec<Trigger>,
+    #[serde(default)]
+    pub sensors: Vec<SensorId>,
+    pub url: String,
+    /// Sent with every request, e.g. `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// JSON posted to the URL, placeholders are escaped to fit in strings.
+    #[serde(default = "default_payload")]
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecSink {
+    #[serde(default)]
+    pub on: Vec<Trigger>,
+    #[serde(default)]
+    pub sensors: Vec<SensorId>,
+    /// Program followed by its arguments, the message is passed in
+    /// `CONTROL_*` variables. A non-zero exit counts as a failure.
+    pub command: Vec<String>,
+}
+
+fn default_subject() -> String {
+    "{{title}}".to_owned()
+}
+
+fn default_text() -> String {
+    "{{description}}\n\nDevice: {{device}}\nTime: {{time}}\n".to_owned()
+}
+
+fn default_payload() -> String {
+    r#"{"trigger": "{{trigger}}", "title": "{{title}}", "event": {{event}}}"#
+        .to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    Email(EmailSink),
+    Webhook(WebhookSink),
+    Exec(ExecSink),
+}
+
+impl Sink {
+    /// Its table in the file.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Sink::Email(_) => "email",
+            Sink::Webhook(_) => "webhook",
+            Sink::Exec(_) => "exec",
+        }
+    }
+
+    /// The `on` and `sensors` lists.
+    pub fn when(&self) -> (&[Trigger], &[SensorId]) {
+        match self {
+            Sink::Email(sink) => (&sink.on, &sink.sensors),
+            Sink::Webhook(sink) => (&sink.on, &sink.sensors),
+            Sink::Exec(sink) => (&sink.on, &sink.sensors),
+        }
+    }
+
+    /// Whether `message` is one the sink was set up for.
+    pub fn wants(&self, message: &Message) -> bool {
+        let (on, sensors) = self.when();
+        match message.sensor() {
+            Some(sensor) => sensors.contains(sensor),
+            None => on.contains(&message.trigger),
+        }
+    }
+
+    /// Catches what can be caught before the first alarm: addresses,
+    /// templates and a webhook body that is not JSON.
+    pub fn validate(&self, sample: &Message) -> anyhow::Result<()> {
+        match self {
+            Sink::Email(sink) => {
+                server_address(&sink.server)?;
+                sink.from
+                    .parse::<Mailbox>()
+                    .with_context(|| {
+                        format!("Invalid from address '{}'", sink.from)
+                    })?;
+                if sink.to.is_empty() {
+                    bail!("to needs at least one address");
+                }
+                for to in &sink.to {
+                    to.parse::<Mailbox>().with_context(|| {
+                        format!("Invalid to address '{to}'")
+                    })?;
+                }
+                if sink.username.is_some() != sink.password.is_some() {
+                    bail!("username and password go together");
+                }
+                render(&sink.subject, sample, false)?;
+                render(&sink.body, sample, false)?;
+            }
+            Sink::Webhook(sink) => {
+                if !sink.url.starts_with("http://")
+                    && !sink.url.starts_with("https://")
+                {
+                    bail!("url must start with http:// or https://");
+                }
+                let body = render(&sink.body, sample, true)?;
+                serde_json::from_str::<serde_json::Value>(&body)
+                    .context("body is not JSON once filled in")?;
+            }
+            Sink::Exec(sink) => {
+                if sink.command.is_empty() {
+                    bail!("command has no program");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// One attempt, blocks until the server or program is done.
+    pub fn deliver(&self, message: &Message) -> anyhow::Result<()> {
+        match self {
+            Sink::Email(sink) => send_email(sink, message),
+            Sink::Webhook(sink) => post_webhook(sink, message),
+            Sink::Exec(sink) => run_program(sink, message),
+        }
+    }
+}
+
+impl Display for Sink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sink::Email(sink) => write!(f, "{}", sink.to.join(", ")),
+            // The rest of the URL is often the secret
+            Sink::Webhook(sink) => {
+                let host = sink
+                    .url
+                    .split("://")
+                    .nth(1)
+                    .and_then(|rest| rest.split('/').next())
+                    .unwrap_or_default();
+                write!(f, "{host}")
+            }
+            Sink::Exec(sink) => {
+                write!(
+                    f,
+                    "{}",
+                    sink.command
+                        .first()
+                        .map_or("", String::as_str)
+                )
+            }
+        }
+    }
+}
+
+fn server_address(server: &str) -> anyhow::Result<(&str, u16)> {
+    server
+        .rsplit_once(':')
+        .and_then(|(host, port)| {
+            Some((host, port.parse().ok()?)).filter(|_| !host.is_empty())
+        })
+        .with_context(|| {
+            format!("server must be host:port like smtp.example.com:587, got '{server}'")
+        })
+}
+
+fn send_email(sink: &EmailSink, message: &Message) -> anyhow::Result<()> {
+    let mut email = lettre::Message::builder()
+        .from(sink.from.parse()?)
+        .subject(render(&sink.subject, message, false)?)
+        .header(ContentType::TEXT_PLAIN);
+    for to in &sink.to {
+        email = email.to(to.parse()?);
+    }
+    let email = email.body(render(&sink.body, message, false)?)?;
+
+    let (host, port) = server_address(&sink.server)?;
+    let mut transport = match sink.security {
+        Security::None => SmtpTransport::builder_dangerous(host),
+        Security::Starttls => SmtpTransport::starttls_relay(host)?,
+        Security::Tls => SmtpTransport::relay(host)?,
+    }
+    .port(port)
+    .timeout(Some(TIMEOUT));
+    if let (Some(username), Some(password)) = (&sink.username, &sink.password) {
+        transport = transport.credentials(Credentials::new(
+            username.clone(),
+            password.clone(),
+        ));
+    }
+
+    // Its source repeats the server's reply
+    transport
+        .build()
+        .send(&email)
+        .map_err(|err| anyhow!("{err}"))?;
+    Ok(())
+}
+
+fn post_webhook(sink: &WebhookSink, message: &Message) -> anyhow::Result<()> {
+    let agent = ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .build(),
+    );
+    let mut request = agent
+        .post(&sink.url)
+        .header("Content-Type", "application/json");
+    for (name, value) in &sink.headers {
+        request = request.header(name, value);
+    }
+
+    request.send(render(&sink.body, message, true)?)?;
+    Ok(())
+}
+
+fn run_program(sink: &ExecSink, message: &Message) -> anyhow::Result<()> {
+    let [program, args @ ..] = sink.command.as_slice() else {
+        bail!("No program");
+    };
+
+    let status = Command::new(program)
+        .args(args)
+        .envs(message.env())
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        bail!("{program} ended with {status}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! `{{placeholder}}` substitution for subjects, bodies and webhook payloads.
+
+use crate::notify::Message;
+use anyhow::bail;
+
+/// What a template may refer to, see [`Message::field`].
+pub const FIELDS: [&str; 6] =
+    ["trigger", "title", "device", "description", "time", "event"];
+
+/// Fills in the placeholders. With `json` the values are escaped to sit
+/// inside a JSON string, except `{{event}}` which is a JSON object already.
+pub fn render(
+    template: &str,
+    message: &Message,
+    json: bool,
+) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            bail!("Unclosed {{{{ in '{template}'");
+        };
+        let name = rest[start + 2..start + end].trim();
+        let Some(value) = message.field(name) else {
+            bail!(
+                "Unknown placeholder {{{{{name}}}}}, known are {}",
+                FIELDS.join(", ")
+            );
+        };
+
+        if json && name != "event" {
+            let quoted = serde_json::to_string(&value)
+                .expect("Strings are always valid JSON");
+            rendered.push_str(&quoted[1..quoted.len() - 1]);
+        } else {
+            rendered.push_str(&value);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
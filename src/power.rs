@@ -0,0 +1,72 @@
+use crate::devices::Device;
+use crate::events::EventKind;
+use crate::events::log::EventLog;
+use crate::notice::Notice;
+use battery::State;
+use chrono::{DateTime, Utc};
+
+/// Watches the batteries of the machine Control runs on. Mains power gone
+/// for too long sets off every device's alarm.
+#[derive(Debug)]
+pub struct PowerMonitor {
+    manager: battery::Manager,
+    on_battery: bool,
+}
+
+impl PowerMonitor {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            manager: battery::Manager::new()?,
+            on_battery: false,
+        })
+    }
+
+    pub fn on_battery(&self) -> bool {
+        self.on_battery
+    }
+
+    /// Reads the batteries, logs a change and tells the alarms. Call it
+    /// regularly, the grace period runs in the alarms.
+    pub fn update(
+        &mut self,
+        devices: &mut [Device],
+        events: &mut EventLog,
+        now: DateTime<Utc>,
+    ) -> Option<Notice> {
+        let on_battery = self
+            .manager
+            .batteries()
+            .iter_mut()
+            .flatten()
+            .any(|b| {
+                if let Ok(b) = b {
+                    let state = b.state();
+                    state == State::Discharging
+                        || state == State::Empty
+                        || state == State::Unknown
+                } else {
+                    false
+                }
+            });
+
+        for device in devices {
+            if on_battery {
+                device.alarm.power_lost(now);
+            } else {
+                device.alarm.power_restored();
+            }
+        }
+
+        if on_battery == self.on_battery {
+            return None;
+        }
+        self.on_battery = on_battery;
+        events.record(EventKind::Battery { on_battery });
+
+        Some(if on_battery {
+            Notice::warning("The device is running on battery.")
+        } else {
+            Notice::info("The device is running on charger.")
+        })
+    }
+}
@@ -0,0 +1,573 @@
+use crate::alarm::Zone;
+use crate::authorized::protocol::{MAX_PAYLOAD, ProtocolError};
+use crate::authorized::serial_connection::{PollResult, SensorReading};
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Map of the stock board, used when there is no sensor file.
+const DEFAULT_MAP: &str = include_str!("../assets/sensors.toml");
+
+/// Name of a sensor in the event log, scripts and filters, e.g.
+/// `front_door`.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct SensorId(String);
+
+impl SensorId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for SensorId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Polarity {
+    #[default]
+    ActiveHigh,
+    /// A cleared bit means the sensor is tripped, e.g. a normally closed
+    /// contact.
+    ActiveLow,
+}
+
+/// How loudly a sensor becoming active is announced.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// One line of the sensor map.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorDef {
+    pub id: SensorId,
+    /// Bit of the poll payload, 0 is the lowest bit of the first byte.
+    /// Either this or `analog` is set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub bit: Option<u8>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub analog: Option<AnalogDef>,
+    pub name: String,
+    pub group: String,
+    #[serde(default)]
+    pub polarity: Polarity,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Zone the sensor sets the alarm off in, `None` if it never does.
+    #[serde(default)]
+    pub alarm: Option<Zone>,
+}
+
+/// A measured value the board sends as a big-endian integer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalogDef {
+    /// Byte of the poll payload the value starts at.
+    pub offset: u8,
+    pub format: ValueFormat,
+    /// Factor from the raw integer to `unit`.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub unit: String,
+    /// The sensor is active above this value...
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub alarm_above: Option<f64>,
+    /// ...or below this one.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub alarm_below: Option<f64>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueFormat {
+    U8,
+    I8,
+    U16,
+    I16,
+}
+
+impl ValueFormat {
+    pub fn len(self) -> usize {
+        match self {
+            ValueFormat::U8 | ValueFormat::I8 => 1,
+            ValueFormat::U16 | ValueFormat::I16 => 2,
+        }
+    }
+
+    fn read(self, bytes: &[u8]) -> i64 {
+        match self {
+            ValueFormat::U8 => bytes[0] as i64,
+            ValueFormat::I8 => bytes[0] as i8 as i64,
+            ValueFormat::U16 => u16::from_be_bytes([bytes[0], bytes[1]]) as i64,
+            ValueFormat::I16 => i16::from_be_bytes([bytes[0], bytes[1]]) as i64,
+        }
+    }
+
+    /// Saturates at the ends of the range.
+    fn write(self, raw: i64, bytes: &mut [u8]) {
+        match self {
+            ValueFormat::U8 => bytes[0] = raw.clamp(0, u8::MAX as i64) as u8,
+            ValueFormat::I8 => {
+                bytes[0] = raw.clamp(i8::MIN as i64, i8::MAX as i64) as u8;
+            }
+            ValueFormat::U16 => bytes.copy_from_slice(
+                &(raw.clamp(0, u16::MAX as i64) as u16).to_be_bytes(),
+            ),
+            ValueFormat::I16 => bytes.copy_from_slice(
+                &(raw.clamp(i16::MIN as i64, i16::MAX as i64) as i16)
+                    .to_be_bytes(),
+            ),
+        }
+    }
+}
+
+impl AnalogDef {
+    fn bytes(&self) -> std::ops::Range<usize> {
+        let start = self.offset as usize;
+        start..start + self.format.len()
+    }
+
+    pub fn is_alarm(&self, value: f64) -> bool {
+        self.alarm_above
+            .is_some_and(|above| value > above)
+            || self
+                .alarm_below
+                .is_some_and(|below| value < below)
+    }
+}
+
+/// Which part of the poll payload is which sensor, read from
+/// `data/sensors.toml` so new hardware revisions need no code changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorMap {
+    #[serde(rename = "sensor")]
+    sensors: Vec<SensorDef>,
+}
+
+impl Default for SensorMap {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_MAP).expect("the built-in sensor map is valid")
+    }
+}
+
+impl SensorMap {
+    /// The stock board's map when the file does not exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let map: Self = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).with_context(|| {
+                format!("Failed to parse {}", path.display())
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                });
+            }
+        };
+
+        map.validate()
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut ids = HashSet::new();
+        let mut bits = HashSet::new();
+        let mut analog_bytes = HashSet::new();
+
+        for sensor in &self.sensors {
+            let id = sensor.id.as_str();
+            if id.is_empty()
+                || !id.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'
+                })
+            {
+                bail!("Sensor id '{id}' may only have a-z, 0-9 and _");
+            }
+
+            if !ids.insert(id) {
+                bail!("Sensor id '{id}' is used twice");
+            }
+
+            match (sensor.bit, &sensor.analog) {
+                (Some(bit), None) => {
+                    if !bits.insert(bit) {
+                        bail!("Bit {bit} is used by more than one sensor");
+                    }
+                }
+                (None, Some(analog)) => {
+                    for byte in analog.bytes() {
+                        if !analog_bytes.insert(byte) {
+                            bail!(
+                                "Byte {byte} is used by more than one analog sensor"
+                            );
+                        }
+                    }
+
+                    if !(analog.scale.is_finite() && analog.scale != 0.0) {
+                        bail!("Sensor '{id}' needs a finite, non-zero scale");
+                    }
+
+                    if sensor.alarm.is_some()
+                        && analog.alarm_above.is_none()
+                        && analog.alarm_below.is_none()
+                    {
+                        bail!(
+                            "Sensor '{id}' has an alarm zone but neither alarm_above nor alarm_below"
+                        );
+                    }
+                }
+                _ => {
+                    bail!("Sensor '{id}' needs either a bit or an analog value")
+                }
+            }
+        }
+
+        if let Some(byte) = bits
+            .iter()
+            .map(|bit| *bit as usize / 8)
+            .find(|byte| analog_bytes.contains(byte))
+        {
+            bail!("Byte {byte} holds both bits and an analog value");
+        }
+
+        if self.payload_len() > MAX_PAYLOAD {
+            bail!(
+                "The sensors need {} bytes, a poll answer carries at most {MAX_PAYLOAD}",
+                self.payload_len()
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn sensors(&self) -> &[SensorDef] {
+        &self.sensors
+    }
+
+    pub fn find(&self, id: &SensorId) -> Option<&SensorDef> {
+        self.sensors
+            .iter()
+            .find(|sensor| &sensor.id == id)
+    }
+
+    /// Group names in the order their first sensor appears.
+    pub fn groups(&self) -> Vec<&str> {
+        let mut groups: Vec<&str> = Vec::new();
+        for sensor in &self.sensors {
+            if !groups.contains(&sensor.group.as_str()) {
+                groups.push(&sensor.group);
+            }
+        }
+
+        groups
+    }
+
+    /// Reads every sensor of the map out of a poll payload. Analog values
+    /// past the end of the payload are left out, the legacy protocol only
+    /// carries the two state bytes.
+    pub fn decode(&self, payload: &[u8]) -> Result<PollResult, ProtocolError> {
+        if payload.is_empty() {
+            return Err(ProtocolError::EmptyPayload);
+        }
+
+        let needed = self.digital_len();
+        if payload.len() < needed {
+            return Err(ProtocolError::PayloadTooShort {
+                needed,
+                actual: payload.len(),
+            });
+        }
+
+        let readings = self
+            .sensors
+            .iter()
+            .filter_map(|sensor| {
+                if let Some(analog) = &sensor.analog {
+                    let raw = analog
+                        .format
+                        .read(payload.get(analog.bytes())?);
+                    let value = raw as f64 * analog.scale;
+
+                    return Some(SensorReading {
+                        id: sensor.id.clone(),
+                        active: analog.is_alarm(value),
+                        value: Some(value),
+                    });
+                }
+
+                let bit = sensor.bit?;
+                let set = payload[bit as usize / 8] & (1 << (bit % 8)) != 0;
+
+                Some(SensorReading {
+                    id: sensor.id.clone(),
+                    active: set == (sensor.polarity == Polarity::ActiveHigh),
+                    value: None,
+                })
+            })
+            .collect();
+
+        Ok(PollResult::new(readings))
+    }
+
+    /// The payload a board would send for these sensor values, what
+    /// [`SensorMap::decode`] reads back.
+    pub fn encode(&self, values: &PollResult) -> Vec<u8> {
+        let mut payload = vec![0u8; self.payload_len().max(1)];
+
+        for sensor in &self.sensors {
+            if let Some(analog) = &sensor.analog {
+                let value = values
+                    .value(&sensor.id)
+                    .unwrap_or_default();
+                let raw = (value / analog.scale).round() as i64;
+                analog
+                    .format
+                    .write(raw, &mut payload[analog.bytes()]);
+            } else if let Some(bit) = sensor.bit
+                && values.get(&sensor.id)
+                    == (sensor.polarity == Polarity::ActiveHigh)
+            {
+                payload[bit as usize / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        payload
+    }
+
+    /// Bytes a poll payload needs to hold every sensor.
+    fn payload_len(&self) -> usize {
+        self.sensors
+            .iter()
+            .filter_map(|sensor| sensor.analog.as_ref())
+            .map(|analog| analog.bytes().end)
+            .max()
+            .unwrap_or(0)
+            .max(self.digital_len())
+    }
+
+    /// Bytes a poll payload needs to hold every bit.
+    fn digital_len(&self) -> usize {
+        self.sensors
+            .iter()
+            .filter_map(|sensor| sensor.bit)
+            .map(|bit| bit as usize / 8 + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"
+[[sensor]]
+id = "front_door"
+bit = 1
+name = "Front door"
+group = "Doors"
+alarm = "entry"
+
+[[sensor]]
+id = "tamper"
+bit = 9
+name = "Tamper"
+group = "Other"
+polarity = "active_low"
+alarm = "twenty_four_hour"
+
+[[sensor]]
+id = "temperature"
+analog = { offset = 2, format = "i16", scale = 0.1, alarm_above = 60.0, alarm_below = -20.0 }
+name = "Temperature"
+group = "Other"
+alarm = "twenty_four_hour"
+
+[[sensor]]
+id = "battery"
+analog = { offset = 4, format = "u8" }
+name = "Battery"
+group = "Power"
+"#;
+
+    fn map() -> SensorMap {
+        let map: SensorMap = toml::from_str(MAP).unwrap();
+        map.validate().unwrap();
+        map
+    }
+
+    fn id(id: &str) -> SensorId {
+        SensorId::new(id)
+    }
+
+    #[test]
+    fn the_built_in_map_is_valid() {
+        let map = SensorMap::default();
+        map.validate().unwrap();
+        assert!(map.find(&id("front_door")).is_some());
+    }
+
+    #[test]
+    fn bits_decode_with_their_polarity_and_zone() {
+        let map = map();
+
+        let poll = map
+            .decode(&[0b10, 0b00, 0, 0, 0])
+            .unwrap();
+        assert!(poll.get(&id("front_door")));
+        // Active low, a cleared bit is a tripped sensor
+        assert!(poll.get(&id("tamper")));
+
+        let poll = map
+            .decode(&[0b00, 0b10, 0, 0, 0])
+            .unwrap();
+        assert!(!poll.get(&id("front_door")));
+        assert!(!poll.get(&id("tamper")));
+
+        assert_eq!(
+            map.find(&id("front_door"))
+                .unwrap()
+                .alarm,
+            Some(Zone::Entry)
+        );
+        assert_eq!(
+            map.find(&id("battery")).unwrap().alarm,
+            None
+        );
+        assert!(matches!(
+            map.decode(&[0]),
+            Err(ProtocolError::PayloadTooShort { needed: 2, actual: 1 })
+        ));
+    }
+
+    #[test]
+    fn analog_values_decode_from_the_payload() {
+        let map = map();
+
+        let poll = map
+            .decode(&[0, 0, 0x00, 0xFA, 120])
+            .unwrap();
+        assert_eq!(
+            poll.value(&id("temperature")),
+            Some(25.0)
+        );
+        assert_eq!(poll.value(&id("battery")), Some(120.0));
+        assert!(!poll.get(&id("temperature")));
+
+        // The legacy protocol only carries the state bytes
+        let poll = map.decode(&[0, 0]).unwrap();
+        assert_eq!(poll.value(&id("temperature")), None);
+
+        let mut values = PollResult::default();
+        values.set(&id("front_door"), true);
+        values.set_value(&id("temperature"), -12.5);
+        values.set_value(&id("battery"), 300.0);
+        let poll = map
+            .decode(&map.encode(&values))
+            .unwrap();
+        assert!(poll.get(&id("front_door")));
+        assert_eq!(
+            poll.value(&id("temperature")),
+            Some(-12.5)
+        );
+        // Saturated at the top of a u8
+        assert_eq!(poll.value(&id("battery")), Some(255.0));
+    }
+
+    #[test]
+    fn thresholds_make_analog_sensors_active() {
+        let map = map();
+        let temperature = |raw: i16| {
+            let [high, low] = raw.to_be_bytes();
+            map.decode(&[0, 0, high, low, 0])
+                .unwrap()
+                .get(&id("temperature"))
+        };
+
+        assert!(!temperature(600));
+        assert!(temperature(601));
+        assert!(!temperature(-200));
+        assert!(temperature(-201));
+    }
+
+    #[test]
+    fn conflicting_maps_are_refused() {
+        for (change, from, to) in [
+            ("a bit twice", "bit = 9", "bit = 1"),
+            (
+                "overlapping values",
+                "offset = 4",
+                "offset = 3",
+            ),
+            (
+                "bits and a value in one byte",
+                "offset = 4",
+                "offset = 1",
+            ),
+            (
+                "a zone without a threshold",
+                ", alarm_above = 60.0, alarm_below = -20.0",
+                "",
+            ),
+        ] {
+            let map = MAP.replace(from, to);
+            let map: SensorMap = toml::from_str(&map).unwrap();
+            assert!(
+                map.validate().is_err(),
+                "{change} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::authorized::identity::{
+    COMMAND_SET, Capabilities, DeviceIdentity, model,
+};
+use crate::authorized::protocol::{
+    Command, FRAME_START, FRAME_VERSION, Frame, LEGACY_STATE_LEN, MAX_PAYLOAD,
+    crc16, nak, response,
+};
+use crate::authorized::serial_connection::PollResult;
+use crate::sensors::{SensorId, SensorMap};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Header bytes following the start marker: version, sequence, kind, length.
+const HEADER_LEN: usize = 4;
+/// Sets off the siren output of `api.ino`, the host never sends it.
+const SIREN_OPCODE: u8 = 0xA6;
+
+/// What the simulated board answers to HELLO.
+pub const SIMULATED_IDENTITY: DeviceIdentity = DeviceIdentity {
+    model: model::SIMULATOR,
+    firmware: [2, 0, 0],
+    command_set: COMMAND_SET,
+    serial_number: 1,
+    capabilities: Capabilities(
+        Capabilities::DOORS | Capabilities::ANALOG | Capabilities::SIREN,
+    ),
+};
+
+/// Misbehaviour the simulated board should show on the wire.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Faults {
+    /// Every reply becomes readable only after this long.
+    pub reply_delay: Duration,
+    /// Junk bytes sent in front of every reply.
+    pub garbage_bytes: usize,
+    /// The next N replies get a broken last byte.
+    pub corrupt_replies: usize,
+    /// The next N replies are not sent at all.
+    pub dropped_replies: usize,
+    /// The board is unplugged: nothing is read, nothing is answered.
+    pub disconnected: bool,
+}
+
+/// What the board knows about the world. Shared between the device, the
+/// script player and whoever else wants to poke at it.
+#[derive(Debug, Default, Clone)]
+pub struct SimulatorState {
+    pub sensors: PollResult,
+    /// Where the board puts each sensor in its state report.
+    pub sensor_map: Arc<SensorMap>,
+    pub faults: Faults,
+    /// Answer to HELLO, `None` plays firmware from before the command,
+    /// where 0xA5 sets off the siren.
+    pub identity: Option<DeviceIdentity>,
+    pub back_door_locked: bool,
+    pub front_door_locked: bool,
+    /// The siren LED the firmware drives on 0xA6 and clears on 0x55.
+    pub alarm_output: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatorHandle(Arc<Mutex<SimulatorState>>);
+
+impl SimulatorHandle {
+    /// Analog sensors start at a value inside their thresholds, so the
+    /// board does not come up in alarm.
+    pub fn new(sensor_map: Arc<SensorMap>) -> Self {
+        let mut sensors = PollResult::default();
+        for sensor in sensor_map.sensors() {
+            if let Some(analog) = &sensor.analog {
+                let value = match (analog.alarm_below, analog.alarm_above) {
+                    (Some(below), Some(above)) => (below + above) / 2.0,
+                    (Some(below), None) => below.max(0.0),
+                    (None, Some(above)) => above.min(0.0),
+                    (None, None) => 0.0,
+                };
+                sensors.set_value(&sensor.id, value);
+            }
+        }
+
+        Self(Arc::new(Mutex::new(SimulatorState {
+            sensors,
+            sensor_map,
+            identity: Some(SIMULATED_IDENTITY),
+            ..SimulatorState::default()
+        })))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, SimulatorState> {
+        // Nothing in the simulator can leave the state half-updated
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn set_sensor(&self, sensor: &SensorId, value: bool) {
+        self.lock().sensors.set(sensor, value);
+    }
+
+    pub fn sensor(&self, sensor: &SensorId) -> bool {
+        self.lock().sensors.get(sensor)
+    }
+
+    pub fn set_value(&self, sensor: &SensorId, value: f64) {
+        self.lock()
+            .sensors
+            .set_value(sensor, value);
+    }
+
+    pub fn value(&self, sensor: &SensorId) -> f64 {
+        self.lock()
+            .sensors
+            .value(sensor)
+            .unwrap_or_default()
+    }
+
+    pub fn sensor_map(&self) -> Arc<SensorMap> {
+        self.lock().sensor_map.clone()
+    }
+
+    pub fn update_faults(&self, update: impl FnOnce(&mut Faults)) {
+        update(&mut self.lock().faults);
+    }
+}
+
+/// Byte-level model of `embed/api/api.ino`. Bytes the host writes go into
+/// [`SimulatedDevice::feed`], replies come out of
+/// [`SimulatedDevice::take_ready`].
+#[derive(Debug)]
+pub struct SimulatedDevice {
+    handle: SimulatorHandle,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    ready_at: Instant,
+}
+
+impl SimulatedDevice {
+    pub fn new(handle: SimulatorHandle) -> Self {
+        Self {
+            handle,
+            input: Vec::new(),
+            output: VecDeque::new(),
+            ready_at: Instant::now(),
+        }
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.handle.lock().faults.disconnected
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.is_disconnected() {
+            return;
+        }
+
+        self.input.extend_from_slice(bytes);
+        while self.process_one() {}
+    }
+
+    /// Returns the pending reply bytes if the reply delay has passed.
+    pub fn take_ready(&mut self) -> Option<Vec<u8>> {
+        if self.output.is_empty() || Instant::now() < self.ready_at {
+            return None;
+        }
+
+        Some(self.output.drain(..).collect())
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.output.len()
+    }
+
+    /// Puts bytes a reader could not take back in front of the queue.
+    pub fn push_front_ready(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter().rev() {
+            self.output.push_front(byte);
+        }
+    }
+
+    pub fn clear_pending(&mut self) {
+        self.output.clear();
+    }
+
+    /// Handles one command from the front of the input buffer. Returns
+    /// `false` when more bytes are needed.
+    fn process_one(&mut self) -> bool {
+        let Some(&first) = self.input.first() else {
+            return false;
+        };
+
+        if first != FRAME_START {
+            self.input.remove(0);
+            self.handle_legacy(first);
+            return true;
+        }
+
+        if self.input.len() < 1 + HEADER_LEN {
+            return false;
+        }
+
+        let header: [u8; HEADER_LEN] = self.input[1..1 + HEADER_LEN]
+            .try_into()
+            .expect("length checked above");
+        let [version, sequence, kind, len] = header;
+
+        if version != FRAME_VERSION {
+            // The firmware silently drops frames it does not understand
+            self.input.drain(..1 + HEADER_LEN);
+            return true;
+        }
+
+        if len as usize > MAX_PAYLOAD {
+            self.input.drain(..1 + HEADER_LEN);
+            self.reply_frame(
+                sequence,
+                response::NAK,
+                &[nak::BAD_LENGTH],
+            );
+            return true;
+        }
+
+        let total = 1 + HEADER_LEN + len as usize + 2;
+        if self.input.len() < total {
+            return false;
+        }
+
+        let frame: Vec<u8> = self.input.drain(..total).collect();
+        let body = &frame[1..total - 2];
+        let actual = u16::from_be_bytes([frame[total - 2], frame[total - 1]]);
+
+        if crc16(body) != actual {
+            self.reply_frame(
+                sequence,
+                response::NAK,
+                &[nak::BAD_CHECKSUM],
+            );
+        } else {
+            self.handle_framed(sequence, kind);
+        }
+
+        true
+    }
+
+    fn handle_legacy(&mut self, opcode: u8) {
+        // Same as api.ino: only the poll and HELLO are ever answered
+        if opcode == Command::Poll.opcode() {
+            let mut state = self.state_bytes();
+            state.resize(LEGACY_STATE_LEN, 0);
+            self.reply(state);
+        } else if opcode == Command::Hello.opcode() {
+            let identity = self.handle.lock().identity;
+            if let Some(identity) = identity {
+                self.reply(identity.encode().to_vec());
+            }
+        } else {
+            self.handle_output(opcode);
+        }
+    }
+
+    fn handle_framed(&mut self, sequence: u8, kind: u8) {
+        if kind == Command::Poll.opcode() {
+            let state = self.state_bytes();
+            self.reply_frame(sequence, response::STATE, &state);
+            return;
+        }
+
+        let identity = self.handle.lock().identity;
+        if kind == Command::Hello.opcode()
+            && let Some(identity) = identity
+        {
+            self.reply_frame(
+                sequence,
+                response::HELLO,
+                &identity.encode(),
+            );
+        } else if self.handle_output(kind) {
+            self.reply_frame(sequence, response::ACK, &[]);
+        } else {
+            self.reply_frame(
+                sequence,
+                response::NAK,
+                &[nak::UNKNOWN_COMMAND],
+            );
+        }
+    }
+
+    /// Door locks and the siren. Returns `false` for opcodes the board does
+    /// not know.
+    fn handle_output(&mut self, opcode: u8) -> bool {
+        let mut state = self.handle.lock();
+        match opcode {
+            _ if opcode == Command::Reset.opcode() => {
+                state.alarm_output = false;
+            }
+            _ if opcode == Command::LockBackDoor.opcode() => {
+                state.back_door_locked = true;
+            }
+            _ if opcode == Command::UnlockBackDoor.opcode() => {
+                state.back_door_locked = false;
+            }
+            _ if opcode == Command::LockFrontDoor.opcode() => {
+                state.front_door_locked = true;
+            }
+            // Firmware from before HELLO drove the siren with it
+            _ if opcode == Command::UnlockFrontDoor.opcode()
+                && state.identity.is_none() =>
+            {
+                state.alarm_output = true;
+            }
+            _ if opcode == Command::UnlockFrontDoor.opcode() => {
+                state.front_door_locked = false;
+            }
+            SIREN_OPCODE => state.alarm_output = true,
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn state_bytes(&self) -> Vec<u8> {
+        let state = self.handle.lock();
+        state.sensor_map.encode(&state.sensors)
+    }
+
+    fn reply_frame(&mut self, sequence: u8, kind: u8, payload: &[u8]) {
+        let frame = Frame::new(sequence, kind, payload.to_vec())
+            .encode()
+            .expect("simulator payloads are always short");
+        self.reply(frame);
+    }
+
+    fn reply(&mut self, mut bytes: Vec<u8>) {
+        let mut state = self.handle.lock();
+        let faults = &mut state.faults;
+
+        if faults.dropped_replies > 0 {
+            faults.dropped_replies -= 1;
+            return;
+        }
+
+        if faults.corrupt_replies > 0 {
+            faults.corrupt_replies -= 1;
+            if let Some(last) = bytes.last_mut() {
+                *last ^= 0xFF;
+            }
+        }
+
+        // Never emit the start marker as garbage, it would look like a frame
+        self.output.extend(
+            (0..faults.garbage_bytes)
+                .map(|i| (i as u8).wrapping_mul(37) | 0x80),
+        );
+        self.output.extend(bytes);
+        self.ready_at = Instant::now() + faults.reply_delay;
+    }
+}
@@ -0,0 +1,154 @@
+pub mod device;
+pub mod port;
+pub mod script;
+
+use crate::DATA_DIR;
+use crate::sensors::SensorMap;
+use crate::simulator::device::{SimulatedDevice, SimulatorHandle};
+use crate::simulator::script::Script;
+use anyhow::{Context, bail};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const USAGE: &str = "Usage: control --simulate [--script FILE] \
+                     [--link PATH | --listen HOST:PORT]";
+
+/// Entry point for `control --simulate`: a fake board on a pseudo-terminal
+/// that the desktop app (or a test) can open like a real port.
+pub fn run_cli(args: &[String]) -> anyhow::Result<()> {
+    let sensors = SensorMap::load(&Path::new(DATA_DIR).join("sensors.toml"))?;
+    let mut script = None;
+    let mut link = None;
+    let mut listen = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--script" => {
+                let path = args.next().context(USAGE)?;
+                script = Some(Script::load(path.as_ref(), &sensors)?);
+            }
+            "--link" => {
+                link = Some(PathBuf::from(
+                    args.next().context(USAGE)?,
+                ));
+            }
+            "--listen" => {
+                listen = Some(args.next().context(USAGE)?.clone());
+            }
+            other => bail!("Unknown argument '{other}'\n{USAGE}"),
+        }
+    }
+
+    let handle = SimulatorHandle::new(Arc::new(sensors));
+    if let Some(script) = script {
+        script.spawn(handle.clone());
+    }
+
+    let device = SimulatedDevice::new(handle);
+    match listen {
+        Some(address) => run_tcp(device, &address),
+        None => run_pty(device, link),
+    }
+}
+
+/// Serves the device on a TCP socket like a ser2net bridge would, one client
+/// at a time.
+fn run_tcp(mut device: SimulatedDevice, address: &str) -> anyhow::Result<()> {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("Failed to listen on {address}"))?;
+    println!(
+        "Simulator listening on tcp://{}",
+        listener.local_addr()?
+    );
+
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        stream.set_read_timeout(Some(Duration::from_millis(10)))?;
+        println!(
+            "Client connected: {}",
+            stream.peer_addr()?
+        );
+
+        let mut buffer = [0u8; 64];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => device.feed(&buffer[..count]),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock
+                    ) => {}
+                Err(_) => break,
+            }
+
+            if let Some(reply) = device.take_ready()
+                && stream.write_all(&reply).is_err()
+            {
+                break;
+            }
+        }
+
+        println!("Client disconnected");
+        device.clear_pending();
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn run_pty(
+    mut device: SimulatedDevice,
+    link: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    use serialport::{SerialPort, TTYPort};
+    use std::io::{ErrorKind, Read, Write};
+    use std::time::Duration;
+
+    let (mut master, slave) =
+        TTYPort::pair().context("Failed to open a pseudo-terminal")?;
+    master.set_timeout(Duration::from_millis(10))?;
+
+    let slave_name = slave
+        .name()
+        .context("Pseudo-terminal has no name")?;
+
+    if let Some(link) = &link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(&slave_name, link).with_context(|| {
+            format!(
+                "Failed to link {} to {slave_name}",
+                link.display()
+            )
+        })?;
+        println!(
+            "Simulator listening on {} -> {slave_name}",
+            link.display()
+        );
+    } else {
+        println!("Simulator listening on {slave_name}");
+    }
+
+    let mut buffer = [0u8; 64];
+    loop {
+        match master.read(&mut buffer) {
+            Ok(count) => device.feed(&buffer[..count]),
+            Err(err) if err.kind() == ErrorKind::TimedOut => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if let Some(reply) = device.take_ready() {
+            master.write_all(&reply)?;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn run_pty(_: SimulatedDevice, _: Option<PathBuf>) -> anyhow::Result<()> {
+    bail!("The pseudo-terminal simulator is only available on Unix")
+}
@@ -0,0 +1,211 @@
+use crate::simulator::device::SimulatedDevice;
+use serialport::{
+    ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const NAME: &str = "simulator";
+
+/// In-memory [`SerialPort`] with a [`SimulatedDevice`] on the other end.
+#[derive(Debug, Clone)]
+pub struct SimulatedPort {
+    device: Arc<Mutex<SimulatedDevice>>,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl SimulatedPort {
+    pub fn new(device: SimulatedDevice) -> Self {
+        Self {
+            device: Arc::new(Mutex::new(device)),
+            timeout: Duration::from_secs(3),
+            baud_rate: 9600,
+        }
+    }
+
+    fn with_device<T>(
+        &self,
+        action: impl FnOnce(&mut SimulatedDevice) -> T,
+    ) -> T {
+        let mut device = self
+            .device
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        action(&mut device)
+    }
+
+    fn unplugged() -> std::io::Error {
+        std::io::Error::new(
+            ErrorKind::BrokenPipe,
+            "Simulated device unplugged",
+        )
+    }
+}
+
+impl Read for SimulatedPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+
+        let pending = loop {
+            let ready = self.with_device(|device| {
+                if device.is_disconnected() {
+                    return Err(Self::unplugged());
+                }
+
+                Ok(device.take_ready())
+            })?;
+
+            if let Some(bytes) = ready {
+                break bytes;
+            }
+
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "Operation timed out",
+                ));
+            }
+
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        let count = pending.len().min(buf.len());
+        buf[..count].copy_from_slice(&pending[..count]);
+
+        // Give back whatever did not fit, it is already due
+        if count < pending.len() {
+            self.with_device(|device| {
+                device.push_front_ready(&pending[count..])
+            });
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for SimulatedPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.with_device(|device| {
+            if device.is_disconnected() {
+                return Err(Self::unplugged());
+            }
+
+            device.feed(buf);
+            Ok(buf.len())
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for SimulatedPort {
+    fn name(&self) -> Option<String> {
+        Some(NAME.to_owned())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.with_device(|device| device.pending_len()) as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != ClearBuffer::Output {
+            self.with_device(SimulatedDevice::clear_pending);
+        }
+
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
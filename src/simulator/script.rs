@@ -0,0 +1,285 @@
+use crate::sensors::{SensorId, SensorMap};
+use crate::simulator::device::{Faults, SimulatorHandle};
+use anyhow::{Context, anyhow, bail};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A timeline of sensor changes and faults, one step per line:
+///
+/// ```text
+/// # seconds since start, then the action
+/// 0     set front_door=0 back_door=0
+/// 1.5   set motion_1=1
+/// 2     set temperature=65.5
+/// 3     fault delay 4s
+/// 8     fault garbage 3
+/// 10    fault corrupt 2
+/// 12    fault drop 1
+/// 14    fault disconnect
+/// 20    fault reconnect
+/// 21    fault clear
+/// 30    loop
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<ScriptStep>,
+}
+
+#[derive(Debug, Clone)]
+struct ScriptStep {
+    at: Duration,
+    action: ScriptAction,
+}
+
+#[derive(Debug, Clone)]
+enum ScriptAction {
+    Set(Vec<(SensorId, SensorValue)>),
+    Fault(FaultChange),
+    Loop,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SensorValue {
+    Digital(bool),
+    Analog(f64),
+}
+
+#[derive(Debug, Clone)]
+enum FaultChange {
+    Delay(Duration),
+    Garbage(usize),
+    Corrupt(usize),
+    Drop(usize),
+    Disconnect,
+    Reconnect,
+    Clear,
+}
+
+impl Script {
+    /// Sensor names are checked against `sensors`.
+    pub fn load(path: &Path, sensors: &SensorMap) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| {
+            format!(
+                "Failed to read script {}",
+                path.display()
+            )
+        })?;
+
+        Self::parse(&text, sensors)
+            .with_context(|| format!("Invalid script {}", path.display()))
+    }
+
+    pub fn parse(text: &str, sensors: &SensorMap) -> anyhow::Result<Self> {
+        let mut steps = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let step = parse_step(line, sensors)
+                .with_context(|| format!("Line {}: {line}", number + 1))?;
+            steps.push(step);
+        }
+
+        if !steps.is_sorted_by_key(|step| step.at) {
+            bail!("Steps must be in chronological order");
+        }
+
+        Ok(Self { steps })
+    }
+
+    /// Plays the script against the simulator on a background thread.
+    pub fn spawn(self, handle: SimulatorHandle) -> JoinHandle<()> {
+        std::thread::Builder::new()
+            .name("simulator-script".to_owned())
+            .spawn(move || self.play(&handle))
+            .expect("Failed to spawn the simulator script thread!")
+    }
+
+    fn play(&self, handle: &SimulatorHandle) {
+        'restart: loop {
+            let start = Instant::now();
+
+            for step in &self.steps {
+                let elapsed = start.elapsed();
+                if step.at > elapsed {
+                    std::thread::sleep(step.at - elapsed);
+                }
+
+                match &step.action {
+                    ScriptAction::Set(values) => {
+                        for (sensor, value) in values {
+                            match *value {
+                                SensorValue::Digital(value) => {
+                                    println!("[simulator] {sensor} = {value}");
+                                    handle.set_sensor(sensor, value);
+                                }
+                                SensorValue::Analog(value) => {
+                                    println!("[simulator] {sensor} = {value}");
+                                    handle.set_value(sensor, value);
+                                }
+                            }
+                        }
+                    }
+                    ScriptAction::Fault(change) => {
+                        println!("[simulator] fault {change:?}");
+                        handle.update_faults(|faults| change.apply(faults));
+                    }
+                    ScriptAction::Loop => continue 'restart,
+                }
+            }
+
+            return;
+        }
+    }
+}
+
+impl FaultChange {
+    fn apply(&self, faults: &mut Faults) {
+        match *self {
+            FaultChange::Delay(delay) => faults.reply_delay = delay,
+            FaultChange::Garbage(count) => faults.garbage_bytes = count,
+            FaultChange::Corrupt(count) => faults.corrupt_replies = count,
+            FaultChange::Drop(count) => faults.dropped_replies = count,
+            FaultChange::Disconnect => faults.disconnected = true,
+            FaultChange::Reconnect => faults.disconnected = false,
+            FaultChange::Clear => *faults = Faults::default(),
+        }
+    }
+}
+
+fn parse_step(line: &str, sensors: &SensorMap) -> anyhow::Result<ScriptStep> {
+    let mut words = line.split_whitespace();
+
+    let at = words
+        .next()
+        .ok_or_else(|| anyhow!("Missing time"))?;
+    let at = at
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| anyhow!("Invalid time '{at}'"))?;
+
+    let action = match words.next() {
+        Some("set") => {
+            let values = words
+                .map(|word| parse_assignment(word, sensors))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if values.is_empty() {
+                bail!("'set' needs at least one sensor=value");
+            }
+
+            ScriptAction::Set(values)
+        }
+        Some("fault") => ScriptAction::Fault(parse_fault(&mut words)?),
+        Some("loop") => ScriptAction::Loop,
+        Some(other) => bail!("Unknown action '{other}'"),
+        None => bail!("Missing action"),
+    };
+
+    Ok(ScriptStep { at, action })
+}
+
+/// `name=1|on|true|0|off|false` for bits, `name=<number>` in the sensor's
+/// unit for analog values.
+fn parse_assignment(
+    word: &str,
+    sensors: &SensorMap,
+) -> anyhow::Result<(SensorId, SensorValue)> {
+    let (name, value) = word
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected sensor=value, got '{word}'"))?;
+
+    let sensor = SensorId::new(name);
+    let Some(def) = sensors.find(&sensor) else {
+        let known: Vec<_> = sensors
+            .sensors()
+            .iter()
+            .map(|s| s.id.as_str())
+            .collect();
+        bail!("Unknown sensor '{name}', expected one of {known:?}");
+    };
+
+    let value = if def.analog.is_some() {
+        match value.parse::<f64>() {
+            Ok(value) if value.is_finite() => SensorValue::Analog(value),
+            _ => bail!("Invalid value '{value}' for {name}"),
+        }
+    } else {
+        match value {
+            "1" | "on" | "true" => SensorValue::Digital(true),
+            "0" | "off" | "false" => SensorValue::Digital(false),
+            _ => bail!("Invalid value '{value}' for {name}"),
+        }
+    };
+
+    Ok((sensor, value))
+}
+
+fn parse_fault<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+) -> anyhow::Result<FaultChange> {
+    let kind = words
+        .next()
+        .ok_or_else(|| anyhow!("Missing fault kind"))?;
+    Ok(match kind {
+        "delay" => {
+            let value = words
+                .next()
+                .ok_or_else(|| anyhow!("'delay' needs a duration"))?;
+            FaultChange::Delay(parse_duration(value)?)
+        }
+        "garbage" => FaultChange::Garbage(parse_count(kind, words)?),
+        "corrupt" => FaultChange::Corrupt(parse_count(kind, words)?),
+        "drop" => FaultChange::Drop(parse_count(kind, words)?),
+        "disconnect" => FaultChange::Disconnect,
+        "reconnect" => FaultChange::Reconnect,
+        "clear" => FaultChange::Clear,
+        other => bail!("Unknown fault '{other}'"),
+    })
+}
+
+fn parse_count<'a>(
+    kind: &str,
+    words: &mut impl Iterator<Item = &'a str>,
+) -> anyhow::Result<usize> {
+    let value = words
+        .next()
+        .ok_or_else(|| anyhow!("'{kind}' needs a count"))?;
+
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid count '{value}'"))
+}
+
+/// Accepts `250ms`, `1.5s` or a bare number of milliseconds.
+fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow!("Invalid duration '{value}'");
+
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid());
+    }
+
+    if let Some(secs) = value.strip_suffix('s') {
+        return secs
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .ok_or_else(invalid);
+    }
+
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| invalid())
+}
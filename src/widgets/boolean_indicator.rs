@@ -16,7 +16,7 @@ impl Widget for BooleanIndicator<'_> {
                 ui.colored_label(Color32::LIGHT_RED, "❌")
             };
 
-            return label_response.union(status_response);
+            label_response.union(status_response)
         })
         .inner
     }
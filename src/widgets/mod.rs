@@ -1 +1,2 @@
 pub mod boolean_indicator;
+pub mod qr_code;
@@ -0,0 +1,50 @@
+use eframe::egui::{Color32, CornerRadius, Rect, Sense, Vec2, Widget};
+use qrcode::{Color, QrCode};
+
+/// Modules of white border around the code, scanners need it.
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCodeImage<'a> {
+    pub data: &'a str,
+    /// Side of one module in points.
+    pub module_size: f32,
+}
+
+impl Widget for QrCodeImage<'_> {
+    fn ui(self, ui: &mut eframe::egui::Ui) -> eframe::egui::Response {
+        let code = match QrCode::new(self.data) {
+            Ok(code) => code,
+            Err(err) => {
+                return ui.colored_label(
+                    Color32::LIGHT_RED,
+                    format!("Cannot draw QR code: {err}"),
+                );
+            }
+        };
+
+        let width = code.width();
+        let side = (width + 2 * QUIET_ZONE) as f32 * self.module_size;
+        let (rect, response) =
+            ui.allocate_exact_size(Vec2::splat(side), Sense::hover());
+
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, CornerRadius::ZERO, Color32::WHITE);
+
+        for (index, color) in code.to_colors().into_iter().enumerate() {
+            if color != Color::Dark {
+                continue;
+            }
+
+            let x = (index % width + QUIET_ZONE) as f32 * self.module_size;
+            let y = (index / width + QUIET_ZONE) as f32 * self.module_size;
+            let min = rect.min + Vec2::new(x, y);
+            painter.rect_filled(
+                Rect::from_min_size(min, Vec2::splat(self.module_size)),
+                CornerRadius::ZERO,
+                Color32::BLACK,
+            );
+        }
+
+        response
+    }
+}
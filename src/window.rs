@@ -0,0 +1,247 @@
+use crate::DATA_DIR;
+use crate::app;
+use crate::auth::dual_control::DualControlPolicy;
+use crate::auth::duress::DuressPolicy;
+use crate::auth::lockout::LoginGuard;
+use crate::auth::users::UserStore;
+use crate::config::{Config, ConfigWatcher};
+use crate::data::{AppState, DeviceForm, HistoryView, UserForm};
+use crate::devices::{self, Device};
+use crate::events::EventKind;
+use crate::events::log::EventLog;
+use crate::http;
+use crate::mqtt;
+use crate::notify::{NotificationPolicy, Notifier};
+use crate::power::PowerMonitor;
+use crate::sensors::SensorMap;
+use chrono::Utc;
+use eframe::NativeOptions;
+use eframe::egui::{Color32, FontData, FontDefinitions, FontFamily};
+use eframe::epaint::CornerRadius;
+use eframe::{egui, run_simple_native};
+use egui_notify::Toasts;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The desktop app, what `control` runs without arguments.
+pub fn run() -> anyhow::Result<()> {
+    let config_path = Path::new(DATA_DIR).join("config.toml");
+    let config = Config::load(&config_path)?;
+    let mut config_watcher = ConfigWatcher::new(&config_path);
+
+    let sensors = Arc::new(SensorMap::load(
+        &Path::new(DATA_DIR).join("sensors.toml"),
+    )?);
+
+    let devices_path = Path::new(DATA_DIR).join("devices.json");
+    let devices: Vec<Device> = devices::load(&devices_path)?
+        .into_iter()
+        .map(|entry| Device::start(entry, &config, &sensors))
+        .collect();
+    let selected_device = (!devices.is_empty()).then_some(0);
+
+    let fonts = configure_fonts();
+
+    let power =
+        PowerMonitor::new().expect("Failed to create a battery manager!");
+    let mut events =
+        EventLog::open(DATA_DIR).expect("Failed to open the event log!");
+    let mut users = UserStore::open(Path::new(DATA_DIR).join("users.json"))
+        .expect("Failed to open the user store!");
+    users.set_max_session_minutes(config.sessions.max_minutes);
+    let login_guard = LoginGuard::open(
+        Path::new(DATA_DIR).join("lockout.json"),
+        config.lockout.policy(),
+    )
+    .expect("Failed to open the login counters!");
+    let dual_control =
+        DualControlPolicy::load(&Path::new(DATA_DIR).join("dual_control.json"))
+            .expect("Failed to load the two-person policy!");
+    let mut notifications = NotificationPolicy::load(
+        &Path::new(DATA_DIR).join("notifications.toml"),
+    )
+    .expect("Failed to load the notifications!");
+    notifications.add_duress_hooks(
+        DuressPolicy::load(&Path::new(DATA_DIR).join("duress.json"))
+            .expect("Failed to load the duress hooks!"),
+    );
+    let mut notifier = Notifier::new(&notifications, &mut events);
+
+    let mut toasts = Toasts::default();
+    let mut http = None;
+    if let Err(err) = http::configure(&mut http, &config.http) {
+        toasts.error(format!("{err:#}"));
+    }
+    let mut mqtt = None;
+    if let Err(err) = mqtt::configure(&mut mqtt, &config.mqtt) {
+        toasts.error(format!("{err:#}"));
+    }
+
+    let mut user_data = AppState {
+        devices,
+        selected_device,
+        devices_path,
+        device_form: DeviceForm::default(),
+        power,
+        current_session: None,
+        input_password: "".to_owned(),
+        input_username: "".to_owned(),
+        input_password_confirm: "".to_owned(),
+        events,
+        history: HistoryView::default(),
+        users,
+        login_guard,
+        dual_control,
+        pending_approval: None,
+        approver_username: "".to_owned(),
+        approver_password: "".to_owned(),
+        approver_code: "".to_owned(),
+        user_form: UserForm::new(config.sessions.default_minutes),
+        pending_login: None,
+        input_code: "".to_owned(),
+        totp_setup: None,
+        duress_alerts: Vec::new(),
+        config_draft: config.clone(),
+        config,
+        config_path,
+        sensors,
+        http,
+        mqtt,
+    };
+
+    let options = NativeOptions { dithering: true, ..Default::default() };
+
+    run_simple_native(
+        "Control",
+        options,
+        move |ctx, _frame| {
+            ctx.set_fonts(fonts.clone());
+            customize_ui(ctx);
+
+            egui::CentralPanel::default().show(ctx, |ui| {
+                ui.ctx().request_repaint();
+
+                if config_watcher.changed() {
+                    app::reload_config(&mut user_data, &mut toasts);
+                }
+
+                let now = Utc::now();
+                app::render_overview(&mut user_data, ui);
+
+                let locked = app::is_locked(&user_data);
+                if let Some(device) = user_data.selected_device {
+                    let device = &mut user_data.devices[device];
+                    ui.separator();
+                    ui.heading(&device.name);
+                    app::render_alarm(&device.alarm, ui);
+
+                    if !locked {
+                        ui.add(device.api.widget());
+                    }
+                }
+
+                if let Some(notice) = user_data.power.update(
+                    &mut user_data.devices,
+                    &mut user_data.events,
+                    now,
+                ) {
+                    app::show_notice(&mut toasts, notice);
+                }
+                if user_data.power.on_battery() {
+                    ui.colored_label(Color32::RED, "THE DEVICE IS ON BATTERY");
+                }
+
+                app::update_devices(&mut user_data, &mut toasts, now);
+                app::update_http(&mut user_data, &mut toasts, now);
+                app::update_mqtt(&mut user_data, &mut toasts);
+
+                app::update_session(&mut user_data, &mut toasts, ui.ctx());
+
+                if locked {
+                    ui.separator();
+                    app::render_lock_screen(&mut user_data, &mut toasts, ui);
+                } else {
+                    ui.separator();
+                    app::render(&mut user_data, &mut toasts, ui);
+                }
+
+                // Silent on purpose: no toast, no label, the operator may be
+                // watched
+                for alert in user_data.duress_alerts.drain(..) {
+                    user_data
+                        .events
+                        .record(EventKind::Duress {
+                            user: alert.user,
+                            action: alert.action,
+                        });
+                }
+                for notice in notifier.update(&user_data.sensors) {
+                    app::show_notice(&mut toasts, notice);
+                }
+            });
+
+            toasts.show(ctx);
+        },
+    )
+    .map_err(|err| anyhow::anyhow!("{err}"))
+}
+
+fn customize_ui(ctx: &egui::Context) {
+    // Get a mutable reference to the default style
+    let mut style = (*ctx.style()).clone();
+
+    style.visuals.interact_cursor = Some(egui::CursorIcon::PointingHand);
+
+    style
+        .visuals
+        .widgets
+        .noninteractive
+        .corner_radius = CornerRadius::ZERO;
+
+    style
+        .visuals
+        .widgets
+        .inactive
+        .corner_radius = CornerRadius::ZERO;
+    style
+        .visuals
+        .widgets
+        .hovered
+        .corner_radius = CornerRadius::ZERO;
+    style
+        .visuals
+        .widgets
+        .active
+        .corner_radius = CornerRadius::ZERO;
+    style.visuals.widgets.open.corner_radius = CornerRadius::ZERO;
+
+    style.visuals.window_corner_radius = CornerRadius::ZERO;
+    style.visuals.menu_corner_radius = CornerRadius::ZERO;
+
+    // Apply the modified style
+    ctx.set_style(style);
+}
+
+fn configure_fonts() -> FontDefinitions {
+    let mut fonts = FontDefinitions::default();
+    fonts.font_data.insert(
+        "JetBrains Mono".to_owned(),
+        Arc::new(FontData::from_static(include_bytes!(
+            "../assets/JetBrainsMono-Regular.ttf"
+        ))),
+    );
+
+    fonts
+        .families
+        .get_mut(&FontFamily::Proportional)
+        .unwrap()
+        .insert(0, "JetBrains Mono".to_owned());
+
+    fonts
+        .families
+        .get_mut(&FontFamily::Monospace)
+        .unwrap()
+        .push("JetBrains Mono".to_owned());
+
+    fonts
+}